@@ -1,13 +1,28 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+    time::Instant,
+};
 
 use crate::{
+    calibration::{Calibration, CalibrationFit, CalibrationWizard, Reference},
+    config::GuiConfig,
+    device::{self, ConnectedDevices, DeviceId, DeviceInfo},
+    device_log::LogMessage,
+    export::{self, ExportFormat},
+    load_control::{LoadControlPanel, LoadField},
+    register_map::{self, RegisterBrowser, RegisterKind, RegisterView},
+    remote_data::RemoteData,
+    server_error::{ConnectionEvent, ErrorKind},
     server_task::ServerMessage,
+    storage::{Series, Store},
     time_interval::TimeInterval,
     tracer_an::{
-        two_bytes_to_f32, BatteryType, Rated, Realtime, RealtimeStatus, Stats, VoltageSettings,
+        BatteryType, LoadControlMode, Rated, Realtime, RealtimeStatus, Stats, VoltageSettings,
+        VOLTAGE_SETTINGS_BASE_ADDRESS,
     },
     voltage_chart::{ChartType, CustomChart},
-    Message, CHART_HEIGHT,
+    Message,
 };
 use iced::{widget::*, Alignment, Element, Length};
 use iced_aw::{TabBar, TabLabel};
@@ -21,6 +36,10 @@ pub struct AllCharts {
     pub pv: CustomChart,
     pub pv_power: CustomChart,
     pub inverter_power: CustomChart,
+    /// one chart per entry of `REALTIME_CHARTS`
+    pub realtime_charts: Vec<CustomChart>,
+    /// arrival of the previous realtime reading
+    pub last_realtime: Option<Instant>,
     pub selected_time_interval: TimeInterval,
     pub time_correctness: f32,
     pub max_time_day: f32,
@@ -28,9 +47,8 @@ pub struct AllCharts {
     pub max_time_fine: f32,
     pub min_y: f32,
     pub max_y: f32,
-    pub register_address_string: String,
-    pub register_address: u16,
-    pub modbus_val: Vec<u8>,
+    pub register_browser: RegisterBrowser,
+    pub load_panel: LoadControlPanel,
     pub realtime_data: Realtime,
     pub realtime_status_data: RealtimeStatus,
     pub rated_data: Rated,
@@ -39,9 +57,83 @@ pub struct AllCharts {
     pub change_voltage_settings: VoltageSettings,
     pub chart_controls: bool,
     pub paused: bool,
-    pub connected: Arc<Mutex<bool>>,
+    pub connected: ConnectedDevices,
+    /// the device these charts belong to, `None` until the first device connected
+    pub device: Option<DeviceId>,
+    pub voltage_buffer_size: usize,
+    /// title of the chart to export or `EXPORT_ALL`
+    pub export_selection: String,
+    pub export_dir: PathBuf,
+    pub export_status: String,
+    pub calibration: Calibration,
+    pub calibration_wizard: CalibrationWizard,
+    pub chart_height: f32,
+    /// newest event first, at most `MAX_CONNECTION_LOG` entries
+    pub connection_log: VecDeque<(chrono::DateTime<chrono::Local>, ConnectionEvent)>,
+    pub error_counts: BTreeMap<ErrorKind, usize>,
+    /// told by the device when it connects
+    pub device_info: Option<DeviceInfo>,
+    /// newest message first, at most `MAX_DEVICE_LOG` entries
+    pub device_log: VecDeque<LogMessage>,
+    /// only messages containing this are shown
+    pub log_filter: String,
+    /// outcome of the last holding write
+    pub write_status: String,
 }
 
+const MAX_CONNECTION_LOG: usize = 500;
+const MAX_DEVICE_LOG: usize = 1000;
+
+pub const EXPORT_ALL: &str = "all charts";
+
+/// realtime fields that get plotted: (field name, chart title, chart type, min_y, max_y)
+pub const REALTIME_CHARTS: [(&str, &str, ChartType, f32, f32); 8] = [
+    ("pv_current", "PV Current", ChartType::Current, 0.0, 40.0),
+    (
+        "battery_power",
+        "Battery Power",
+        ChartType::Power,
+        0.0,
+        1200.0,
+    ),
+    (
+        "load_voltage",
+        "Load Voltage",
+        ChartType::Voltage,
+        0.0,
+        60.0,
+    ),
+    (
+        "load_current",
+        "Load Current",
+        ChartType::Current,
+        0.0,
+        40.0,
+    ),
+    ("load_power", "Load Power", ChartType::Power, 0.0, 1200.0),
+    (
+        "battery_temperature",
+        "Battery Temperature",
+        ChartType::Temperature,
+        -10.0,
+        60.0,
+    ),
+    (
+        "equipment_temperature",
+        "Equipment Temperature",
+        ChartType::Temperature,
+        -10.0,
+        80.0,
+    ),
+    (
+        "remaining_battery_capacity",
+        "Battery Capacity",
+        ChartType::Percent,
+        0.0,
+        100.0,
+    ),
+];
+
 impl Default for AllCharts {
     fn default() -> Self {
         let battery1 = CustomChart {
@@ -72,6 +164,17 @@ impl Default for AllCharts {
             chart_type: ChartType::Power,
             ..Default::default()
         };
+        let realtime_charts = REALTIME_CHARTS
+            .iter()
+            .map(|&(_, title, chart_type, min_y, max_y)| CustomChart {
+                title: title.to_string(),
+                chart_type,
+                min_y,
+                max_y,
+                tick_len: 5.0,
+                ..Default::default()
+            })
+            .collect();
         AllCharts {
             selected_tab: SelectedTab::VoltageCharts,
             battery1,
@@ -80,6 +183,8 @@ impl Default for AllCharts {
             pv,
             pv_power,
             inverter_power,
+            realtime_charts,
+            last_realtime: None,
             selected_time_interval: Default::default(),
             max_time_day: 0.0,
             max_time: 0.0,
@@ -89,30 +194,50 @@ impl Default for AllCharts {
             time_correctness: 1.0,
             chart_controls: true,
             paused: false,
-            register_address: 0,
-            register_address_string: String::new(),
-            modbus_val: Vec::new(),
+            register_browser: Default::default(),
+            load_panel: Default::default(),
             realtime_data: Default::default(),
             realtime_status_data: Default::default(),
             voltage_settings: Default::default(),
             change_voltage_settings: Default::default(),
             rated_data: Default::default(),
             stats: Default::default(),
-            connected: Arc::new(Mutex::new(false)),
+            connected: Default::default(),
+            device: None,
+            voltage_buffer_size: 0,
+            export_selection: EXPORT_ALL.to_string(),
+            export_dir: PathBuf::from("."),
+            export_status: String::new(),
+            calibration: Default::default(),
+            calibration_wizard: Default::default(),
+            chart_height: GuiConfig::default().chart_height,
+            connection_log: VecDeque::new(),
+            error_counts: BTreeMap::new(),
+            device_info: None,
+            device_log: VecDeque::new(),
+            log_filter: String::new(),
+            write_status: String::new(),
         }
     }
 }
 
 impl AllCharts {
-    pub fn view(&self) -> Element<Message> {
+    pub fn view(&self) -> Element<'_, Message> {
         let tab_bar = TabBar::new(Message::TabSelected)
             .push(0, TabLabel::Text(String::from("Voltage Charts")))
             .push(1, TabLabel::Text(String::from("Power Charts")))
-            .push(2, TabLabel::Text(String::from("Stats")))
-            .push(3, TabLabel::Text(String::from("Settings")))
+            .push(2, TabLabel::Text(String::from("Realtime Charts")))
+            .push(3, TabLabel::Text(String::from("Stats")))
+            .push(4, TabLabel::Text(String::from("Settings")))
+            .push(5, TabLabel::Text(String::from("Connection")))
+            .push(6, TabLabel::Text(String::from("Device Log")))
+            .push(7, TabLabel::Text(String::from("Load")))
             .set_active_tab(&(self.selected_tab as i32));
 
-        let connected = *self.connected.lock().expect("could not lock mutex");
+        let connected = self
+            .device
+            .as_ref()
+            .is_some_and(|device| device::is_connected(&self.connected, device));
         let mut main_contents = Column::new();
         if !connected {
             main_contents = main_contents.push(Text::new("No connection !!!").size(36));
@@ -121,8 +246,12 @@ impl AllCharts {
         main_contents = main_contents.push(match self.selected_tab {
             SelectedTab::VoltageCharts => self.view_voltage_charts(),
             SelectedTab::PowerCharts => self.view_power_charts(),
+            SelectedTab::RealtimeCharts => self.view_realtime_charts(),
             SelectedTab::Stats => self.view_modbus(),
             SelectedTab::Settings => self.view_settings(),
+            SelectedTab::Connection => self.view_connection(),
+            SelectedTab::DeviceLog => self.view_device_log(),
+            SelectedTab::Load => self.view_load_control(),
         });
         Scrollable::new(
             Column::new()
@@ -134,7 +263,7 @@ impl AllCharts {
         .into()
     }
 
-    fn view_voltage_charts(&self) -> Element<Message> {
+    fn view_voltage_charts(&self) -> Element<'_, Message> {
         let control_row = self.view_chart_controls();
         let row1 = Row::new()
             .spacing(15)
@@ -142,16 +271,16 @@ impl AllCharts {
             .width(Length::Fill)
             .height(Length::Shrink)
             .align_items(Alignment::Center)
-            .push(self.battery_pack.view(0, CHART_HEIGHT))
-            .push(self.battery1.view(1, CHART_HEIGHT));
+            .push(self.battery_pack.view(0, self.chart_height))
+            .push(self.battery1.view(1, self.chart_height));
         let row2 = Row::new()
             .spacing(15)
             .padding(20)
             .width(Length::Fill)
             .height(Length::Shrink)
             .align_items(Alignment::Center)
-            .push(self.pv.view(0, CHART_HEIGHT))
-            .push(self.battery2.view(1, CHART_HEIGHT));
+            .push(self.pv.view(0, self.chart_height))
+            .push(self.battery2.view(1, self.chart_height));
 
         Column::new()
             .width(Length::Fill)
@@ -163,7 +292,7 @@ impl AllCharts {
             .into()
     }
 
-    fn view_power_charts(&self) -> Element<Message> {
+    fn view_power_charts(&self) -> Element<'_, Message> {
         let control_row = self.view_chart_controls();
         let chart_row = Row::new()
             .spacing(15)
@@ -171,7 +300,8 @@ impl AllCharts {
             .width(Length::Fill)
             .height(Length::Shrink)
             .align_items(Alignment::Center)
-            .push(self.pv_power.view(0, CHART_HEIGHT * 1.7));
+            .push(self.pv_power.view(0, self.chart_height * 1.7))
+            .push(self.inverter_power.view(1, self.chart_height * 1.7));
         Column::new()
             .width(Length::Fill)
             .height(Length::Shrink)
@@ -181,7 +311,33 @@ impl AllCharts {
             .into()
     }
 
-    fn view_chart_controls(&self) -> Row<Message> {
+    fn view_realtime_charts(&self) -> Element<'_, Message> {
+        let control_row = self.view_chart_controls();
+        let rows = self.realtime_charts.chunks(2).map(|charts| {
+            charts
+                .iter()
+                .enumerate()
+                .fold(
+                    Row::new()
+                        .spacing(15)
+                        .padding(20)
+                        .width(Length::Fill)
+                        .height(Length::Shrink)
+                        .align_items(Alignment::Center),
+                    |row, (idx, chart)| row.push(chart.view(idx, self.chart_height)),
+                )
+                .into()
+        });
+        Column::new()
+            .width(Length::Fill)
+            .height(Length::Shrink)
+            .align_items(Alignment::Start)
+            .push(control_row)
+            .extend(rows)
+            .into()
+    }
+
+    fn view_chart_controls(&self) -> Row<'_, Message> {
         let selected = self.selected_time_interval;
         let control_row = Row::new();
         let toggle_chart_controls = Button::new(if self.chart_controls { "-" } else { "+" })
@@ -266,15 +422,32 @@ impl AllCharts {
             .push(min_sub_interval_slider)
             .push(spacer())
             .push(max_sub_interval_slider)
-            .push(Text::new(format!(
-                "{:.3} kWh",
-                self.pv_power.kilo_watt_hours()
-            )))
+            .push(self.view_energy_balance())
             .push(Text::new(format!(
                 "{} s ..= {} s",
                 self.pv_power.integration_sub_range.start, self.pv_power.integration_sub_range.end
             )));
 
+        let mut export_options = vec![EXPORT_ALL.to_string()];
+        export_options.extend(self.charts().iter().map(|chart| chart.title.clone()));
+        let export_col = Column::new()
+            .push(PickList::new(
+                export_options,
+                Some(self.export_selection.clone()),
+                Message::ExportChartSelected,
+            ))
+            .push(
+                Row::new()
+                    .push(Button::new("export csv").on_press(Message::Export(ExportFormat::Csv)))
+                    .push(
+                        Button::new("export columnar")
+                            .on_press(Message::Export(ExportFormat::Columnar)),
+                    )
+                    .spacing(5),
+            )
+            .push(Text::new(&self.export_status).width(300))
+            .spacing(5);
+
         if self.chart_controls {
             control_row
                 .push(toggle_chart_controls)
@@ -300,59 +473,47 @@ impl AllCharts {
                 .push(Space::new(30., 30.))
                 .push(pause_button)
                 .push(integration_col)
+                .push(Space::new(30., 30.))
+                .push(export_col)
         } else {
             control_row.push(toggle_chart_controls)
         }
     }
 
-    fn view_modbus(&self) -> Element<Message> {
-        let register_text_input = text_input(
-            "enter register address of holding",
-            &self.register_address_string,
-        )
-        .width(140)
-        .on_input(Message::AddressInput);
-        let holding_button = Button::new("get holding val").on_press(Message::ReadHoldings {
-            register_address: self.register_address,
-            size: 1,
-        });
-        let register_button = Button::new("get input register").on_press(Message::ReadRegisters {
-            register_address: self.register_address,
-            size: 1,
-        });
+    /// PV energy in against inverter energy out over the integration range
+    fn view_energy_balance(&self) -> Element<'_, Message> {
+        let pv_in = self.pv_power.kilo_watt_hours();
+        let inverter_out = self.inverter_power.kilo_watt_hours();
+        let efficiency = if pv_in > 0.0 {
+            format!("{:.1} %", inverter_out / pv_in * 100.0)
+        } else {
+            "-".to_string()
+        };
+        Column::new()
+            .push(Text::new(format!("PV in: {pv_in:.3} kWh")))
+            .push(Text::new(format!("inverter out: {inverter_out:.3} kWh")))
+            .push(Text::new(format!(
+                "balance: {:+.3} kWh, inverter/PV: {efficiency}",
+                pv_in - inverter_out
+            )))
+            .into()
+    }
+
+    fn view_modbus(&self) -> Element<'_, Message> {
         let read_realtime_button =
             Button::new("read realtime data").on_press(Message::ReadRealtime);
         let read_realtime_status_button =
             Button::new("read realtime status data").on_press(Message::ReadRealtimeStatus);
-        let register_numeric_text =
-            text(format!("numeric register value: {}", self.register_address));
-        let holding_text = if self.modbus_val.len() >= 2 {
-            text(format!(
-                "received value: {:?}",
-                two_bytes_to_f32([self.modbus_val[0], self.modbus_val[1]])
-            ))
-        } else {
-            text("no value")
-        };
         let realtime_text = text(format!("{}", self.realtime_data));
         let realtime_status_text = text(format!("{}", self.realtime_status_data));
         let rated_col = self.view_rated();
         let stats_col = self.view_stats();
-        let register_col = Column::new()
-            .push(register_text_input)
-            .push(spacer())
-            .push(holding_button)
-            .push(register_button)
-            .push(spacer())
-            .push(register_numeric_text)
-            .push(holding_text);
         let realtime_col = Column::new().push(read_realtime_button).push(realtime_text);
         let realtime_status_col = Column::new()
             .push(read_realtime_status_button)
             .push(realtime_status_text);
         let row1 = Row::new()
             .push(Space::new(100, 10))
-            .push(register_col)
             .push(realtime_col)
             .push(realtime_status_col)
             .spacing(100);
@@ -361,22 +522,334 @@ impl AllCharts {
             .push(rated_col)
             .push(stats_col)
             .spacing(100);
+        let row3 = Row::new()
+            .push(Space::new(100, 10))
+            .push(self.view_register_browser());
         Column::new()
             .push(spacer())
             .push(row1)
             .push(row2)
+            .push(row3)
             .spacing(50)
             .into()
     }
 
-    fn view_settings(&self) -> Element<Message> {
+    /// Reads a range of registers and decodes it by the register map
+    fn view_register_browser(&self) -> Element<'_, Message> {
+        let browser = &self.register_browser;
+        let controls = Row::new()
+            .push(PickList::new(
+                RegisterKind::ALL,
+                Some(browser.kind),
+                Message::RegisterKindSelected,
+            ))
+            .push(
+                text_input("hex address", &browser.address_input)
+                    .on_input(Message::RegisterAddressInput)
+                    .width(100),
+            )
+            .push(
+                text_input("count", &browser.count_input)
+                    .on_input(Message::RegisterCountInput)
+                    .width(60),
+            )
+            .push(Button::new("read").on_press(Message::ReadRegisterRange))
+            .push(PickList::new(
+                RegisterView::ALL,
+                Some(browser.view),
+                Message::RegisterViewSelected,
+            ))
+            .push(
+                PickList::new(
+                    register_map::TRACER_AN,
+                    None::<register_map::RegisterDef>,
+                    Message::RegisterSelected,
+                )
+                .placeholder("go to register"),
+            )
+            .spacing(10)
+            .align_items(Alignment::Center);
+        let column = |header: &str| Column::new().push(text(header)).spacing(5);
+        let (mut addresses, mut names, mut values, mut units) = (
+            column("address"),
+            column("name"),
+            column("value"),
+            column("unit"),
+        );
+        for value in &browser.values {
+            addresses = addresses.push(text(format!("{:04x}", value.address)));
+            names = names.push(text(value.name()));
+            values = values.push(text(value.format(browser.view)));
+            units = units.push(text(value.unit()));
+        }
+        Column::new()
+            .push(text("register browser").size(24))
+            .push(controls)
+            .push(text(&browser.status))
+            .push(
+                Row::new()
+                    .push(addresses)
+                    .push(names)
+                    .push(values)
+                    .push(units)
+                    .spacing(30),
+            )
+            .spacing(10)
+            .into()
+    }
+
+    fn view_connection(&self) -> Element<'_, Message> {
+        let counts = ErrorKind::ALL
+            .iter()
+            .fold(Row::new().spacing(30), |row, kind| {
+                let count = self.error_counts.get(kind).copied().unwrap_or(0);
+                row.push(text(format!("{kind}: {count}")))
+            });
+        let log =
+            self.connection_log
+                .iter()
+                .fold(Column::new().spacing(5), |column, (time, event)| {
+                    column.push(text(format!(
+                        "{} {event}",
+                        time.format("%Y-%m-%d %H:%M:%S")
+                    )))
+                });
+        let device_info = match &self.device_info {
+            Some(info) => Column::new()
+                .push(text(format!("firmware: {}", info.firmware())))
+                .push(text(format!(
+                    "buffers: {}",
+                    info.buffers
+                        .iter()
+                        .map(|buffer_type| buffer_type.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )))
+                .push(text(format!("adc channels: {}", info.adc_channels)))
+                .push(text(format!(
+                    "sample intervals: voltage {} ms, power {} ms",
+                    self.pv.tick_len * 1000.0,
+                    self.pv_power.tick_len * 1000.0
+                )))
+                .spacing(5),
+            None => Column::new().push(text("no device info yet")),
+        };
+        Column::new()
+            .push(spacer())
+            .push(text("device"))
+            .push(device_info)
+            .push(spacer())
+            .push(text("errors by kind"))
+            .push(counts)
+            .push(spacer())
+            .push(text("connection events"))
+            .push(log)
+            .spacing(10)
+            .padding(20)
+            .into()
+    }
+
+    fn view_device_log(&self) -> Element<'_, Message> {
+        let messages = self
+            .device_log
+            .iter()
+            .filter(|message| message.matches(&self.log_filter))
+            .fold(Column::new().spacing(5), |column, message| {
+                column.push(text(message.to_string()))
+            });
+        Column::new()
+            .push(spacer())
+            .push(
+                text_input("filter", &self.log_filter)
+                    .on_input(Message::LogFilterChanged)
+                    .width(300),
+            )
+            .push(
+                Scrollable::new(messages)
+                    .width(Length::Fill)
+                    .height(Length::Fixed(self.chart_height * 2.0)),
+            )
+            .spacing(10)
+            .padding(20)
+            .into()
+    }
+
+    /// Switches the load output and edits when the controller switches it
+    fn view_load_control(&self) -> Element<'_, Message> {
+        let panel = &self.load_panel;
+        let load_on = match panel.load_on {
+            Some(true) => "load output on",
+            Some(false) => "load output off",
+            None => "load output unknown",
+        };
+        let output_row = Row::new()
+            .push(text(load_on).width(200))
+            .push(Button::new("switch on").on_press(Message::SetLoadOutput(true)))
+            .push(Button::new("switch off").on_press(Message::SetLoadOutput(false)))
+            .spacing(10)
+            .align_items(Alignment::Center);
+        let load_control = panel.load_control.unwrap_or_default();
+        let mode_row = Row::new()
+            .push(text("load control mode").width(200))
+            .push(
+                PickList::new(
+                    LoadControlMode::ALL,
+                    load_control.mode,
+                    Message::LoadControlModeSelected,
+                )
+                .placeholder("unknown"),
+            )
+            .push(
+                Checkbox::new("load on by default", load_control.default_load_on)
+                    .on_toggle(Message::DefaultLoadToggled),
+            )
+            .spacing(10)
+            .align_items(Alignment::Center);
+        let fields = LoadField::ALL
+            .into_iter()
+            .fold(Column::new().spacing(5), |column, field| {
+                column.push(
+                    Row::new()
+                        .push(text(field.label()).width(200))
+                        .push(
+                            text_input("", panel.input(field))
+                                .on_input(move |input| Message::LoadFieldInput(field, input))
+                                .width(100),
+                        )
+                        .push(Button::new("set").on_press(Message::ApplyLoadField(field)))
+                        .spacing(10)
+                        .align_items(Alignment::Center),
+                )
+            });
+        Column::new()
+            .push(spacer())
+            .push(Button::new("read load control").on_press(Message::ReadLoadControl))
+            .push(output_row)
+            .push(text(&panel.status))
+            .push(mode_row)
+            .push(fields)
+            .push(text(&self.write_status))
+            .spacing(20)
+            .padding(20)
+            .into()
+    }
+
+    fn view_settings(&self) -> Element<'_, Message> {
         Row::new()
             .push(spacer())
             .push(self.view_voltage_settings())
+            .push(spacer())
+            .push(self.view_calibration())
             .into()
     }
 
-    fn view_voltage_settings(&self) -> Element<Message> {
+    fn view_calibration(&self) -> Element<'_, Message> {
+        let wizard = &self.calibration_wizard;
+        let format_voltage = |voltage: Option<f32>| match voltage {
+            Some(voltage) => format!("{voltage:.3} V"),
+            None => "-".to_string(),
+        };
+        let channel = self.calibration.channel(wizard.channel);
+        let latest = wizard.latest();
+        let mut col = Column::new()
+            .spacing(10)
+            .push(Text::new("ADC calibration").size(24))
+            .push(
+                Row::new()
+                    .spacing(10)
+                    .align_items(Alignment::Center)
+                    .push(Text::new("channel"))
+                    .push(PickList::new(
+                        CalibrationWizard::CHANNELS,
+                        Some(wizard.channel),
+                        Message::CalibrationChannelSelected,
+                    )),
+            )
+            .push(
+                Row::new()
+                    .spacing(10)
+                    .align_items(Alignment::Center)
+                    .push(Text::new("reference"))
+                    .push(PickList::new(
+                        Reference::ALL,
+                        Some(wizard.reference),
+                        Message::CalibrationReferenceSelected,
+                    )),
+            );
+        if wizard.reference == Reference::Manual {
+            col = col.push(
+                text_input("reference voltage", &wizard.manual_reference)
+                    .width(140)
+                    .on_input(Message::CalibrationManualReference),
+            );
+        }
+        col = col
+            .push(Text::new(format!(
+                "adc voltage: {}",
+                format_voltage(latest)
+            )))
+            .push(Text::new(format!(
+                "calibrated: {}",
+                format_voltage(latest.zip(channel).map(|(v, c)| c.apply(v)))
+            )))
+            .push(Text::new(format!(
+                "reference: {}",
+                format_voltage(wizard.reference_voltage(&self.realtime_data, &self.stats))
+            )))
+            .push(
+                Row::new()
+                    .spacing(5)
+                    .push(Button::new("capture point").on_press(Message::CalibrationCapture))
+                    .push(Button::new("clear points").on_press(Message::CalibrationClearPoints)),
+            );
+        for (adc, reference) in &wizard.points {
+            col = col.push(Text::new(format!("{adc:.3} V -> {reference:.3} V")));
+        }
+        let current = match channel {
+            Some(channel) if channel.points.len() >= 2 => {
+                format!("current: table with {} points", channel.points.len())
+            }
+            Some(channel) => format!(
+                "current: gain {:.4}, offset {:.3} V",
+                channel.gain, channel.offset
+            ),
+            None => String::new(),
+        };
+        col.push(
+            Row::new()
+                .spacing(5)
+                .push(
+                    Button::new("fit gain/offset")
+                        .on_press(Message::CalibrationApply(CalibrationFit::Linear)),
+                )
+                .push(
+                    Button::new("use table")
+                        .on_press(Message::CalibrationApply(CalibrationFit::Piecewise)),
+                )
+                .push(
+                    Button::new("reset channel")
+                        .on_press(Message::CalibrationApply(CalibrationFit::Reset)),
+                ),
+        )
+        .push(Text::new(current))
+        .push(Text::new(&wizard.status).width(300))
+        .into()
+    }
+
+    /// Replaces the calibration of the wizard's channel, returns false if there are not enough points
+    pub fn apply_calibration(&mut self, fit: CalibrationFit) -> bool {
+        let channel = self.calibration_wizard.channel;
+        let Some(calibration) = self.calibration_wizard.fit(fit) else {
+            self.calibration_wizard.status = "not enough distinct points".to_string();
+            return false;
+        };
+        if let Some(channel_calibration) = self.calibration.channel_mut(channel) {
+            *channel_calibration = calibration;
+        }
+        true
+    }
+
+    fn view_voltage_settings(&self) -> Element<'_, Message> {
         let s = self.voltage_settings;
         let cs = self.change_voltage_settings;
         let get_voltage_settings_button =
@@ -502,10 +975,11 @@ impl AllCharts {
             .push(spacer())
             .push(get_voltage_settings_button)
             .push(row)
+            .push(Text::new(&self.write_status))
             .into()
     }
 
-    fn view_rated(&self) -> Element<Message> {
+    fn view_rated(&self) -> Element<'_, Message> {
         let read_rated_button = Button::new("read rated").on_press(Message::ReadRated);
         let rated_text = Text::new(format!("{}", self.rated_data));
         Column::new()
@@ -515,7 +989,7 @@ impl AllCharts {
             .into()
     }
 
-    fn view_stats(&self) -> Element<Message> {
+    fn view_stats(&self) -> Element<'_, Message> {
         let read_stats_button = Button::new("read stats").on_press(Message::ReadStats);
         let stats_text = Text::new(format!("{}", self.stats));
         Column::new()
@@ -525,6 +999,172 @@ impl AllCharts {
             .into()
     }
 
+    /// Routes remote data of this device to its charts and readings
+    pub fn update_remote_data(&mut self, mut remote_data: RemoteData) {
+        let mut bupdate_battery2 = false;
+        match remote_data {
+            RemoteData::NoData => {}
+            RemoteData::BatteryVoltage(_) => {
+                self.battery1
+                    .update_voltages_from_remote(&mut remote_data, &self.calibration);
+                bupdate_battery2 = true;
+            }
+            RemoteData::BatteryPackVoltage(_) => {
+                self.battery_pack
+                    .update_voltages_from_remote(&mut remote_data, &self.calibration);
+                bupdate_battery2 = true;
+            }
+            RemoteData::PVVoltage(_) => {
+                self.pv
+                    .update_voltages_from_remote(&mut remote_data, &self.calibration);
+            }
+            RemoteData::PVPower(_) => {
+                self.pv_power.update_power_from_remote(&mut remote_data);
+            }
+            RemoteData::InverterPower(_) => {
+                self.inverter_power
+                    .update_power_from_remote(&mut remote_data);
+            }
+            RemoteData::VoltageBufferSize(s) => self.voltage_buffer_size = s,
+            RemoteData::VoltageIntervalms(interval) => {
+                let tick_len = interval as f32 / 1000.0;
+                self.battery1.tick_len = tick_len;
+                self.battery2.tick_len = tick_len;
+                self.battery_pack.tick_len = tick_len;
+                self.pv.tick_len = tick_len;
+            }
+            RemoteData::PowerIntervalms(interval) => {
+                let tick_len = interval as f32 / 1000.0;
+                println!("power chart tick_len : {}", tick_len);
+                self.pv_power.tick_len = tick_len;
+                self.inverter_power.tick_len = tick_len;
+            }
+            RemoteData::Holdings(_) | RemoteData::InputRegisters(_) => {
+                self.register_browser.update(&remote_data);
+            }
+            RemoteData::HoldingsWritten {
+                register_address,
+                written,
+                read_back,
+            } => {
+                self.write_status = if written == read_back {
+                    format!(
+                        "{} holdings written at {register_address:#06x}",
+                        written.len()
+                    )
+                } else {
+                    format!(
+                        "holdings at {register_address:#06x} read back as {read_back:?} after writing {written:?}"
+                    )
+                };
+                // the read back settings are what the controller uses now
+                if register_address == VOLTAGE_SETTINGS_BASE_ADDRESS {
+                    let bytes: Vec<u8> = read_back.iter().flat_map(|v| v.to_be_bytes()).collect();
+                    if let Ok(voltage_settings) = VoltageSettings::from_bytes(&bytes) {
+                        self.voltage_settings = voltage_settings;
+                    }
+                }
+            }
+            RemoteData::Realtime(realtime) => {
+                self.update_realtime_charts(&realtime);
+                self.realtime_data = realtime;
+            }
+            RemoteData::RealtimeStatus(realtime_status) => {
+                self.realtime_status_data = realtime_status
+            }
+            RemoteData::VoltageSettings(voltage_settings) => {
+                self.voltage_settings = voltage_settings;
+                self.change_voltage_settings = voltage_settings;
+            }
+            RemoteData::Rated(rated) => {
+                self.rated_data = rated;
+            }
+            RemoteData::Stats(stats) => {
+                self.stats = stats;
+            }
+            RemoteData::ConnectionEvent(ref event) => {
+                self.load_panel.update(&remote_data);
+                self.log_connection_event(event.clone())
+            }
+            RemoteData::DeviceInfo(device_info) => self.device_info = Some(device_info),
+            RemoteData::LogMessage(message) => {
+                self.device_log.push_front(message);
+                self.device_log.truncate(MAX_DEVICE_LOG);
+            }
+            RemoteData::LoadControl(_) | RemoteData::LoadOutput(_) => {
+                self.load_panel.update(&remote_data);
+            }
+        }
+        if bupdate_battery2 {
+            self.update_battery2();
+        }
+    }
+
+    fn log_connection_event(&mut self, event: ConnectionEvent) {
+        if let ConnectionEvent::Error { kind, .. } = &event {
+            *self.error_counts.entry(*kind).or_default() += 1;
+        }
+        self.connection_log
+            .push_front((chrono::Local::now(), event));
+        self.connection_log.truncate(MAX_CONNECTION_LOG);
+    }
+
+    /// Draws the charts of `others` as lines into the matching charts of this device.
+    /// Their time window follows this device's, an empty map removes the overlays.
+    pub fn overlay(&mut self, others: &mut BTreeMap<DeviceId, AllCharts>) {
+        let windows: Vec<(f32, f32)> = self
+            .charts()
+            .iter()
+            .map(|chart| (chart.min_time, chart.max_time))
+            .collect();
+        let mut overlays = vec![Vec::new(); windows.len()];
+        for (device, other) in others.iter_mut() {
+            let mut ix = 0;
+            other.map_charts(|chart| {
+                (chart.min_time, chart.max_time) = windows[ix];
+                chart.accumulate_into_view_buffer();
+                overlays[ix].push((
+                    device.to_string(),
+                    chart.display_data.iter().cloned().collect(),
+                ));
+                ix += 1;
+            });
+        }
+        let mut overlays = overlays.into_iter();
+        self.map_charts(|chart| chart.overlays = overlays.next().unwrap_or_default());
+    }
+
+    /// fills the charts with the samples stored earlier today
+    pub fn load_history(&mut self, store: &Store) {
+        for series in Series::ALL {
+            let history = match store.load_history(series) {
+                Ok(history) => history,
+                Err(e) => {
+                    println!("could not load history of {series:?}: {e}");
+                    continue;
+                }
+            };
+            if history.values.is_empty() {
+                continue;
+            }
+            let chart = match series {
+                Series::BatteryVoltage => &mut self.battery1,
+                Series::BatteryPackVoltage => &mut self.battery_pack,
+                Series::PVVoltage => &mut self.pv,
+                Series::PVPower => &mut self.pv_power,
+                Series::InverterPower => &mut self.inverter_power,
+            };
+            chart.data = history.values.into();
+            chart.history_end_ms = Some(history.end_ms);
+            if history.tick_len > 0.0 {
+                chart.tick_len = history.tick_len;
+            }
+            chart.accumulate_into_view_buffer();
+        }
+        self.battery2.tick_len = self.battery1.tick_len;
+        self.update_battery2();
+    }
+
     pub fn update_battery2(&mut self) {
         let voltages = self
             .battery_pack
@@ -533,6 +1173,7 @@ impl AllCharts {
             .zip(self.battery1.data.iter())
             .map(|(bp_voltage, b1_voltage)| bp_voltage - b1_voltage);
         self.battery2.data = voltages.collect();
+        self.battery2.history_end_ms = self.battery1.history_end_ms;
         self.battery2.accumulate_into_view_buffer();
     }
 
@@ -562,10 +1203,67 @@ impl AllCharts {
         self.inverter_power.max_y = self.max_y * 6.0;
     }
 
+    /// Feeds a realtime reading into the realtime charts
+    pub fn update_realtime_charts(&mut self, realtime: &Realtime) {
+        let now = Instant::now();
+        let elapsed = self
+            .last_realtime
+            .map(|last| now.duration_since(last).as_secs_f32());
+        self.last_realtime = Some(now);
+        let values = realtime.values();
+        for ((field, ..), chart) in REALTIME_CHARTS.iter().zip(&mut self.realtime_charts) {
+            if let Some((_, _, value)) = values.iter().find(|(name, ..)| name == field) {
+                chart.push_reading(*value, elapsed);
+            }
+        }
+    }
+
+    /// Time between two polled realtime readings
+    pub fn set_realtime_tick_len(&mut self, tick_len: f32) {
+        for chart in &mut self.realtime_charts {
+            chart.tick_len = tick_len;
+        }
+    }
+
     pub fn clear_caches(&mut self) {
         self.map_charts(|vc| vc.cache.clear());
     }
 
+    /// Exports the visible time range of the selected chart(s) into `export_dir`
+    pub fn export(&mut self, format: ExportFormat) {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let columns: Vec<export::Column> = self
+            .charts()
+            .into_iter()
+            .filter(|chart| {
+                self.export_selection == EXPORT_ALL || self.export_selection == chart.title
+            })
+            .map(|chart| {
+                let end_ms = chart.history_end_ms.unwrap_or(now_ms);
+                export::Column::from_chart(chart, end_ms, chart.min_time..=chart.max_time)
+            })
+            .collect();
+        let name = export::column_name(&self.export_selection);
+        let path = export::export_path(&self.export_dir, &name, format);
+        self.export_status = match export::export_to_file(&path, &columns, format) {
+            Ok(()) => format!("exported to {}", path.display()),
+            Err(e) => format!("export failed: {e}"),
+        };
+    }
+
+    fn charts(&self) -> Vec<&CustomChart> {
+        let mut charts = vec![
+            &self.battery_pack,
+            &self.battery1,
+            &self.battery2,
+            &self.pv,
+            &self.pv_power,
+            &self.inverter_power,
+        ];
+        charts.extend(&self.realtime_charts);
+        charts
+    }
+
     fn map_charts<F: FnMut(&mut CustomChart)>(&mut self, f: F) {
         [
             &mut self.battery_pack,
@@ -575,7 +1273,9 @@ impl AllCharts {
             &mut self.pv_power,
             &mut self.inverter_power,
         ]
-        .map(f);
+        .into_iter()
+        .chain(&mut self.realtime_charts)
+        .for_each(f);
     }
 }
 
@@ -583,8 +1283,12 @@ impl AllCharts {
 pub enum SelectedTab {
     VoltageCharts,
     PowerCharts,
+    RealtimeCharts,
     Stats,
     Settings,
+    Connection,
+    DeviceLog,
+    Load,
 }
 
 fn spacer() -> Space {
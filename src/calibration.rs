@@ -0,0 +1,359 @@
+use crate::{
+    command::BufferType,
+    config::AdcConfig,
+    remote_data::RemoteData,
+    tracer_an::{Realtime, Stats},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// stored next to the sample segments in the data dir
+pub const CALIBRATION_FILE: &str = "calibration.toml";
+
+/// Correction of the voltage computed by `AdcConfig` for one channel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelCalibration {
+    pub gain: f32,
+    pub offset: f32,
+    /// (uncalibrated, true) voltages sorted by the uncalibrated voltage.
+    /// With two or more points the table is interpolated and `gain`/`offset` are ignored.
+    pub points: Vec<(f32, f32)>,
+}
+
+impl Default for ChannelCalibration {
+    fn default() -> Self {
+        ChannelCalibration {
+            gain: 1.0,
+            offset: 0.0,
+            points: Vec::new(),
+        }
+    }
+}
+
+impl ChannelCalibration {
+    pub fn apply(&self, voltage: f32) -> f32 {
+        if self.points.len() < 2 {
+            return self.gain * voltage + self.offset;
+        }
+        // outside of the table the first or last segment is extended
+        let ix = self
+            .points
+            .windows(2)
+            .position(|w| voltage <= w[1].0)
+            .unwrap_or(self.points.len() - 2);
+        let ((x0, y0), (x1, y1)) = (self.points[ix], self.points[ix + 1]);
+        y0 + (voltage - x0) * (y1 - y0) / (x1 - x0)
+    }
+
+    /// Least squares line through the points, a single point only determines the gain
+    pub fn fit_linear(points: &[(f32, f32)]) -> Option<ChannelCalibration> {
+        match points {
+            [] => None,
+            [(x, y)] if *x != 0.0 => Some(ChannelCalibration {
+                gain: y / x,
+                ..Default::default()
+            }),
+            [_] => None,
+            _ => {
+                let n = points.len() as f32;
+                let mean_x = points.iter().map(|p| p.0).sum::<f32>() / n;
+                let mean_y = points.iter().map(|p| p.1).sum::<f32>() / n;
+                let var_x: f32 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+                if var_x == 0.0 {
+                    return None;
+                }
+                let cov: f32 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+                let gain = cov / var_x;
+                Some(ChannelCalibration {
+                    gain,
+                    offset: mean_y - gain * mean_x,
+                    points: Vec::new(),
+                })
+            }
+        }
+    }
+
+    /// Interpolation table through the points, needs two distinct uncalibrated voltages
+    pub fn piecewise(points: &[(f32, f32)]) -> Option<ChannelCalibration> {
+        let mut points = points.to_vec();
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        points.dedup_by(|a, b| a.0 == b.0);
+        if points.len() < 2 {
+            return None;
+        }
+        Some(ChannelCalibration {
+            points,
+            ..Default::default()
+        })
+    }
+}
+
+/// The adc conversion plus a correction for every voltage channel
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Calibration {
+    #[serde(skip)]
+    pub adc: AdcConfig,
+    pub pv: ChannelCalibration,
+    pub battery1: ChannelCalibration,
+    pub battery_pack: ChannelCalibration,
+}
+
+impl Calibration {
+    pub fn new(adc: AdcConfig) -> Self {
+        Calibration {
+            adc,
+            ..Default::default()
+        }
+    }
+
+    /// `None` for the power buffers
+    pub fn channel(&self, buffer_type: BufferType) -> Option<&ChannelCalibration> {
+        match buffer_type {
+            BufferType::PVVoltage => Some(&self.pv),
+            BufferType::Battery1Voltage => Some(&self.battery1),
+            BufferType::BatteryPackVoltage => Some(&self.battery_pack),
+            BufferType::PVPower | BufferType::InverterPower => None,
+        }
+    }
+
+    pub fn channel_mut(&mut self, buffer_type: BufferType) -> Option<&mut ChannelCalibration> {
+        match buffer_type {
+            BufferType::PVVoltage => Some(&mut self.pv),
+            BufferType::Battery1Voltage => Some(&mut self.battery1),
+            BufferType::BatteryPackVoltage => Some(&mut self.battery_pack),
+            BufferType::PVPower | BufferType::InverterPower => None,
+        }
+    }
+
+    pub fn to_voltage(&self, buffer_type: BufferType, adc_reading: u16) -> f32 {
+        let voltage = self.adc.reading_to_voltage(adc_reading);
+        match self.channel(buffer_type) {
+            Some(channel) => channel.apply(voltage),
+            None => voltage,
+        }
+    }
+
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join(CALIBRATION_FILE)
+    }
+
+    /// Uncalibrated channels if the file does not exist yet
+    pub fn load(dir: &Path, adc: AdcConfig) -> io::Result<Calibration> {
+        let text = match fs::read_to_string(Self::path(dir)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Calibration::new(adc)),
+            Err(e) => return Err(e),
+        };
+        let calibration: Calibration = toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Calibration { adc, ..calibration })
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let text = toml::to_string(self).map_err(io::Error::other)?;
+        fs::write(Self::path(dir), text)
+    }
+}
+
+/// Where the true voltage of a calibration point comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    /// `Realtime` pv voltage measured by the charge controller
+    ModbusPvVoltage,
+    /// battery voltage from the controller's `Stats`
+    ModbusBatteryVoltage,
+    /// typed in, e.g. read from a multimeter
+    Manual,
+}
+
+impl Reference {
+    pub const ALL: [Reference; 3] = [
+        Reference::ModbusPvVoltage,
+        Reference::ModbusBatteryVoltage,
+        Reference::Manual,
+    ];
+}
+
+impl Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reference::ModbusPvVoltage => write!(f, "modbus pv voltage"),
+            Reference::ModbusBatteryVoltage => write!(f, "modbus battery voltage"),
+            Reference::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+/// How the wizard turns the captured points into a channel calibration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationFit {
+    Linear,
+    Piecewise,
+    /// back to the uncorrected adc conversion
+    Reset,
+}
+
+/// State of the calibration wizard: collects (adc voltage, reference voltage) pairs for a channel
+#[derive(Debug, Clone)]
+pub struct CalibrationWizard {
+    pub channel: BufferType,
+    pub reference: Reference,
+    pub manual_reference: String,
+    pub points: Vec<(f32, f32)>,
+    pub status: String,
+    /// uncalibrated mean voltage of the last buffer per channel: pv, battery1, battery pack
+    latest: [Option<f32>; 3],
+}
+
+impl Default for CalibrationWizard {
+    fn default() -> Self {
+        CalibrationWizard {
+            channel: BufferType::PVVoltage,
+            reference: Reference::ModbusPvVoltage,
+            manual_reference: String::new(),
+            points: Vec::new(),
+            status: String::new(),
+            latest: [None; 3],
+        }
+    }
+}
+
+impl CalibrationWizard {
+    pub const CHANNELS: [BufferType; 3] = [
+        BufferType::PVVoltage,
+        BufferType::Battery1Voltage,
+        BufferType::BatteryPackVoltage,
+    ];
+
+    fn channel_index(channel: BufferType) -> Option<usize> {
+        Self::CHANNELS.iter().position(|c| *c == channel)
+    }
+
+    /// Remembers the mean of every voltage buffer before it is converted
+    pub fn observe(&mut self, remote_data: &RemoteData, adc: &AdcConfig) {
+        let (Some(channel), Some(readings)) = (remote_data.buffer_type(), remote_data.readings())
+        else {
+            return;
+        };
+        let (Some(ix), false) = (Self::channel_index(channel), readings.is_empty()) else {
+            return;
+        };
+        let mean = readings.iter().map(|r| *r as f32).sum::<f32>() / readings.len() as f32;
+        // the adc conversion is linear, so converting the mean reading is the mean voltage
+        self.latest[ix] = Some(adc.reading_to_voltage(1) * mean);
+    }
+
+    pub fn latest(&self) -> Option<f32> {
+        self.latest[Self::channel_index(self.channel)?]
+    }
+
+    pub fn reference_voltage(&self, realtime: &Realtime, stats: &Stats) -> Option<f32> {
+        match self.reference {
+            Reference::ModbusPvVoltage => Some(realtime.pv_voltage()),
+            Reference::ModbusBatteryVoltage => Some(stats.battery_voltage()),
+            Reference::Manual => self.manual_reference.trim().parse().ok(),
+        }
+        .filter(|voltage| *voltage > 0.0)
+    }
+
+    /// Adds the current pair of adc and reference voltage
+    pub fn capture(&mut self, realtime: &Realtime, stats: &Stats) {
+        match (self.latest(), self.reference_voltage(realtime, stats)) {
+            (Some(adc), Some(reference)) => {
+                self.points.push((adc, reference));
+                self.status = format!("captured {adc:.3} V -> {reference:.3} V");
+            }
+            (None, _) => self.status = "no adc reading for this channel yet".to_string(),
+            (_, None) => self.status = "no reference voltage yet".to_string(),
+        }
+    }
+
+    pub fn fit(&self, fit: CalibrationFit) -> Option<ChannelCalibration> {
+        match fit {
+            CalibrationFit::Linear => ChannelCalibration::fit_linear(&self.points),
+            CalibrationFit::Piecewise => ChannelCalibration::piecewise(&self.points),
+            CalibrationFit::Reset => Some(ChannelCalibration::default()),
+        }
+    }
+
+    pub fn select_channel(&mut self, channel: BufferType) {
+        self.channel = channel;
+        self.points.clear();
+        self.status.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_fit() {
+        let fit =
+            ChannelCalibration::fit_linear(&[(10.0, 21.0), (20.0, 41.0), (30.0, 61.0)]).unwrap();
+        assert!((fit.gain - 2.0).abs() < 1e-4);
+        assert!((fit.offset - 1.0).abs() < 1e-3);
+        let fit = ChannelCalibration::fit_linear(&[(10.0, 11.0)]).unwrap();
+        assert_eq!(fit.apply(20.0), 22.0);
+        assert!(ChannelCalibration::fit_linear(&[(10.0, 11.0), (10.0, 12.0)]).is_none());
+    }
+
+    #[test]
+    fn piecewise_interpolation() {
+        let table =
+            ChannelCalibration::piecewise(&[(20.0, 22.0), (10.0, 10.0), (30.0, 30.0)]).unwrap();
+        assert_eq!(table.apply(10.0), 10.0);
+        assert_eq!(table.apply(15.0), 16.0);
+        assert_eq!(table.apply(25.0), 26.0);
+        // extrapolated with the outer segments
+        assert_eq!(table.apply(0.0), -2.0);
+        assert_eq!(table.apply(40.0), 38.0);
+    }
+
+    #[test]
+    fn persisted_per_channel() {
+        let dir = std::env::temp_dir().join(format!("epmon_calibration_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let adc = AdcConfig::default();
+        assert_eq!(Calibration::load(&dir, adc).unwrap(), Calibration::new(adc));
+
+        let mut calibration = Calibration::new(adc);
+        calibration.battery_pack.gain = 1.05;
+        calibration.pv = ChannelCalibration::piecewise(&[(0.0, 0.0), (50.0, 51.0)]).unwrap();
+        calibration.save(&dir).unwrap();
+        let loaded = Calibration::load(&dir, adc).unwrap();
+        assert_eq!(loaded, calibration);
+        assert_eq!(
+            loaded.to_voltage(BufferType::BatteryPackVoltage, 100),
+            1.05 * adc.reading_to_voltage(100)
+        );
+        assert_eq!(
+            loaded.to_voltage(BufferType::Battery1Voltage, 100),
+            adc.reading_to_voltage(100)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wizard_captures_pairs() {
+        let mut wizard = CalibrationWizard {
+            reference: Reference::Manual,
+            manual_reference: "24.5".to_string(),
+            ..Default::default()
+        };
+        let adc = AdcConfig::default();
+        wizard.capture(&Realtime::default(), &Stats::default());
+        assert!(wizard.points.is_empty());
+        wizard.observe(&RemoteData::PVVoltage(vec![100, 300]), &adc);
+        wizard.capture(&Realtime::default(), &Stats::default());
+        let (voltage, reference) = wizard.points[0];
+        assert!((voltage - adc.reading_to_voltage(200)).abs() < 1e-4);
+        assert_eq!(reference, 24.5);
+    }
+}
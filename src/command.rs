@@ -1,9 +1,102 @@
 pub const COMMAND_SIZE: usize = 33;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// every v2 frame starts with these bytes
+pub const FRAME_MAGIC: [u8; 2] = *b"EP";
+/// registers written by one `Command::ModbusWriteHoldings`
+pub const MAX_WRITE_HOLDINGS: usize = 14;
+
+/// the framed protocol, the legacy protocol sends bare commands and answers
+pub const PROTOCOL_VERSION: u8 = 2;
+/// magic, version, sequence number, payload length and crc
+pub const FRAME_HEADER_SIZE: usize = 8;
+
+/// Framing spoken on a connection, every connection starts with `Legacy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Legacy,
+    V2,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Legacy => write!(f, "legacy framing"),
+            Protocol::V2 => write!(f, "framing v{PROTOCOL_VERSION}"),
+        }
+    }
+}
+
+/// First byte of every command, `Command::opcode` and `Command::try_from` both go through it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
+pub enum Opcode {
+    GetVoltageIntervalms = 0,
+    GetPowerIntervalms = 1,
+    GetVoltageBufferSize = 2,
+    GetBuffer = 3,
+    RetransmitBuffers = 4,
+    ModbusGetHoldings = 5,
+    ModbusGetInputRegisters = 6,
+    ModbusSetHoldings = 7,
+    GetLastLogMessage = 8,
+    Heartbeat = 9,
+    NegotiateProtocol = 10,
+    GetDeviceInfo = 11,
+    ModbusWriteHoldings = 12,
+    ModbusGetCoil = 13,
+    ModbusSetCoil = 14,
+}
+
+impl Opcode {
+    /// ordered by value, every opcode is its index
+    pub const ALL: [Opcode; 15] = [
+        Opcode::GetVoltageIntervalms,
+        Opcode::GetPowerIntervalms,
+        Opcode::GetVoltageBufferSize,
+        Opcode::GetBuffer,
+        Opcode::RetransmitBuffers,
+        Opcode::ModbusGetHoldings,
+        Opcode::ModbusGetInputRegisters,
+        Opcode::ModbusSetHoldings,
+        Opcode::GetLastLogMessage,
+        Opcode::Heartbeat,
+        Opcode::NegotiateProtocol,
+        Opcode::GetDeviceInfo,
+        Opcode::ModbusWriteHoldings,
+        Opcode::ModbusGetCoil,
+        Opcode::ModbusSetCoil,
+    ];
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Opcode::GetVoltageIntervalms,
+            1 => Opcode::GetPowerIntervalms,
+            2 => Opcode::GetVoltageBufferSize,
+            3 => Opcode::GetBuffer,
+            4 => Opcode::RetransmitBuffers,
+            5 => Opcode::ModbusGetHoldings,
+            6 => Opcode::ModbusGetInputRegisters,
+            7 => Opcode::ModbusSetHoldings,
+            8 => Opcode::GetLastLogMessage,
+            9 => Opcode::Heartbeat,
+            10 => Opcode::NegotiateProtocol,
+            11 => Opcode::GetDeviceInfo,
+            12 => Opcode::ModbusWriteHoldings,
+            13 => Opcode::ModbusGetCoil,
+            14 => Opcode::ModbusSetCoil,
+            _ => return Err(()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Command {
-    GetVoltageIntervalms = 0x0,
+    GetVoltageIntervalms,
     GetPowerIntervalms,
     GetVoltageBufferSize,
     GetBuffer(BufferType),
@@ -22,12 +115,32 @@ pub enum Command {
         new_holding_values: [u16; 15],
     },
     GetLastLogMessage,
+    /// the device echoes the sequence number, sent while the connection is otherwise idle
+    Heartbeat(u8),
+    /// Asks the device to switch to the given protocol version. The device answers with
+    /// the version it switched to, old firmware does not answer at all.
+    NegotiateProtocol(u8),
+    /// firmware version and capabilities, answered by firmware speaking the v2 protocol
+    GetDeviceInfo,
+    /// writes the first `count` values, see `Command::write_holdings`
+    ModbusWriteHoldings {
+        register_address: u16,
+        count: u8,
+        values: [u16; MAX_WRITE_HOLDINGS],
+    },
+    /// answered with 1 for a set coil and 0 otherwise
+    ModbusGetCoil(u16),
+    /// switches a coil, the device does not answer
+    ModbusSetCoil {
+        register_address: u16,
+        on: bool,
+    },
 }
 
 impl Command {
     pub fn to_bytes(&self) -> [u8; COMMAND_SIZE] {
         let mut res = [0; COMMAND_SIZE];
-        res[0] = self.discriminant();
+        res[0] = self.opcode() as u8;
         match self {
             Command::ModbusGetInputRegisters {
                 register_address,
@@ -57,14 +170,84 @@ impl Command {
                     holding_bytes[ix * 2 + 1] = chunk[1];
                 }
             }
+            Command::ModbusWriteHoldings {
+                register_address,
+                count,
+                values,
+            } => {
+                let [b1, b2] = register_address.to_be_bytes();
+                res[1] = b1;
+                res[2] = b2;
+                res[3] = *count;
+                for (ix, val) in values.iter().take(*count as usize).enumerate() {
+                    let [v1, v2] = val.to_be_bytes();
+                    res[4 + ix * 2] = v1;
+                    res[5 + ix * 2] = v2;
+                }
+            }
+            Command::ModbusGetCoil(register_address) => {
+                let [b1, b2] = register_address.to_be_bytes();
+                res[1] = b1;
+                res[2] = b2;
+            }
+            Command::ModbusSetCoil {
+                register_address,
+                on,
+            } => {
+                let [b1, b2] = register_address.to_be_bytes();
+                res[1] = b1;
+                res[2] = b2;
+                res[3] = *on as u8;
+            }
             Command::GetBuffer(buffer_type) => {
                 res[1] = *buffer_type as u8;
             }
+            Command::Heartbeat(sequence) | Command::NegotiateProtocol(sequence) => {
+                res[1] = *sequence;
+            }
             _ => {}
         }
         res
     }
 
+    /// The command in a v2 frame, the answer carries the same sequence number
+    pub fn to_frame(&self, sequence: u8) -> Vec<u8> {
+        frame(sequence, &self.to_bytes())
+    }
+
+    /// Writes `values` to consecutive holdings, at most `MAX_WRITE_HOLDINGS` at once
+    pub fn write_holdings(register_address: u16, values: &[u16]) -> Result<Command, String> {
+        if values.is_empty() || values.len() > MAX_WRITE_HOLDINGS {
+            return Err(format!(
+                "{} holdings can not be written at once, 1 to {MAX_WRITE_HOLDINGS} can",
+                values.len()
+            ));
+        }
+        let mut padded = [0; MAX_WRITE_HOLDINGS];
+        padded[..values.len()].copy_from_slice(values);
+        Ok(Command::ModbusWriteHoldings {
+            register_address,
+            count: values.len() as u8,
+            values: padded,
+        })
+    }
+
+    /// The holdings a command writes, starting at the returned address
+    pub fn written_holdings(&self) -> Option<(u16, &[u16])> {
+        match self {
+            Command::ModbusSetHoldings {
+                register_address,
+                new_holding_values,
+            } => Some((*register_address, &new_holding_values[..])),
+            Command::ModbusWriteHoldings {
+                register_address,
+                count,
+                values,
+            } => Some((*register_address, &values[..*count as usize])),
+            _ => None,
+        }
+    }
+
     pub fn size(&self) -> u8 {
         match self {
             Command::ModbusGetHoldings { size, .. } => *size,
@@ -73,48 +256,158 @@ impl Command {
         }
     }
 
-    fn discriminant(&self) -> u8 {
-        // WHYYYYYY !!!!!!!!!!!!!!!!!!!!!!
-        unsafe { *(self as *const Self as *const u8) }
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Command::GetVoltageIntervalms => Opcode::GetVoltageIntervalms,
+            Command::GetPowerIntervalms => Opcode::GetPowerIntervalms,
+            Command::GetVoltageBufferSize => Opcode::GetVoltageBufferSize,
+            Command::GetBuffer(_) => Opcode::GetBuffer,
+            Command::RetransmitBuffers => Opcode::RetransmitBuffers,
+            Command::ModbusGetHoldings { .. } => Opcode::ModbusGetHoldings,
+            Command::ModbusGetInputRegisters { .. } => Opcode::ModbusGetInputRegisters,
+            Command::ModbusSetHoldings { .. } => Opcode::ModbusSetHoldings,
+            Command::GetLastLogMessage => Opcode::GetLastLogMessage,
+            Command::Heartbeat(_) => Opcode::Heartbeat,
+            Command::NegotiateProtocol(_) => Opcode::NegotiateProtocol,
+            Command::GetDeviceInfo => Opcode::GetDeviceInfo,
+            Command::ModbusWriteHoldings { .. } => Opcode::ModbusWriteHoldings,
+            Command::ModbusGetCoil(_) => Opcode::ModbusGetCoil,
+            Command::ModbusSetCoil { .. } => Opcode::ModbusSetCoil,
+        }
     }
 }
 
 impl TryFrom<&[u8]> for Command {
     type Error = ();
 
+    /// every opcode has its own arm, so a new opcode does not compile without a parser
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        match value {
-            [0, ..] => Ok(Command::GetVoltageIntervalms),
-            [1, ..] => Ok(Command::GetPowerIntervalms),
-            [2, ..] => Ok(Command::GetVoltageBufferSize),
-            [3, 0, ..] => Ok(Command::GetBuffer(BufferType::PVVoltage)),
-            [3, 1, ..] => Ok(Command::GetBuffer(BufferType::PVPower)),
-            [3, 2, ..] => Ok(Command::GetBuffer(BufferType::Battery1Voltage)),
-            [3, 3, ..] => Ok(Command::GetBuffer(BufferType::BatteryPackVoltage)),
-            [3, 4, ..] => Ok(Command::GetBuffer(BufferType::InverterPower)),
-            [4, ..] => Ok(Command::RetransmitBuffers),
-            [5, h1, h2, h3, ..] => Ok(Command::ModbusGetHoldings {
-                register_address: u16::from_be_bytes([*h1, *h2]),
-                size: *h3,
-            }),
-            [6, h1, h2, h3, ..] => Ok(Command::ModbusGetInputRegisters {
-                register_address: u16::from_be_bytes([*h1, *h2]),
-                size: *h3,
-            }),
-            [7, h1, h2, new_values @ ..] => {
+        let (opcode, args) = value.split_first().ok_or(())?;
+        let address = |h1: &u8, h2: &u8| u16::from_be_bytes([*h1, *h2]);
+        match Opcode::try_from(*opcode)? {
+            Opcode::GetVoltageIntervalms => Ok(Command::GetVoltageIntervalms),
+            Opcode::GetPowerIntervalms => Ok(Command::GetPowerIntervalms),
+            Opcode::GetVoltageBufferSize => Ok(Command::GetVoltageBufferSize),
+            Opcode::GetBuffer => {
+                let [buffer_type, ..] = args else {
+                    return Err(());
+                };
+                BufferType::try_from(*buffer_type).map(Command::GetBuffer)
+            }
+            Opcode::RetransmitBuffers => Ok(Command::RetransmitBuffers),
+            Opcode::ModbusGetHoldings => {
+                let [h1, h2, size, ..] = args else {
+                    return Err(());
+                };
+                Ok(Command::ModbusGetHoldings {
+                    register_address: address(h1, h2),
+                    size: *size,
+                })
+            }
+            Opcode::ModbusGetInputRegisters => {
+                let [h1, h2, size, ..] = args else {
+                    return Err(());
+                };
+                Ok(Command::ModbusGetInputRegisters {
+                    register_address: address(h1, h2),
+                    size: *size,
+                })
+            }
+            Opcode::ModbusSetHoldings => {
+                let [h1, h2, new_values @ ..] = args else {
+                    return Err(());
+                };
+                if new_values.len() < 30 {
+                    return Err(());
+                }
                 let mut new_holding_values = [0; 15];
-                for (ix, chunk) in new_values.chunks(2).enumerate() {
-                    new_holding_values[ix] = u16::from_be_bytes([chunk[0], chunk[1]]);
+                for (value, chunk) in new_holding_values
+                    .iter_mut()
+                    .zip(new_values.chunks_exact(2))
+                {
+                    *value = u16::from_be_bytes([chunk[0], chunk[1]]);
                 }
                 Ok(Command::ModbusSetHoldings {
-                    register_address: u16::from_be_bytes([*h1, *h2]),
+                    register_address: address(h1, h2),
                     new_holding_values,
                 })
             }
-            [8, ..] => Ok(Command::GetLastLogMessage),
-            _ => Err(()),
+            Opcode::GetLastLogMessage => Ok(Command::GetLastLogMessage),
+            Opcode::Heartbeat => {
+                let [sequence, ..] = args else {
+                    return Err(());
+                };
+                Ok(Command::Heartbeat(*sequence))
+            }
+            Opcode::NegotiateProtocol => {
+                let [version, ..] = args else {
+                    return Err(());
+                };
+                Ok(Command::NegotiateProtocol(*version))
+            }
+            Opcode::GetDeviceInfo => Ok(Command::GetDeviceInfo),
+            Opcode::ModbusWriteHoldings => {
+                let [h1, h2, count, new_values @ ..] = args else {
+                    return Err(());
+                };
+                let count = *count as usize;
+                if !(1..=MAX_WRITE_HOLDINGS).contains(&count) || new_values.len() < count * 2 {
+                    return Err(());
+                }
+                let values: Vec<u16> = new_values
+                    .chunks_exact(2)
+                    .take(count)
+                    .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+                    .collect();
+                Command::write_holdings(address(h1, h2), &values).map_err(|_| ())
+            }
+            Opcode::ModbusGetCoil => {
+                let [h1, h2, ..] = args else {
+                    return Err(());
+                };
+                Ok(Command::ModbusGetCoil(address(h1, h2)))
+            }
+            Opcode::ModbusSetCoil => {
+                let [h1, h2, on @ (0 | 1), ..] = args else {
+                    return Err(());
+                };
+                Ok(Command::ModbusSetCoil {
+                    register_address: address(h1, h2),
+                    on: *on == 1,
+                })
+            }
+        }
+    }
+}
+
+/// A v2 frame around `payload`, commands and answers are framed alike
+pub fn frame(sequence: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&FRAME_MAGIC);
+    frame.push(PROTOCOL_VERSION);
+    frame.push(sequence);
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    let crc = crc16(&frame[2..], payload);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// CRC-16/MODBUS over the frame header after the magic and the payload,
+/// the device computes it with the same routine as its modbus frames
+pub fn crc16(header: &[u8], payload: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in header.iter().chain(payload) {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
         }
     }
+    crc
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -125,3 +418,209 @@ pub enum BufferType {
     BatteryPackVoltage,
     InverterPower,
 }
+
+impl BufferType {
+    pub const ALL: [BufferType; 5] = [
+        BufferType::PVVoltage,
+        BufferType::PVPower,
+        BufferType::Battery1Voltage,
+        BufferType::BatteryPackVoltage,
+        BufferType::InverterPower,
+    ];
+}
+
+impl TryFrom<u8> for BufferType {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => BufferType::PVVoltage,
+            1 => BufferType::PVPower,
+            2 => BufferType::Battery1Voltage,
+            3 => BufferType::BatteryPackVoltage,
+            4 => BufferType::InverterPower,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl std::fmt::Display for BufferType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BufferType::PVVoltage => write!(f, "PV voltage"),
+            BufferType::PVPower => write!(f, "PV power"),
+            BufferType::Battery1Voltage => write!(f, "battery1 voltage"),
+            BufferType::BatteryPackVoltage => write!(f, "battery pack voltage"),
+            BufferType::InverterPower => write!(f, "inverter power"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn any_command() -> impl Strategy<Value = Command> {
+        let buffer_type = prop::sample::select(BufferType::ALL.to_vec());
+        prop_oneof![
+            Just(Command::GetVoltageIntervalms),
+            Just(Command::GetPowerIntervalms),
+            Just(Command::GetVoltageBufferSize),
+            buffer_type.prop_map(Command::GetBuffer),
+            Just(Command::RetransmitBuffers),
+            (any::<u16>(), any::<u8>()).prop_map(|(register_address, size)| {
+                Command::ModbusGetHoldings {
+                    register_address,
+                    size,
+                }
+            }),
+            (any::<u16>(), any::<u8>()).prop_map(|(register_address, size)| {
+                Command::ModbusGetInputRegisters {
+                    register_address,
+                    size,
+                }
+            }),
+            (any::<u16>(), any::<[u16; 15]>()).prop_map(
+                |(register_address, new_holding_values)| {
+                    Command::ModbusSetHoldings {
+                        register_address,
+                        new_holding_values,
+                    }
+                }
+            ),
+            Just(Command::GetLastLogMessage),
+            any::<u8>().prop_map(Command::Heartbeat),
+            any::<u8>().prop_map(Command::NegotiateProtocol),
+            Just(Command::GetDeviceInfo),
+            (
+                any::<u16>(),
+                prop::collection::vec(any::<u16>(), 1..=MAX_WRITE_HOLDINGS)
+            )
+                .prop_map(|(register_address, values)| {
+                    Command::write_holdings(register_address, &values).unwrap()
+                }),
+            any::<u16>().prop_map(Command::ModbusGetCoil),
+            (any::<u16>(), any::<bool>()).prop_map(|(register_address, on)| {
+                Command::ModbusSetCoil {
+                    register_address,
+                    on,
+                }
+            }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn commands_round_trip(command in any_command()) {
+            prop_assert_eq!(command.to_bytes()[0], command.opcode() as u8);
+            prop_assert_eq!(Command::try_from(&command.to_bytes()[..]), Ok(command));
+        }
+
+        #[test]
+        fn arbitrary_bytes_parse_or_fail(bytes in prop::collection::vec(any::<u8>(), 0..40)) {
+            if let Ok(command) = Command::try_from(&bytes[..]) {
+                prop_assert_eq!(command.to_bytes()[0], bytes[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn opcode_table() {
+        for (ix, opcode) in Opcode::ALL.into_iter().enumerate() {
+            assert_eq!(opcode as usize, ix);
+            assert_eq!(Opcode::try_from(ix as u8), Ok(opcode));
+            // arguments every opcode accepts, the parsed command encodes to the same opcode
+            let mut bytes = vec![ix as u8, 0, 0, 1];
+            bytes.resize(34, 0);
+            let command = Command::try_from(&bytes[..]).unwrap();
+            assert_eq!(command.opcode(), opcode);
+        }
+        for value in Opcode::ALL.len()..=u8::MAX as usize {
+            assert_eq!(Opcode::try_from(value as u8), Err(()));
+        }
+        for (ix, buffer_type) in BufferType::ALL.into_iter().enumerate() {
+            assert_eq!(buffer_type as usize, ix);
+            assert_eq!(BufferType::try_from(ix as u8), Ok(buffer_type));
+        }
+        for value in BufferType::ALL.len()..=u8::MAX as usize {
+            assert_eq!(BufferType::try_from(value as u8), Err(()));
+        }
+    }
+
+    #[test]
+    fn all_lists_every_variant() {
+        // exhaustive, a new variant does not compile until it is counted here
+        let opcodes = match Opcode::Heartbeat {
+            Opcode::GetVoltageIntervalms
+            | Opcode::GetPowerIntervalms
+            | Opcode::GetVoltageBufferSize
+            | Opcode::GetBuffer
+            | Opcode::RetransmitBuffers
+            | Opcode::ModbusGetHoldings
+            | Opcode::ModbusGetInputRegisters
+            | Opcode::ModbusSetHoldings
+            | Opcode::GetLastLogMessage
+            | Opcode::Heartbeat
+            | Opcode::NegotiateProtocol
+            | Opcode::GetDeviceInfo
+            | Opcode::ModbusWriteHoldings
+            | Opcode::ModbusGetCoil
+            | Opcode::ModbusSetCoil => 15,
+        };
+        assert_eq!(Opcode::ALL.len(), opcodes);
+        let buffer_types = match BufferType::PVVoltage {
+            BufferType::PVVoltage
+            | BufferType::PVPower
+            | BufferType::Battery1Voltage
+            | BufferType::BatteryPackVoltage
+            | BufferType::InverterPower => 5,
+        };
+        assert_eq!(BufferType::ALL.len(), buffer_types);
+    }
+
+    #[test]
+    fn write_holdings() {
+        let command = Command::write_holdings(0x9013, &[1, 2, 3]).unwrap();
+        assert_eq!(
+            &command.to_bytes()[..10],
+            &[12, 0x90, 0x13, 3, 0, 1, 0, 2, 0, 3]
+        );
+        assert_eq!(command.written_holdings(), Some((0x9013, &[1, 2, 3][..])));
+        assert!(Command::write_holdings(0x9000, &[]).is_err());
+        assert!(Command::write_holdings(0x9000, &[0; MAX_WRITE_HOLDINGS + 1]).is_err());
+        let mut bytes = command.to_bytes();
+        bytes[3] = MAX_WRITE_HOLDINGS as u8 + 1;
+        assert_eq!(Command::try_from(&bytes[..]), Err(()));
+    }
+
+    #[test]
+    fn short_set_holdings_is_rejected() {
+        let mut bytes = Command::ModbusSetHoldings {
+            register_address: 0x9000,
+            new_holding_values: [1; 15],
+        }
+        .to_bytes()
+        .to_vec();
+        bytes.truncate(COMMAND_SIZE - 1);
+        assert_eq!(Command::try_from(&bytes[..]), Err(()));
+    }
+
+    #[test]
+    fn crc16_matches_modbus() {
+        assert_eq!(crc16(b"1234", b"56789"), 0x4b37);
+    }
+
+    #[test]
+    fn frame_layout() {
+        let frame = Command::Heartbeat(7).to_frame(42);
+        assert_eq!(frame.len(), FRAME_HEADER_SIZE + COMMAND_SIZE);
+        assert_eq!(&frame[..6], &[b'E', b'P', 2, 42, 0, COMMAND_SIZE as u8]);
+        let crc = u16::from_be_bytes([frame[6], frame[7]]);
+        assert_eq!(crc, crc16(&frame[2..6], &frame[FRAME_HEADER_SIZE..]));
+        assert_eq!(
+            Command::try_from(&frame[FRAME_HEADER_SIZE..]),
+            Ok(Command::Heartbeat(7))
+        );
+    }
+}
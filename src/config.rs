@@ -0,0 +1,314 @@
+use crate::{
+    arg_value,
+    http_api::{DEFAULT_HTTP_ADDRESS, DEFAULT_HTTP_PORT},
+    mqtt::MqttConfig,
+    poll_schedule::PollSchedule,
+    storage::DEFAULT_DATA_DIR,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// read from the working directory if `--config` is not given
+pub const DEFAULT_CONFIG_PATH: &str = "epmon.toml";
+
+/// Everything that can be set in the TOML configuration file.
+/// Missing keys fall back to the defaults, command line flags override the file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub server: ServerConfig,
+    pub discovery: DiscoveryConfig,
+    pub adc: AdcConfig,
+    pub gui: GuiConfig,
+    pub http: HttpConfig,
+    pub poll: PollSchedule,
+    pub mqtt: Option<MqttConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            server: Default::default(),
+            discovery: Default::default(),
+            adc: Default::default(),
+            gui: Default::default(),
+            http: Default::default(),
+            poll: Default::default(),
+            mqtt: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// the device connects to this port
+    pub tcp_port: u16,
+    /// pause between two rounds of buffer reads
+    pub loop_sleep_ms: u64,
+    /// a device that does not answer within this time is disconnected
+    pub read_timeout_ms: u64,
+    pub write_timeout_ms: u64,
+    /// a heartbeat is sent when the device was silent this long, 0 disables it
+    pub heartbeat_interval_ms: u64,
+    /// old firmware does not answer the v2 negotiation within this time and keeps
+    /// the legacy framing, 0 skips the negotiation
+    pub negotiation_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            tcp_port: 8900,
+            loop_sleep_ms: 500,
+            read_timeout_ms: 10000,
+            write_timeout_ms: 10000,
+            heartbeat_interval_ms: 5000,
+            negotiation_timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// the device listens for broadcasts and answers on this port
+    pub udp_port: u16,
+    /// broadcast address of the network the device is in
+    pub broadcast_address: String,
+    /// broadcasting stops while this many devices are connected
+    pub expected_devices: usize,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            udp_port: 9900,
+            broadcast_address: "192.168.178.255".to_string(),
+            expected_devices: 1,
+        }
+    }
+}
+
+/// `voltage = reading / max_reading * reference_voltage * divider_top / divider_bottom`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdcConfig {
+    /// sum of the voltage divider resistors
+    pub divider_top: f32,
+    /// resistor the adc measures across
+    pub divider_bottom: f32,
+    pub reference_voltage: f32,
+    /// reading at `reference_voltage`
+    pub max_reading: f32,
+}
+
+impl Default for AdcConfig {
+    fn default() -> Self {
+        AdcConfig {
+            divider_top: 20700.0,
+            divider_bottom: 124.0,
+            reference_voltage: 1.1,
+            max_reading: 4081.0,
+        }
+    }
+}
+
+impl AdcConfig {
+    pub fn reading_to_voltage(&self, adc_reading: u16) -> f32 {
+        (self.divider_top / self.divider_bottom) * self.reference_voltage * adc_reading as f32
+            / self.max_reading
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GuiConfig {
+    pub chart_height: f32,
+}
+
+impl Default for GuiConfig {
+    fn default() -> Self {
+        GuiConfig {
+            chart_height: 400.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    /// only this machine can reach the api by default, "0.0.0.0" exposes it to the network
+    pub address: String,
+    pub port: u16,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: true,
+            address: DEFAULT_HTTP_ADDRESS.to_string(),
+            port: DEFAULT_HTTP_PORT,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `--config <path>` (or `epmon.toml` if present) and applies the command line flags
+    pub fn load(args: &[String]) -> Result<Config, String> {
+        let mut config = match arg_value(args, "--config") {
+            Some(path) => Config::from_file(Path::new(path))?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Config::default(),
+        };
+        config.apply_args(args)?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+        toml::from_str(&text).map_err(|e| format!("invalid config {}: {e}", path.display()))
+    }
+
+    pub fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(args: &[String], name: &str) -> Result<Option<T>, String> {
+            arg_value(args, name)
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid {name}: {value}"))
+                })
+                .transpose()
+        }
+        if let Some(data_dir) = arg_value(args, "--data-dir") {
+            self.data_dir = PathBuf::from(data_dir);
+        }
+        if let Some(port) = parse(args, "--tcp-port")? {
+            self.server.tcp_port = port;
+        }
+        if let Some(sleep_ms) = parse(args, "--loop-sleep-ms")? {
+            self.server.loop_sleep_ms = sleep_ms;
+        }
+        if let Some(timeout_ms) = parse(args, "--read-timeout-ms")? {
+            self.server.read_timeout_ms = timeout_ms;
+        }
+        if let Some(timeout_ms) = parse(args, "--write-timeout-ms")? {
+            self.server.write_timeout_ms = timeout_ms;
+        }
+        if let Some(interval_ms) = parse(args, "--heartbeat-interval-ms")? {
+            self.server.heartbeat_interval_ms = interval_ms;
+        }
+        if let Some(timeout_ms) = parse(args, "--negotiation-timeout-ms")? {
+            self.server.negotiation_timeout_ms = timeout_ms;
+        }
+        if let Some(port) = parse(args, "--udp-port")? {
+            self.discovery.udp_port = port;
+        }
+        if let Some(address) = arg_value(args, "--broadcast-address") {
+            self.discovery.broadcast_address = address.to_string();
+        }
+        if let Some(expected_devices) = parse(args, "--expected-devices")? {
+            self.discovery.expected_devices = expected_devices;
+        }
+        if let Some(chart_height) = parse(args, "--chart-height")? {
+            self.gui.chart_height = chart_height;
+        }
+        if args.iter().any(|arg| arg == "--no-http") {
+            self.http.enabled = false;
+        }
+        if let Some(address) = arg_value(args, "--http-address") {
+            self.http.address = address.to_string();
+        }
+        if let Some(port) = parse(args, "--http-port")? {
+            self.http.port = port;
+        }
+        self.poll.apply_args(args)?;
+        MqttConfig::apply_args(&mut self.mqtt, args);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poll_schedule::PollInterval;
+    use std::time::Duration;
+
+    #[test]
+    fn file_with_overrides() {
+        let mut config: Config = toml::from_str(
+            r#"
+            data_dir = "/var/lib/epmon"
+
+            [discovery]
+            broadcast_address = "10.0.0.255"
+
+            [adc]
+            divider_top = 10000.0
+
+            [poll]
+            stats = 30
+            rated = "never"
+
+            [mqtt]
+            address = "broker:1883"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.discovery.udp_port, 9900);
+        assert_eq!(config.adc.divider_bottom, 124.0);
+        assert_eq!(
+            config.poll.stats,
+            PollInterval::Every(Duration::from_secs(30))
+        );
+        assert_eq!(config.poll.rated, PollInterval::Never);
+        assert_eq!(config.mqtt.as_ref().unwrap().base_topic, "epmon");
+
+        assert_eq!(config.http.address, "127.0.0.1");
+
+        let args: Vec<String> = [
+            "--tcp-port",
+            "8901",
+            "--broadcast-address",
+            "10.0.1.255",
+            "--http-address",
+            "0.0.0.0",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        config.apply_args(&args).unwrap();
+        assert_eq!(config.server.tcp_port, 8901);
+        assert_eq!(config.discovery.broadcast_address, "10.0.1.255");
+        assert_eq!(config.http.address, "0.0.0.0");
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/epmon"));
+
+        let args = vec!["--tcp-port".to_string(), "x".to_string()];
+        assert!(config.apply_args(&args).is_err());
+        assert!(toml::from_str::<Config>("tcp_port = 1").is_err());
+    }
+
+    #[test]
+    fn example_matches_defaults() {
+        let example: Config = toml::from_str(include_str!("../epmon.example.toml")).unwrap();
+        assert_eq!(example, Config::default());
+    }
+
+    #[test]
+    fn default_adc_conversion() {
+        let adc = AdcConfig::default();
+        assert_eq!(
+            adc.reading_to_voltage(4081),
+            (20700.0 / 124.0) * 1.1 * 4081.0 / 4081.0
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::command::BufferType;
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Identifies a monitoring device by the ip address it connected from
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(transparent)]
+pub struct DeviceId(pub String);
+
+impl DeviceId {
+    pub fn from_peer(addr: SocketAddr) -> Self {
+        DeviceId(addr.ip().to_string())
+    }
+
+    /// usable in file names, mqtt topics and Home Assistant object ids
+    pub fn slug(&self) -> String {
+        self.0
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    }
+}
+
+impl Display for DeviceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Devices with an open connection to the server
+pub type ConnectedDevices = Arc<Mutex<BTreeSet<DeviceId>>>;
+
+pub fn is_connected(connected: &ConnectedDevices, device: &DeviceId) -> bool {
+    connected
+        .lock()
+        .map(|connected| connected.contains(device))
+        .unwrap_or(false)
+}
+
+pub fn connected_count(connected: &ConnectedDevices) -> usize {
+    connected
+        .lock()
+        .map(|connected| connected.len())
+        .unwrap_or(0)
+}
+
+/// What the firmware of a device supports, told by its answer to `Command::GetDeviceInfo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    /// major, minor, patch, `None` for firmware without the handshake
+    pub firmware_version: Option<[u8; 3]>,
+    pub buffers: Vec<BufferType>,
+    pub adc_channels: u8,
+    pub voltage_interval_ms: u16,
+    pub power_interval_ms: u16,
+}
+
+impl DeviceInfo {
+    /// size of the answer to `Command::GetDeviceInfo`
+    pub const SIZE: usize = 9;
+
+    /// Assumed for firmware without the handshake, the intervals are read separately
+    pub fn legacy() -> Self {
+        DeviceInfo {
+            firmware_version: None,
+            buffers: vec![
+                BufferType::PVVoltage,
+                BufferType::PVPower,
+                BufferType::Battery1Voltage,
+                BufferType::BatteryPackVoltage,
+            ],
+            adc_channels: 3,
+            voltage_interval_ms: 0,
+            power_interval_ms: 0,
+        }
+    }
+
+    /// version, a bit per supported `BufferType`, adc channel count, voltage and power interval
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        DeviceInfo {
+            firmware_version: Some([bytes[0], bytes[1], bytes[2]]),
+            buffers: BufferType::ALL
+                .into_iter()
+                .filter(|buffer_type| bytes[3] & (1 << *buffer_type as u8) != 0)
+                .collect(),
+            adc_channels: bytes[4],
+            voltage_interval_ms: u16::from_be_bytes([bytes[5], bytes[6]]),
+            power_interval_ms: u16::from_be_bytes([bytes[7], bytes[8]]),
+        }
+    }
+
+    pub fn supports(&self, buffer_type: BufferType) -> bool {
+        self.buffers.contains(&buffer_type)
+    }
+
+    pub fn firmware(&self) -> String {
+        match self.firmware_version {
+            Some([major, minor, patch]) => format!("{major}.{minor}.{patch}"),
+            None => "unknown (legacy protocol)".to_string(),
+        }
+    }
+}
+
+impl Default for DeviceInfo {
+    fn default() -> Self {
+        Self::legacy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_info_from_bytes() {
+        let info = DeviceInfo::from_bytes(&[1, 4, 2, 0b0_0101, 3, 0x03, 0xe8, 0x13, 0x88]);
+        assert_eq!(info.firmware(), "1.4.2");
+        assert_eq!(
+            info.buffers,
+            vec![BufferType::PVVoltage, BufferType::Battery1Voltage]
+        );
+        assert!(!info.supports(BufferType::InverterPower));
+        assert_eq!(info.adc_channels, 3);
+        assert_eq!(info.voltage_interval_ms, 1000);
+        assert_eq!(info.power_interval_ms, 5000);
+    }
+}
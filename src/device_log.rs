@@ -0,0 +1,128 @@
+use chrono::{DateTime, Local};
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// written into the data dir of every device
+pub const LOG_FILE: &str = "device.log";
+/// a log file is rotated once it grows beyond this
+pub const MAX_LOG_FILE_BYTES: u64 = 1024 * 1024;
+/// `device.log.1` ..= `device.log.3` are kept besides `device.log`
+pub const ROTATED_LOG_FILES: usize = 3;
+
+/// A message logged by the firmware, stamped when the server received it
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogMessage {
+    pub received: DateTime<Local>,
+    pub text: String,
+}
+
+impl LogMessage {
+    pub fn new(text: String) -> Self {
+        LogMessage {
+            received: Local::now(),
+            text,
+        }
+    }
+
+    /// case insensitive, an empty filter matches everything
+    pub fn matches(&self, filter: &str) -> bool {
+        self.text.to_lowercase().contains(&filter.to_lowercase())
+    }
+}
+
+impl Display for LogMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.received.format("%Y-%m-%d %H:%M:%S"),
+            self.text
+        )
+    }
+}
+
+/// Appends log messages to `device.log`, a full file is renamed to `device.log.1`
+/// and the older ones move up, the oldest is deleted
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+}
+
+impl LogFile {
+    pub fn open(dir: &Path) -> io::Result<LogFile> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(LogFile {
+            path,
+            file,
+            len,
+            max_bytes: MAX_LOG_FILE_BYTES,
+        })
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn append(&mut self, message: &LogMessage) -> io::Result<()> {
+        if self.len >= self.max_bytes {
+            self.rotate()?;
+        }
+        let line = format!("{message}\n");
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| self.path.with_extension(format!("log.{n}"));
+        for n in (1..ROTATED_LOG_FILES).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        self.file = File::create(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_file_rotates() {
+        let dir = std::env::temp_dir().join(format!("epmon_device_log_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut log_file = LogFile::open(&dir).unwrap().with_max_bytes(30);
+        for i in 0..6 {
+            log_file
+                .append(&LogMessage::new(format!("message {i}")))
+                .unwrap();
+        }
+        // every line is 30 bytes long, so each file holds one message
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert!(read(LOG_FILE).ends_with("message 5\n"));
+        assert!(read("device.log.1").ends_with("message 4\n"));
+        assert!(read("device.log.3").ends_with("message 2\n"));
+        assert!(!dir.join("device.log.4").exists());
+
+        let message = LogMessage::new("Battery LOW".to_string());
+        assert!(message.matches("low"));
+        assert!(message.matches(""));
+        assert!(!message.matches("pv"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use crate::{
+    command::{Command, Protocol, FRAME_HEADER_SIZE, PROTOCOL_VERSION},
+    remote_data::FrameHeader,
+    server_error::ServerError,
+};
+use std::{collections::VecDeque, pin::Pin, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+
+/// a device answers every command well within this time
+pub const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait AsyncReadWrite: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> AsyncReadWrite for T {}
+
+/// The connection to a device, every read and write fails with `ServerError::Timeout`
+/// instead of waiting for the kernel to notice a stalled peer.
+/// With `Protocol::V2` the framing is handled here, readers see the bare answers.
+pub struct DeviceStream {
+    stream: Pin<Box<dyn AsyncReadWrite>>,
+    /// errors refer to the command whose answer is being read
+    last_command: Option<Command>,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    /// end of the last successful read
+    last_read: Instant,
+    protocol: Protocol,
+    /// sequence number of the last v2 frame sent
+    sequence: u8,
+    /// unread payload of the current v2 answer
+    payload: VecDeque<u8>,
+}
+
+impl DeviceStream {
+    pub fn new(stream: impl AsyncReadWrite + 'static) -> Self {
+        DeviceStream {
+            stream: Box::pin(stream),
+            last_command: None,
+            read_timeout: IO_TIMEOUT,
+            write_timeout: IO_TIMEOUT,
+            last_read: Instant::now(),
+            protocol: Protocol::Legacy,
+            sequence: 0,
+            payload: VecDeque::new(),
+        }
+    }
+
+    pub fn with_timeouts(mut self, read_timeout: Duration, write_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// time since the device last sent something
+    pub fn idle(&self) -> Duration {
+        self.last_read.elapsed()
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Asks the device for the v2 framing, old firmware that stays silent for `timeout`
+    /// keeps the legacy framing
+    pub async fn negotiate_protocol(&mut self, timeout: Duration) -> Result<Protocol, ServerError> {
+        self.send_command(Command::NegotiateProtocol(PROTOCOL_VERSION))
+            .await?;
+        let mut version = [0];
+        self.protocol = match self.read_raw(&mut version, timeout).await {
+            Ok(()) if version[0] == PROTOCOL_VERSION => Protocol::V2,
+            Ok(()) => Protocol::Legacy,
+            Err(ServerError::Timeout { bytes_read: 0, .. }) => Protocol::Legacy,
+            Err(e) => return Err(e),
+        };
+        Ok(self.protocol)
+    }
+
+    pub async fn send_command(&mut self, command: Command) -> Result<(), ServerError> {
+        self.last_command = Some(command);
+        let bytes = match self.protocol {
+            Protocol::Legacy => command.to_bytes().to_vec(),
+            Protocol::V2 => {
+                // whatever is left of the previous answer is not read anymore
+                self.payload.clear();
+                self.sequence = self.sequence.wrapping_add(1);
+                command.to_frame(self.sequence)
+            }
+        };
+        let write = async {
+            self.stream.write_all(&bytes).await?;
+            self.stream.flush().await
+        };
+        match tokio::time::timeout(self.write_timeout, write).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(source)) => Err(ServerError::Io {
+                command: Some(command),
+                source,
+            }),
+            Err(_) => Err(ServerError::Timeout {
+                command: Some(command),
+                bytes_read: 0,
+                expected: 0,
+            }),
+        }
+    }
+
+    /// Fills `buf` from the answer to the last command, errors tell how much of it arrived
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ServerError> {
+        match self.protocol {
+            Protocol::Legacy => self.read_raw(buf, self.read_timeout).await,
+            Protocol::V2 => {
+                // a long answer may be split into several frames
+                while self.payload.len() < buf.len() {
+                    self.read_frame().await?;
+                }
+                let len = buf.len();
+                buf.iter_mut()
+                    .zip(self.payload.drain(..len))
+                    .for_each(|(byte, payload)| *byte = payload);
+                Ok(())
+            }
+        }
+    }
+
+    /// Appends the payload of the next frame answering the last command,
+    /// late answers to earlier commands are skipped
+    async fn read_frame(&mut self) -> Result<(), ServerError> {
+        loop {
+            let mut header = [0; FRAME_HEADER_SIZE];
+            self.read_raw(&mut header, self.read_timeout).await?;
+            let header = FrameHeader::parse(&header).map_err(|e| self.protocol_error(e))?;
+            let mut payload = vec![0; header.len as usize];
+            self.read_raw(&mut payload, self.read_timeout).await?;
+            header.check(&payload).map_err(|e| self.protocol_error(e))?;
+            if header.sequence == self.sequence {
+                self.payload.extend(payload);
+                return Ok(());
+            }
+            println!(
+                "skipping answer {} while waiting for {}",
+                header.sequence, self.sequence
+            );
+        }
+    }
+
+    async fn read_raw(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), ServerError> {
+        let deadline = Instant::now() + timeout;
+        let command = self.last_command;
+        let expected = buf.len();
+        let mut bytes_read = 0;
+        while bytes_read < expected {
+            match tokio::time::timeout_at(deadline, self.stream.read(&mut buf[bytes_read..])).await
+            {
+                Ok(Ok(0)) => {
+                    return Err(ServerError::ShortRead {
+                        command,
+                        bytes_read,
+                        expected,
+                    })
+                }
+                Ok(Ok(n)) => bytes_read += n,
+                Ok(Err(source)) => return Err(ServerError::Io { command, source }),
+                Err(_) => {
+                    return Err(ServerError::Timeout {
+                        command,
+                        bytes_read,
+                        expected,
+                    })
+                }
+            }
+        }
+        self.last_read = Instant::now();
+        Ok(())
+    }
+
+    /// An error about the answer to the last command
+    pub fn protocol_error(&self, message: impl Into<String>) -> ServerError {
+        ServerError::Protocol {
+            command: self.last_command,
+            message: message.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::frame;
+
+    #[tokio::test]
+    async fn stalled_peer_times_out() {
+        let (device, mut server) = tokio::io::duplex(64);
+        let timeout = Duration::from_millis(50);
+        let mut stream = DeviceStream::new(device).with_timeouts(timeout, timeout);
+        server.write_all(&[1, 2, 3]).await.unwrap();
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2]);
+        stream
+            .send_command(Command::GetVoltageBufferSize)
+            .await
+            .unwrap();
+        match stream.read_exact(&mut buf).await {
+            Err(ServerError::Timeout {
+                command: Some(Command::GetVoltageBufferSize),
+                bytes_read: 1,
+                expected: 2,
+            }) => {}
+            other => panic!("expected a timeout, got {other:?}"),
+        }
+        drop(server);
+        match stream.read_exact(&mut buf).await {
+            Err(ServerError::ShortRead { bytes_read: 0, .. }) => {}
+            other => panic!("expected a short read, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiates_v2_and_falls_back_to_legacy() {
+        let timeout = Duration::from_millis(50);
+        let (device, mut old_firmware) = tokio::io::duplex(64);
+        let mut stream = DeviceStream::new(device).with_timeouts(timeout, timeout);
+        assert_eq!(
+            stream.negotiate_protocol(timeout).await.ok(),
+            Some(Protocol::Legacy)
+        );
+        let mut command = [0; crate::command::COMMAND_SIZE];
+        old_firmware.read_exact(&mut command).await.unwrap();
+        assert_eq!(command[..2], [10, PROTOCOL_VERSION]);
+
+        let (device, mut new_firmware) = tokio::io::duplex(256);
+        let mut stream = DeviceStream::new(device).with_timeouts(timeout, timeout);
+        new_firmware.write_all(&[PROTOCOL_VERSION]).await.unwrap();
+        assert_eq!(
+            stream.negotiate_protocol(timeout).await.ok(),
+            Some(Protocol::V2)
+        );
+        stream
+            .send_command(Command::GetVoltageBufferSize)
+            .await
+            .unwrap();
+        let mut command_frame = [0; FRAME_HEADER_SIZE + crate::command::COMMAND_SIZE];
+        new_firmware.read_exact(&mut command).await.unwrap();
+        new_firmware.read_exact(&mut command_frame).await.unwrap();
+        assert_eq!(command_frame[3], 1);
+        // a late answer to an earlier command, then the answer split in two frames
+        new_firmware.write_all(&frame(0, &[9, 9])).await.unwrap();
+        new_firmware.write_all(&frame(1, &[0, 0])).await.unwrap();
+        new_firmware.write_all(&frame(1, &[1, 2])).await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 1, 2]);
+
+        let mut corrupted = frame(1, &[1, 2]);
+        corrupted[FRAME_HEADER_SIZE] = 3;
+        new_firmware.write_all(&corrupted).await.unwrap();
+        match stream.read_exact(&mut buf[..2]).await {
+            Err(ServerError::Protocol { message, .. }) => assert!(message.contains("crc")),
+            other => panic!("expected a crc mismatch, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{
+    storage::{Sample, Series, Store},
+    voltage_chart::CustomChart,
+};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+/// magic bytes at the start of a columnar export file
+const COLUMNAR_MAGIC: [u8; 4] = *b"EPMC";
+const COLUMNAR_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// timestamps are delta encoded, values stored as f32, one column after the other
+    Columnar,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Columnar => "epmc",
+        }
+    }
+}
+
+/// Samples of one series with absolute timestamps
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Column {
+    pub name: String,
+    /// ascending, the lookups and the delta encoding rely on it
+    pub timestamps_ms: Vec<i64>,
+    pub values: Vec<f32>,
+}
+
+impl Column {
+    /// Takes the chart samples with a relative time (0 being the newest sample) inside `range`.
+    /// The newest sample is stamped with `end_ms`, the others are derived from `tick_len`.
+    pub fn from_chart(chart: &CustomChart, end_ms: i64, range: RangeInclusive<f32>) -> Column {
+        let max_ix = chart.data.len() as i64 - 1;
+        let tick_ms = (chart.tick_len * 1000.0) as i64;
+        let mut column = Column {
+            name: column_name(&chart.title),
+            ..Default::default()
+        };
+        for (ix, value) in chart.data.iter().enumerate() {
+            let time = chart.tick_len * (ix as i64 - max_ix) as f32;
+            if range.contains(&time) {
+                column
+                    .timestamps_ms
+                    .push(end_ms - (max_ix - ix as i64) * tick_ms);
+                column.values.push(*value);
+            }
+        }
+        column
+    }
+
+    /// Samples that were stored out of order, e.g. after the clock was set back, are sorted
+    pub fn from_samples(series: Series, samples: &[Sample]) -> Column {
+        let mut samples: Vec<&Sample> = samples
+            .iter()
+            .filter(|sample| sample.series == series)
+            .collect();
+        samples.sort_by_key(|sample| sample.timestamp_ms);
+        let (timestamps_ms, values) = samples
+            .iter()
+            .map(|sample| (sample.timestamp_ms, sample.value))
+            .unzip();
+        Column {
+            name: series.name().to_string(),
+            timestamps_ms,
+            values,
+        }
+    }
+
+    /// median distance between 2 samples
+    fn tick_ms(&self) -> Option<i64> {
+        let mut deltas: Vec<i64> = self
+            .timestamps_ms
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .filter(|&d| d > 0)
+            .collect();
+        deltas.sort_unstable();
+        deltas.get(deltas.len() / 2).copied()
+    }
+
+    /// value of the sample closest to `timestamp_ms`, if it is less than `max_distance_ms` away
+    fn value_at(&self, timestamp_ms: i64, max_distance_ms: i64) -> Option<f32> {
+        let ix = self.timestamps_ms.partition_point(|&t| t < timestamp_ms);
+        [ix.checked_sub(1), Some(ix)]
+            .into_iter()
+            .flatten()
+            .filter(|&ix| ix < self.timestamps_ms.len())
+            .map(|ix| ((self.timestamps_ms[ix] - timestamp_ms).abs(), ix))
+            .filter(|&(distance, _)| distance < max_distance_ms)
+            .min()
+            .map(|(_, ix)| self.values[ix])
+    }
+}
+
+/// "PV Power" => "pv_power"
+pub fn column_name(title: &str) -> String {
+    title.trim().to_lowercase().replace(' ', "_")
+}
+
+fn format_timestamp(timestamp_ms: i64) -> String {
+    match Local.timestamp_millis_opt(timestamp_ms).single() {
+        Some(t) => t.format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string(),
+        None => timestamp_ms.to_string(),
+    }
+}
+
+pub fn write_csv<W: Write>(writer: &mut W, column: &Column) -> io::Result<()> {
+    writeln!(writer, "timestamp,{}", column.name)?;
+    for (timestamp, value) in column.timestamps_ms.iter().zip(&column.values) {
+        writeln!(writer, "{},{}", format_timestamp(*timestamp), value)?;
+    }
+    Ok(())
+}
+
+/// Writes all columns on one time axis. The axis uses the finest sample interval of all columns,
+/// cells without a sample nearby stay empty.
+pub fn write_csv_aligned<W: Write>(writer: &mut W, columns: &[Column]) -> io::Result<()> {
+    write!(writer, "timestamp")?;
+    for column in columns {
+        write!(writer, ",{}", column.name)?;
+    }
+    writeln!(writer)?;
+    let first = columns.iter().filter_map(|c| c.timestamps_ms.first()).min();
+    let last = columns.iter().filter_map(|c| c.timestamps_ms.last()).max();
+    let (Some(&first), Some(&last)) = (first, last) else {
+        return Ok(());
+    };
+    let tick_ms = columns
+        .iter()
+        .filter_map(Column::tick_ms)
+        .min()
+        .unwrap_or(1000)
+        .max(1);
+    let max_distances: Vec<i64> = columns
+        .iter()
+        .map(|column| (column.tick_ms().unwrap_or(tick_ms) / 2).max(1))
+        .collect();
+    let mut timestamp = first;
+    while timestamp <= last {
+        write!(writer, "{}", format_timestamp(timestamp))?;
+        for (column, &max_distance) in columns.iter().zip(&max_distances) {
+            match column.value_at(timestamp, max_distance) {
+                Some(value) => write!(writer, ",{value}")?,
+                None => write!(writer, ",")?,
+            }
+        }
+        writeln!(writer)?;
+        timestamp += tick_ms;
+    }
+    Ok(())
+}
+
+/// Layout (all numbers big endian):
+/// magic "EPMC", version u8, column count u16, then per column:
+/// name length u16, name, sample count u32, first timestamp i64,
+/// (count - 1) timestamp deltas u32 in ms, count values f32.
+/// Fails with `InvalidInput` for timestamps that are not ascending or more than 49 days apart.
+pub fn write_columnar<W: Write>(writer: &mut W, columns: &[Column]) -> io::Result<()> {
+    writer.write_all(&COLUMNAR_MAGIC)?;
+    writer.write_all(&[COLUMNAR_VERSION])?;
+    writer.write_all(&(columns.len() as u16).to_be_bytes())?;
+    for column in columns {
+        writer.write_all(&(column.name.len() as u16).to_be_bytes())?;
+        writer.write_all(column.name.as_bytes())?;
+        writer.write_all(&(column.values.len() as u32).to_be_bytes())?;
+        let first = column.timestamps_ms.first().copied().unwrap_or(0);
+        writer.write_all(&first.to_be_bytes())?;
+        for w in column.timestamps_ms.windows(2) {
+            let delta = u32::try_from(w[1] - w[0]).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{}: no timestamp delta from {} to {}",
+                        column.name, w[0], w[1]
+                    ),
+                )
+            })?;
+            writer.write_all(&delta.to_be_bytes())?;
+        }
+        for value in &column.values {
+            writer.write_all(&value.to_be_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn read_columnar<R: Read>(reader: &mut R) -> io::Result<Vec<Column>> {
+    fn read_array<const N: usize, R: Read>(reader: &mut R) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if read_array::<4, _>(reader)? != COLUMNAR_MAGIC {
+        return Err(invalid("not an epmon columnar file"));
+    }
+    if read_array::<1, _>(reader)? != [COLUMNAR_VERSION] {
+        return Err(invalid("unsupported columnar version"));
+    }
+    let num_columns = u16::from_be_bytes(read_array(reader)?);
+    let mut columns = Vec::with_capacity(num_columns as usize);
+    for _ in 0..num_columns {
+        let name_len = u16::from_be_bytes(read_array(reader)?) as usize;
+        let mut name = vec![0; name_len];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| invalid("column name is not utf8"))?;
+        let count = u32::from_be_bytes(read_array(reader)?) as usize;
+        let mut timestamp = i64::from_be_bytes(read_array(reader)?);
+        let mut column = Column {
+            name,
+            ..Default::default()
+        };
+        for ix in 0..count {
+            if ix > 0 {
+                timestamp += u32::from_be_bytes(read_array(reader)?) as i64;
+            }
+            column.timestamps_ms.push(timestamp);
+        }
+        for _ in 0..count {
+            column.values.push(f32::from_be_bytes(read_array(reader)?));
+        }
+        columns.push(column);
+    }
+    Ok(columns)
+}
+
+/// Writes a single column as plain csv, several columns aligned on a shared time axis
+pub fn export_to_file(path: &Path, columns: &[Column], format: ExportFormat) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match (format, columns) {
+        (ExportFormat::Csv, [column]) => write_csv(&mut writer, column)?,
+        (ExportFormat::Csv, columns) => write_csv_aligned(&mut writer, columns)?,
+        (ExportFormat::Columnar, columns) => write_columnar(&mut writer, columns)?,
+    }
+    writer.flush()
+}
+
+/// `<dir>/<name>_<local time>.<extension>`
+pub fn export_path(dir: &Path, name: &str, format: ExportFormat) -> PathBuf {
+    dir.join(format!(
+        "{name}_{}.{}",
+        Local::now().format("%Y%m%d_%H%M%S"),
+        format.extension()
+    ))
+}
+
+/// `epmon_server export [--device ID] [--from TIME] [--to TIME] [--series a,b] [--format csv|columnar] [--out PATH]`
+/// TIME is local time, either `2024-06-01` or `2024-06-01T12:30:00`.
+/// Without `--from` the export starts at midnight, without `--to` it ends now.
+pub fn run_cli(args: &[String], store: &Store) -> Result<PathBuf, String> {
+    let arg_value = |name: &str| crate::arg_value(args, name);
+    let now_ms = Utc::now().timestamp_millis();
+    let from_ms = match arg_value("--from") {
+        Some(s) => parse_local_time(s)?,
+        None => parse_local_time(&Local::now().format("%Y-%m-%d").to_string())?,
+    };
+    let to_ms = match arg_value("--to") {
+        Some(s) => parse_local_time(s)?,
+        None => now_ms,
+    };
+    let series = match arg_value("--series") {
+        Some(names) => names
+            .split(',')
+            .map(|name| Series::from_name(name).ok_or(format!("unknown series: {name}")))
+            .collect::<Result<Vec<Series>, String>>()?,
+        None => Series::ALL.to_vec(),
+    };
+    let format = match arg_value("--format") {
+        None | Some("csv") => ExportFormat::Csv,
+        Some("columnar") => ExportFormat::Columnar,
+        Some(other) => return Err(format!("unknown format: {other}")),
+    };
+    let samples = store
+        .load_range(from_ms, to_ms)
+        .map_err(|e| format!("could not read samples: {e}"))?;
+    let columns: Vec<Column> = series
+        .iter()
+        .map(|series| Column::from_samples(*series, &samples))
+        .collect();
+    let path = match arg_value("--out") {
+        Some(path) => PathBuf::from(path),
+        None => export_path(Path::new("."), "export", format),
+    };
+    export_to_file(&path, &columns, format)
+        .map_err(|e| format!("could not write {}: {e}", path.display()))?;
+    Ok(path)
+}
+
+fn parse_local_time(s: &str) -> Result<i64, String> {
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(|d| d.and_hms_opt(0, 0, 0).unwrap_or_default())
+        })
+        .map_err(|_| format!("could not parse time: {s}"))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|t| t.timestamp_millis())
+        .ok_or(format!("time does not exist in local time zone: {s}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, timestamps_ms: Vec<i64>, values: Vec<f32>) -> Column {
+        Column {
+            name: name.to_string(),
+            timestamps_ms,
+            values,
+        }
+    }
+
+    #[test]
+    fn chart_range_to_column() {
+        let chart = CustomChart {
+            title: "PV Power".to_string(),
+            data: vec![1.0, 2.0, 3.0, 4.0].into(),
+            tick_len: 0.5,
+            ..Default::default()
+        };
+        let c = Column::from_chart(&chart, 10_000, -1.0..=-0.5);
+        assert_eq!(c, column("pv_power", vec![9_000, 9_500], vec![2.0, 3.0]));
+    }
+
+    #[test]
+    fn columnar_round_trip() {
+        let columns = vec![
+            column("pv", vec![0, 1000, 2000], vec![1.0, 2.5, 3.0]),
+            column("pv_power", vec![500], vec![100.0]),
+            column("empty", vec![], vec![]),
+        ];
+        let mut bytes = Vec::new();
+        write_columnar(&mut bytes, &columns).unwrap();
+        assert_eq!(read_columnar(&mut bytes.as_slice()).unwrap(), columns);
+    }
+
+    #[test]
+    fn columnar_rejects_unencodable_deltas() {
+        for timestamps_ms in [vec![1000, 0], vec![0, 50 * 24 * 3600 * 1000]] {
+            let columns = [column("pv", timestamps_ms, vec![1.0, 2.0])];
+            let error = write_columnar(&mut Vec::new(), &columns).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        let samples = [2000, 0, 1000].map(|timestamp_ms| Sample {
+            series: Series::PVPower,
+            timestamp_ms,
+            value: timestamp_ms as f32,
+        });
+        let column = Column::from_samples(Series::PVPower, &samples);
+        assert_eq!(column.timestamps_ms, vec![0, 1000, 2000]);
+        assert_eq!(column.values, vec![0.0, 1000.0, 2000.0]);
+    }
+
+    #[test]
+    fn aligned_csv_leaves_gaps_empty() {
+        let columns = [
+            column("a", vec![0, 1000, 2000], vec![1.0, 2.0, 3.0]),
+            column("b", vec![0, 2000], vec![5.0, 6.0]),
+        ];
+        let mut bytes = Vec::new();
+        write_csv_aligned(&mut bytes, &columns).unwrap();
+        let csv = String::from_utf8(bytes).unwrap();
+        let cells: Vec<String> = csv
+            .lines()
+            .map(|line| line.split_once(',').unwrap().1.to_string())
+            .collect();
+        assert_eq!(cells, vec!["a,b", "1,5", "2,", "3,6"]);
+    }
+}
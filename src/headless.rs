@@ -0,0 +1,65 @@
+use crate::{
+    device::{self, ConnectedDevices, DeviceId},
+    live_data::LiveData,
+    recorder::Recorder,
+    remote_data::RemoteData,
+};
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+/// how often a status line is printed when running without a window
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Consumes the remote data channel without ever creating the iced application.
+/// Returns when the server thread hangs up.
+pub fn run(
+    remote_data_receiver: Receiver<(DeviceId, RemoteData)>,
+    connected: ConnectedDevices,
+    mut recorder: Recorder,
+) {
+    let mut last_report = Instant::now();
+    println!("running headless");
+    loop {
+        match remote_data_receiver.recv_timeout(REPORT_INTERVAL) {
+            Ok((device, mut remote_data)) => {
+                if let RemoteData::LogMessage(message) = &remote_data {
+                    println!("{device}: device log: {}", message.text);
+                }
+                recorder.record(&device, &mut remote_data)
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                println!("headless: server thread stopped");
+                break;
+            }
+        }
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            if let Ok(live_data) = recorder.live_data.lock() {
+                if live_data.is_empty() {
+                    println!("no device connected yet");
+                }
+                for (device, live_data) in live_data.iter() {
+                    report(device, live_data, &connected);
+                }
+            }
+            last_report = Instant::now();
+        }
+    }
+}
+
+fn report(device: &DeviceId, live_data: &LiveData, connected: &ConnectedDevices) {
+    let connected = device::is_connected(connected, device);
+    let fmt = |v: Option<f32>| v.map_or("-".to_string(), |v| format!("{v:.2}"));
+    println!(
+        "{device}: connected: {connected}, pv: {} V, pv power: {} W, inverter power: {} W, battery pack: {} V, battery1: {} V, battery2: {} V, samples: {}",
+        fmt(live_data.pv.latest()),
+        fmt(live_data.pv_power.latest()),
+        fmt(live_data.inverter_power.latest()),
+        fmt(live_data.battery_pack.latest()),
+        fmt(live_data.battery1.latest()),
+        fmt(live_data.battery2()),
+        live_data.pv.data.len(),
+    );
+}
@@ -0,0 +1,300 @@
+use crate::{
+    device::{ConnectedDevices, DeviceId},
+    live_data::LiveData,
+    metrics,
+    recorder::SharedLiveData,
+    storage::Series,
+};
+use chrono::Utc;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+/// the api has no authentication, it is not reachable from the network unless configured
+pub const DEFAULT_HTTP_ADDRESS: &str = "127.0.0.1";
+pub const DEFAULT_HTTP_PORT: u16 = 8080;
+/// range of `/api/samples` if `from` is not given
+const DEFAULT_SAMPLE_RANGE_MS: i64 = 3600 * 1000;
+
+/// Serves the live data as JSON:
+/// `/api/devices`, `/api/status`, `/api/realtime`, `/api/realtime_status`, `/api/rated`,
+/// `/api/stats`, `/api/voltage_settings` and
+/// `/api/samples?series=pv&from=<unix ms>&to=<unix ms>`.
+/// All but `/api/devices` take `device=<id>`, the first device is used without it.
+/// `/api/realtime`, `/api/realtime_status` and `/api/stats` are `null` until the device reported them.
+/// `/metrics` exposes the readings of every device in the OpenMetrics text format.
+pub fn run(address: &str, port: u16, live_data: SharedLiveData, connected: ConnectedDevices) {
+    let tcp_listener = match TcpListener::bind((address, port)) {
+        Ok(tcp_listener) => tcp_listener,
+        Err(e) => {
+            println!("http api: could not bind {address}:{port}: {e}");
+            return;
+        }
+    };
+    println!("http api listening on {address}:{port}");
+    for stream in tcp_listener.incoming().flatten() {
+        let live_data = live_data.clone();
+        let connected = connected.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &live_data, &connected) {
+                println!("http api: {e}");
+            }
+        });
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    live_data: &Mutex<BTreeMap<DeviceId, LiveData>>,
+    connected: &Mutex<BTreeSet<DeviceId>>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are not needed, but have to be consumed
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let response = match request_line.split_whitespace().collect::<Vec<&str>>()[..] {
+        ["GET", target, ..] => {
+            let connected = connected.lock().map(|c| c.clone()).unwrap_or_default();
+            match live_data.lock() {
+                Ok(live_data) => route(target, &live_data, &connected),
+                Err(_) => Response::error(500, "live data unavailable"),
+            }
+        }
+        [_, _, ..] => Response::error(405, "only GET is supported"),
+        _ => Response::error(400, "malformed request"),
+    };
+    response.write_to(&mut stream)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json<T: Serialize>(value: &T) -> Response {
+        match serde_json::to_string(value) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )?;
+        writer.flush()
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceStatus<'a> {
+    device: &'a DeviceId,
+    connected: bool,
+}
+
+#[derive(Serialize)]
+struct Status<'a> {
+    device: &'a DeviceId,
+    connected: bool,
+    voltage_buffer_size: usize,
+    samples: BTreeMap<&'static str, usize>,
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// `target` is the path with an optional query string
+pub fn route(
+    target: &str,
+    devices: &BTreeMap<DeviceId, LiveData>,
+    connected: &BTreeSet<DeviceId>,
+) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match path {
+        "/api/devices" => {
+            return Response::json(
+                &devices
+                    .keys()
+                    .map(|device| DeviceStatus {
+                        device,
+                        connected: connected.contains(device),
+                    })
+                    .collect::<Vec<DeviceStatus>>(),
+            )
+        }
+        "/metrics" => {
+            return Response {
+                status: 200,
+                content_type: metrics::CONTENT_TYPE,
+                body: metrics::render(devices, connected),
+            }
+        }
+        _ => {}
+    }
+    let device = match query_param(query, "device") {
+        Some(device) => devices.get_key_value(&DeviceId(device.to_string())),
+        None => devices.iter().next(),
+    };
+    let Some((device, live_data)) = device else {
+        return Response::error(404, "unknown device");
+    };
+    match path {
+        "/api/status" => Response::json(&Status {
+            device,
+            connected: connected.contains(device),
+            voltage_buffer_size: live_data.voltage_buffer_size,
+            samples: Series::ALL
+                .iter()
+                .map(|series| (series.name(), live_data.samples(*series).data.len()))
+                .collect(),
+        }),
+        "/api/realtime" => Response::json(&live_data.realtime),
+        "/api/realtime_status" => Response::json(&live_data.realtime_status),
+        "/api/rated" => Response::json(&live_data.rated),
+        "/api/stats" => Response::json(&live_data.stats),
+        "/api/voltage_settings" => Response::json(&live_data.voltage_settings),
+        "/api/samples" => samples(query, live_data),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn samples(query: &str, live_data: &LiveData) -> Response {
+    let param = |name: &str| query_param(query, name);
+    let Some(series) = param("series").and_then(Series::from_name) else {
+        return Response::error(400, "missing or unknown series");
+    };
+    let now_ms = Utc::now().timestamp_millis();
+    let parse = |name: &'static str, default: i64| match param(name) {
+        Some(value) => value.parse::<i64>().map_err(|_| name),
+        None => Ok(default),
+    };
+    match (
+        parse("from", now_ms - DEFAULT_SAMPLE_RANGE_MS),
+        parse("to", now_ms),
+    ) {
+        (Ok(from), Ok(to)) => {
+            Response::json(&live_data.samples(series).column(series.name(), from..=to))
+        }
+        (Err(name), _) | (_, Err(name)) => {
+            Response::error(400, &format!("{name} is not a unix timestamp in ms"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::live_data::Samples;
+
+    fn device(id: &str) -> DeviceId {
+        DeviceId(id.to_string())
+    }
+
+    #[test]
+    fn samples_range_query() {
+        let live_data = LiveData {
+            pv: Samples {
+                data: vec![1.0, 2.0, 3.0].into(),
+                tick_len: 1.0,
+                end_ms: 10_000,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let live_data = BTreeMap::from([(device("a"), live_data)]);
+        let connected = BTreeSet::new();
+        let response = route(
+            "/api/samples?series=pv&from=9000&to=10000",
+            &live_data,
+            &connected,
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.body,
+            r#"{"name":"pv","timestamps_ms":[9000,10000],"values":[2.0,3.0]}"#
+        );
+        assert_eq!(
+            route("/api/samples?series=x", &live_data, &connected).status,
+            400
+        );
+        assert_eq!(
+            route("/api/samples?series=pv&from=abc", &live_data, &connected).status,
+            400
+        );
+    }
+
+    #[test]
+    fn json_endpoints() {
+        let live_data = BTreeMap::from([
+            (device("a"), LiveData::default()),
+            (device("b"), LiveData::default()),
+        ]);
+        let connected = BTreeSet::from([device("b")]);
+        let response = route("/api/status", &live_data, &connected);
+        assert!(response
+            .body
+            .starts_with(r#"{"device":"a","connected":false"#));
+        let response = route("/api/status?device=b", &live_data, &connected);
+        assert!(response
+            .body
+            .starts_with(r#"{"device":"b","connected":true"#));
+        let response = route("/api/devices", &live_data, &connected);
+        assert_eq!(
+            response.body,
+            r#"[{"device":"a","connected":false},{"device":"b","connected":true}]"#
+        );
+        let response = route("/api/realtime_status", &live_data, &connected);
+        assert_eq!(response.body, "null");
+        assert_eq!(route("/api/stats", &live_data, &connected).status, 200);
+        assert_eq!(
+            route("/api/stats?device=c", &live_data, &connected).status,
+            404
+        );
+        assert_eq!(route("/nope", &live_data, &connected).status, 404);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{
+    calibration::Calibration,
+    command::BufferType,
+    export::Column,
+    remote_data::RemoteData,
+    server_error::{ConnectionEvent, ErrorKind},
+    storage::{Series, Store},
+    tracer_an::{Rated, Realtime, RealtimeStatus, Stats, VoltageSettings},
+};
+use chrono::Utc;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::RangeInclusive,
+};
+
+/// how many seconds of samples are kept in memory per series
+pub const HISTORY_SECONDS: f32 = 24.0 * 3600.0;
+
+/// Ticks between the newest stored sample at `history_end_ms` and `new_values` that end now,
+/// filled with 0 like the gaps within the stored history. At most `HISTORY_SECONDS` long.
+pub fn missing_ticks(history_end_ms: i64, tick_len: f32, new_values: usize) -> usize {
+    let tick_ms = ((tick_len * 1000.0) as i64).max(1);
+    let elapsed_ms = Utc::now().timestamp_millis() - history_end_ms;
+    let ticks = (elapsed_ms + tick_ms / 2) / tick_ms - new_values as i64;
+    ticks.clamp(0, (HISTORY_SECONDS / tick_len) as i64) as usize
+}
+
+#[derive(Debug, Clone)]
+pub struct Samples {
+    pub data: VecDeque<f32>,
+    /// time between 2 samples in seconds
+    pub tick_len: f32,
+    /// unix timestamp of the newest sample
+    pub end_ms: i64,
+    /// set while the newest sample comes from the stored history
+    pub history_end_ms: Option<i64>,
+}
+
+impl Default for Samples {
+    fn default() -> Self {
+        Self {
+            data: Default::default(),
+            tick_len: 0.02,
+            end_ms: 0,
+            history_end_ms: None,
+        }
+    }
+}
+
+impl Samples {
+    /// The newest value is stamped with the current time, an empty reply keeps `end_ms`.
+    /// The first values after the stored history follow a gap as long as the downtime.
+    pub fn extend<I: IntoIterator<Item = f32>>(&mut self, values: I) {
+        let values: Vec<f32> = values.into_iter().collect();
+        if values.is_empty() {
+            return;
+        }
+        if let Some(history_end_ms) = self.history_end_ms.take() {
+            let missing = missing_ticks(history_end_ms, self.tick_len, values.len());
+            self.data.extend(std::iter::repeat_n(0.0, missing));
+        }
+        self.data.extend(values);
+        self.end_ms = Utc::now().timestamp_millis();
+        let max_len = (HISTORY_SECONDS / self.tick_len) as usize;
+        if self.data.len() > max_len {
+            let excess = self.data.len() - max_len;
+            self.data.drain(..excess);
+        }
+    }
+
+    pub fn latest(&self) -> Option<f32> {
+        self.data.back().copied()
+    }
+
+    /// samples with a unix timestamp in `range_ms`
+    pub fn column(&self, name: &str, range_ms: RangeInclusive<i64>) -> Column {
+        let tick_ms = (self.tick_len * 1000.0) as i64;
+        let max_ix = self.data.len() as i64 - 1;
+        let mut column = Column {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        for (ix, value) in self.data.iter().enumerate() {
+            let timestamp = self.end_ms - (max_ix - ix as i64) * tick_ms;
+            if range_ms.contains(&timestamp) {
+                column.timestamps_ms.push(timestamp);
+                column.values.push(*value);
+            }
+        }
+        column
+    }
+}
+
+/// Everything the device reported so far, independent of any GUI
+#[derive(Debug, Clone, Default)]
+pub struct LiveData {
+    pub battery1: Samples,
+    pub battery_pack: Samples,
+    pub pv: Samples,
+    pub pv_power: Samples,
+    pub inverter_power: Samples,
+    pub voltage_buffer_size: usize,
+    /// `None` until the device reported it
+    pub realtime: Option<Realtime>,
+    pub realtime_status: Option<RealtimeStatus>,
+    pub rated: Rated,
+    pub stats: Option<Stats>,
+    pub voltage_settings: VoltageSettings,
+    /// converts the voltage buffers
+    pub calibration: Calibration,
+    /// connection errors since the start
+    pub error_counts: BTreeMap<ErrorKind, u64>,
+}
+
+impl LiveData {
+    pub fn update(&mut self, remote_data: RemoteData) {
+        let calibration = &self.calibration;
+        match remote_data {
+            RemoteData::NoData => {}
+            RemoteData::BatteryVoltage(adc_readings) => self.battery1.extend(
+                adc_readings
+                    .into_iter()
+                    .map(|r| calibration.to_voltage(BufferType::Battery1Voltage, r)),
+            ),
+            RemoteData::BatteryPackVoltage(adc_readings) => self.battery_pack.extend(
+                adc_readings
+                    .into_iter()
+                    .map(|r| calibration.to_voltage(BufferType::BatteryPackVoltage, r)),
+            ),
+            RemoteData::PVVoltage(adc_readings) => self.pv.extend(
+                adc_readings
+                    .into_iter()
+                    .map(|r| calibration.to_voltage(BufferType::PVVoltage, r)),
+            ),
+            RemoteData::PVPower(power_readings) => self
+                .pv_power
+                .extend(power_readings.into_iter().map(|p| p as f32)),
+            RemoteData::InverterPower(power_readings) => self
+                .inverter_power
+                .extend(power_readings.into_iter().map(|p| p as f32)),
+            RemoteData::VoltageBufferSize(s) => self.voltage_buffer_size = s,
+            RemoteData::VoltageIntervalms(interval) => {
+                let tick_len = interval as f32 / 1000.0;
+                self.battery1.tick_len = tick_len;
+                self.battery_pack.tick_len = tick_len;
+                self.pv.tick_len = tick_len;
+            }
+            RemoteData::PowerIntervalms(interval) => {
+                let tick_len = interval as f32 / 1000.0;
+                self.pv_power.tick_len = tick_len;
+                self.inverter_power.tick_len = tick_len;
+            }
+            RemoteData::Holdings(_) | RemoteData::InputRegisters(_) => {}
+            RemoteData::Realtime(realtime) => self.realtime = Some(realtime),
+            RemoteData::RealtimeStatus(realtime_status) => {
+                self.realtime_status = Some(realtime_status)
+            }
+            RemoteData::VoltageSettings(voltage_settings) => {
+                self.voltage_settings = voltage_settings
+            }
+            RemoteData::Rated(rated) => self.rated = rated,
+            RemoteData::Stats(stats) => self.stats = Some(stats),
+            RemoteData::ConnectionEvent(ConnectionEvent::Error { kind, .. }) => {
+                *self.error_counts.entry(kind).or_default() += 1
+            }
+            RemoteData::ConnectionEvent(_)
+            | RemoteData::DeviceInfo(_)
+            | RemoteData::LogMessage(_)
+            | RemoteData::HoldingsWritten { .. }
+            | RemoteData::LoadControl(_)
+            | RemoteData::LoadOutput(_) => {}
+        }
+    }
+
+    pub fn load_history(&mut self, store: &Store) {
+        for series in Series::ALL {
+            let history = match store.load_history(series) {
+                Ok(history) => history,
+                Err(e) => {
+                    println!("could not load history of {series:?}: {e}");
+                    continue;
+                }
+            };
+            let samples = match series {
+                Series::BatteryVoltage => &mut self.battery1,
+                Series::BatteryPackVoltage => &mut self.battery_pack,
+                Series::PVVoltage => &mut self.pv,
+                Series::PVPower => &mut self.pv_power,
+                Series::InverterPower => &mut self.inverter_power,
+            };
+            if history.tick_len > 0.0 {
+                samples.tick_len = history.tick_len;
+            }
+            if history.values.is_empty() {
+                continue;
+            }
+            samples.extend(history.values);
+            samples.end_ms = history.end_ms;
+            samples.history_end_ms = Some(history.end_ms);
+        }
+    }
+
+    pub fn samples(&self, series: Series) -> &Samples {
+        match series {
+            Series::BatteryVoltage => &self.battery1,
+            Series::BatteryPackVoltage => &self.battery_pack,
+            Series::PVVoltage => &self.pv,
+            Series::PVPower => &self.pv_power,
+            Series::InverterPower => &self.inverter_power,
+        }
+    }
+
+    /// battery2 is not measured directly, it is the difference of the pack and battery1
+    pub fn battery2(&self) -> Option<f32> {
+        Some(self.battery_pack.latest()? - self.battery1.latest()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_capped_to_history() {
+        let mut samples = Samples {
+            tick_len: 3600.0,
+            ..Default::default()
+        };
+        samples.extend((0..100).map(|i| i as f32));
+        assert_eq!(samples.data.len(), 24);
+        assert_eq!(samples.latest(), Some(99.0));
+        assert_eq!(samples.data.front(), Some(&76.0));
+
+        let end_ms = samples.end_ms;
+        samples.extend([]);
+        assert_eq!(samples.end_ms, end_ms);
+    }
+
+    #[test]
+    fn live_samples_follow_the_downtime() {
+        let history_end_ms = Utc::now().timestamp_millis() - 10_000;
+        let mut samples = Samples {
+            data: vec![1.0, 2.0, 3.0].into(),
+            tick_len: 1.0,
+            end_ms: history_end_ms,
+            history_end_ms: Some(history_end_ms),
+        };
+        samples.extend([4.0, 5.0]);
+        assert_eq!(samples.data.len(), 3 + 8 + 2);
+        let column = samples.column("pv", i64::MIN..=i64::MAX);
+        assert!((column.timestamps_ms[2] - history_end_ms).abs() < 1000);
+        assert_eq!(column.values[2], 3.0);
+        assert_eq!(column.values[11..], [4.0, 5.0]);
+
+        // only the first reply after the history is placed after a gap
+        samples.extend([6.0]);
+        assert_eq!(samples.data.len(), 14);
+    }
+
+    #[test]
+    fn update_converts_adc_readings() {
+        let mut live_data = LiveData::default();
+        live_data.update(RemoteData::VoltageIntervalms(1000));
+        live_data.update(RemoteData::BatteryPackVoltage(vec![0, 4081]));
+        live_data.update(RemoteData::BatteryVoltage(vec![0]));
+        live_data.update(RemoteData::PVPower(vec![250]));
+        live_data.update(RemoteData::InverterPower(vec![180]));
+        assert_eq!(live_data.battery_pack.tick_len, 1.0);
+        assert_eq!(
+            live_data.battery_pack.latest(),
+            Some(Calibration::default().to_voltage(BufferType::BatteryPackVoltage, 4081))
+        );
+        assert_eq!(
+            live_data.battery2(),
+            Some(Calibration::default().to_voltage(BufferType::BatteryPackVoltage, 4081))
+        );
+        assert_eq!(live_data.pv_power.latest(), Some(250.0));
+        assert_eq!(live_data.inverter_power.latest(), Some(180.0));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::{
+    command::Command,
+    remote_data::RemoteData,
+    server_error::ConnectionEvent,
+    tracer_an::{two_bytes_to_f32, HoldingSetting, LoadControlMode, LoadTimer},
+};
+use chrono::{NaiveTime, Timelike};
+use std::collections::BTreeMap;
+
+/// The holdings deciding when the load output is on, read with `LoadControl::generate_get_commands`
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub struct LoadControl {
+    /// `None` for a value the Tracer does not define
+    pub mode: Option<LoadControlMode>,
+    pub default_load_on: bool,
+    pub nighttime_threshold_voltage: f32,
+    /// in minutes
+    pub light_signal_startup_delay: u16,
+    pub daytime_threshold_voltage: f32,
+    /// in minutes
+    pub light_signal_close_delay: u16,
+    /// turn on and turn off time of the first and second timer, `None` if invalid
+    pub turn_on: [Option<NaiveTime>; 2],
+    pub turn_off: [Option<NaiveTime>; 2],
+}
+
+impl LoadControl {
+    pub fn generate_get_commands() -> [Command; 4] {
+        [
+            Command::ModbusGetHoldings {
+                register_address: 0x901E,
+                size: 4,
+            },
+            Command::ModbusGetHoldings {
+                register_address: 0x903D,
+                size: 1,
+            },
+            Command::ModbusGetHoldings {
+                register_address: 0x9042,
+                size: 12,
+            },
+            Command::ModbusGetHoldings {
+                register_address: 0x906A,
+                size: 1,
+            },
+        ]
+    }
+
+    pub fn data_len() -> usize {
+        36
+    }
+
+    /// the answers to `generate_get_commands` one after the other
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < Self::data_len() {
+            return Err(format!(
+                "LoadControl needs {} bytes, got {}",
+                Self::data_len(),
+                bytes.len()
+            ));
+        }
+        let register = |ix: usize| u16::from_be_bytes([bytes[ix * 2], bytes[ix * 2 + 1]]);
+        // seconds, minutes and hours in 3 registers
+        let time = |ix: usize| {
+            NaiveTime::from_hms_opt(
+                register(ix + 2) as u32,
+                register(ix + 1) as u32,
+                register(ix) as u32,
+            )
+        };
+        Ok(LoadControl {
+            nighttime_threshold_voltage: two_bytes_to_f32([bytes[0], bytes[1]]),
+            light_signal_startup_delay: register(1),
+            daytime_threshold_voltage: two_bytes_to_f32([bytes[4], bytes[5]]),
+            light_signal_close_delay: register(3),
+            mode: LoadControlMode::from_register(register(4)),
+            turn_on: [time(5), time(11)],
+            turn_off: [time(8), time(14)],
+            default_load_on: register(17) != 0,
+        })
+    }
+}
+
+/// A load control value edited as text in the load tab
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoadField {
+    NighttimeThresholdVoltage,
+    LightSignalStartupDelay,
+    DaytimeThresholdVoltage,
+    LightSignalCloseDelay,
+    TurnOn(LoadTimer),
+    TurnOff(LoadTimer),
+}
+
+impl LoadField {
+    pub const ALL: [LoadField; 8] = [
+        LoadField::NighttimeThresholdVoltage,
+        LoadField::LightSignalStartupDelay,
+        LoadField::DaytimeThresholdVoltage,
+        LoadField::LightSignalCloseDelay,
+        LoadField::TurnOn(LoadTimer::First),
+        LoadField::TurnOff(LoadTimer::First),
+        LoadField::TurnOn(LoadTimer::Second),
+        LoadField::TurnOff(LoadTimer::Second),
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LoadField::NighttimeThresholdVoltage => "night below PV voltage (V)",
+            LoadField::LightSignalStartupDelay => "night after (min)",
+            LoadField::DaytimeThresholdVoltage => "day above PV voltage (V)",
+            LoadField::LightSignalCloseDelay => "day after (min)",
+            LoadField::TurnOn(LoadTimer::First) => "timer 1 on",
+            LoadField::TurnOff(LoadTimer::First) => "timer 1 off",
+            LoadField::TurnOn(LoadTimer::Second) => "timer 2 on",
+            LoadField::TurnOff(LoadTimer::Second) => "timer 2 off",
+        }
+    }
+
+    pub fn format(&self, load_control: &LoadControl) -> String {
+        let time = |time: Option<NaiveTime>| match time {
+            Some(time) => time.format("%H:%M:%S").to_string(),
+            None => "invalid".to_string(),
+        };
+        match self {
+            LoadField::NighttimeThresholdVoltage => {
+                format!("{:.2}", load_control.nighttime_threshold_voltage)
+            }
+            LoadField::LightSignalStartupDelay => {
+                load_control.light_signal_startup_delay.to_string()
+            }
+            LoadField::DaytimeThresholdVoltage => {
+                format!("{:.2}", load_control.daytime_threshold_voltage)
+            }
+            LoadField::LightSignalCloseDelay => load_control.light_signal_close_delay.to_string(),
+            LoadField::TurnOn(timer) => time(load_control.turn_on[*timer as usize]),
+            LoadField::TurnOff(timer) => time(load_control.turn_off[*timer as usize]),
+        }
+    }
+
+    /// The setting writing `input` to the controller
+    pub fn parse(&self, input: &str) -> Result<HoldingSetting, String> {
+        let input = input.trim();
+        let voltage = || {
+            input
+                .parse::<f32>()
+                .ok()
+                .filter(|voltage| (0.0..=100.0).contains(voltage))
+                .ok_or_else(|| format!("{}: {input:?} is not a voltage", self.label()))
+        };
+        let minutes = || {
+            input
+                .parse::<u16>()
+                .map_err(|_| format!("{}: {input:?} is not a number of minutes", self.label()))
+        };
+        let time = || {
+            NaiveTime::parse_from_str(input, "%H:%M:%S")
+                .or_else(|_| NaiveTime::parse_from_str(input, "%H:%M"))
+                .map(|time| time.with_nanosecond(0).unwrap_or(time))
+                .map_err(|_| format!("{}: {input:?} is not a time like 19:30", self.label()))
+        };
+        Ok(match *self {
+            LoadField::NighttimeThresholdVoltage => {
+                HoldingSetting::NighttimeThresholdVoltage(voltage()?)
+            }
+            LoadField::LightSignalStartupDelay => {
+                HoldingSetting::LightSignalStartupDelay(minutes()?)
+            }
+            LoadField::DaytimeThresholdVoltage => {
+                HoldingSetting::DaytimeThresholdVoltage(voltage()?)
+            }
+            LoadField::LightSignalCloseDelay => HoldingSetting::LightSignalCloseDelay(minutes()?),
+            LoadField::TurnOn(timer) => HoldingSetting::TurnOnTiming(timer, time()?),
+            LoadField::TurnOff(timer) => HoldingSetting::TurnOffTiming(timer, time()?),
+        })
+    }
+}
+
+/// State of the load tab, the inputs are refilled whenever the load control is read
+#[derive(Debug, Clone, Default)]
+pub struct LoadControlPanel {
+    pub load_control: Option<LoadControl>,
+    /// state of the load coil, unknown with firmware without coil access
+    pub load_on: Option<bool>,
+    pub inputs: BTreeMap<LoadField, String>,
+    /// the last connection error, e.g. a switch the firmware refused
+    pub status: String,
+}
+
+impl LoadControlPanel {
+    pub fn input(&self, field: LoadField) -> &str {
+        self.inputs.get(&field).map_or("", String::as_str)
+    }
+
+    pub fn update(&mut self, remote_data: &RemoteData) {
+        match remote_data {
+            RemoteData::LoadControl(load_control) => {
+                self.load_control = Some(*load_control);
+                for field in LoadField::ALL {
+                    self.inputs.insert(field, field.format(load_control));
+                }
+            }
+            RemoteData::LoadOutput(on) => {
+                self.load_on = Some(*on);
+                self.status.clear();
+            }
+            RemoteData::ConnectionEvent(ConnectionEvent::Error { message, .. }) => {
+                self.status = message.clone();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server_error::ErrorKind,
+        simulator::{SimulatorConfig, VirtualDevice},
+        tracer_an::{Realtime, LOAD_COIL_ADDRESS},
+    };
+
+    fn read(device: &mut VirtualDevice) -> LoadControl {
+        let bytes: Vec<u8> = LoadControl::generate_get_commands()
+            .into_iter()
+            .flat_map(|command| device.answer(command).unwrap())
+            .collect();
+        LoadControl::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn load_control_of_the_simulator() {
+        let mut device = VirtualDevice::new(SimulatorConfig {
+            start_hour: 20.0,
+            speed: 0.0,
+            ..Default::default()
+        });
+        let load_control = read(&mut device);
+        assert_eq!(load_control.mode, Some(LoadControlMode::Manual));
+        assert!(load_control.default_load_on);
+        assert_eq!(load_control.nighttime_threshold_voltage, 5.0);
+        assert_eq!(load_control.turn_on[0], NaiveTime::from_hms_opt(19, 0, 0));
+        assert_eq!(load_control.turn_off[0], NaiveTime::from_hms_opt(6, 0, 0));
+
+        let mut panel = LoadControlPanel::default();
+        panel.update(&RemoteData::LoadControl(load_control));
+        assert_eq!(panel.input(LoadField::TurnOn(LoadTimer::First)), "19:00:00");
+        let refused = ConnectionEvent::Error {
+            kind: ErrorKind::Protocol,
+            message: "refused".to_string(),
+        };
+        panel.update(&RemoteData::ConnectionEvent(refused));
+        assert_eq!(panel.status, "refused");
+        panel.update(&RemoteData::LoadOutput(false));
+        assert_eq!((panel.load_on, panel.status.as_str()), (Some(false), ""));
+        let field = LoadField::TurnOff(LoadTimer::Second);
+        let setting = field.parse("7:15").unwrap();
+        assert_eq!(device.answer(setting.generate_set_command()), None);
+        let mode = HoldingSetting::LoadControlMode(LoadControlMode::TimeControl);
+        assert_eq!(device.answer(mode.generate_set_command()), None);
+        let load_control = read(&mut device);
+        assert_eq!(load_control.turn_off[1], NaiveTime::from_hms_opt(7, 15, 0));
+        assert_eq!(load_control.mode, Some(LoadControlMode::TimeControl));
+
+        assert!(LoadField::NighttimeThresholdVoltage.parse("five").is_err());
+        assert!(LoadField::TurnOn(LoadTimer::First).parse("25:00").is_err());
+        assert!(LoadControl::from_bytes(&[0; 35]).is_err());
+    }
+
+    #[test]
+    fn load_coil_switches_the_inverter() {
+        let mut device = VirtualDevice::new(SimulatorConfig {
+            start_hour: 20.0,
+            speed: 0.0,
+            ..Default::default()
+        });
+        let coil = LOAD_COIL_ADDRESS;
+        assert_eq!(device.answer(Command::ModbusGetCoil(coil)), Some(vec![1]));
+        let switch_off = Command::ModbusSetCoil {
+            register_address: coil,
+            on: false,
+        };
+        assert_eq!(device.answer(switch_off), None);
+        assert_eq!(device.answer(Command::ModbusGetCoil(coil)), Some(vec![0]));
+        let realtime: Vec<u8> = Realtime::generate_commands()
+            .into_iter()
+            .flat_map(|command| device.answer(command).unwrap())
+            .collect();
+        let realtime = Realtime::from_bytes(&realtime).unwrap();
+        let load_power = realtime
+            .values()
+            .iter()
+            .find(|value| value.0 == "load_power")
+            .unwrap()
+            .2;
+        assert_eq!(load_power, 0.0);
+    }
+}
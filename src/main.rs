@@ -1,45 +1,130 @@
 use all_charts::{AllCharts, SelectedTab};
-use command::Command;
+use calibration::{CalibrationFit, Reference};
+use command::BufferType;
+use config::Config;
+use device::{ConnectedDevices, DeviceId};
+use export::ExportFormat;
 use iced::{
     executor, font,
-    widget::{Column, Container},
+    widget::{Checkbox, Column, Container, PickList, Row},
     Alignment, Application, Length, Settings, Subscription,
 };
+use load_control::LoadField;
+use poll_schedule::PollInterval;
+use recorder::Recorder;
+use register_map::{RegisterDef, RegisterKind, RegisterView};
 use remote_data::RemoteData;
-use server_task::{Server, ServerMessage};
+use server_task::{DeviceMessage, Server, ServerMessage};
 use std::{
+    collections::BTreeMap,
+    path::PathBuf,
     sync::{mpsc::*, Arc, Mutex},
     thread,
     time::Instant,
 };
+use storage::Store;
 use time_interval::TimeInterval;
-use tracer_an::BatteryType;
+use tracer_an::{BatteryType, HoldingSetting, LoadControlMode};
 use udp_broadcast_task::udp_broadcast;
 
 pub mod all_charts;
+pub mod calibration;
 pub mod command;
+pub mod config;
+pub mod device;
+pub mod device_log;
+pub mod device_stream;
+pub mod export;
+pub mod headless;
+pub mod http_api;
+pub mod live_data;
+pub mod load_control;
+pub mod metrics;
+pub mod mqtt;
+pub mod poll_schedule;
+pub mod recorder;
+pub mod register_map;
 pub mod remote_data;
+pub mod server_error;
 pub mod server_task;
+pub mod simulator;
+pub mod storage;
 pub mod time_interval;
 pub mod tracer_an;
 pub mod udp_broadcast_task;
 pub mod voltage_chart;
 
-pub const CHART_HEIGHT: f32 = 400.0;
-
 fn main() {
-    let connected = Arc::new(Mutex::new(false));
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let headless = args.iter().any(|arg| arg == "--headless");
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{e}");
+            return;
+        }
+    };
+    if args.first().map(String::as_str) == Some("export") {
+        let res = storage::select_device_dir(&config.data_dir, arg_value(&args, "--device"))
+            .and_then(|dir| {
+                Store::open(&dir).map_err(|e| format!("could not open {}: {e}", dir.display()))
+            })
+            .and_then(|store| export::run_cli(&args[1..], &store));
+        match res {
+            Ok(path) => println!("exported to {}", path.display()),
+            Err(e) => println!("export failed: {e}"),
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("simulate") {
+        if let Err(e) = simulator::run_cli(&args[1..], &config) {
+            println!("simulator failed: {e}");
+        }
+        return;
+    }
+    let connected = ConnectedDevices::default();
     let connected_bc = connected.clone();
     let connected_main_app = connected.clone();
-    let (remote_data_sender, remote_data_receiver) = channel();
+    let live_data = Arc::new(Mutex::new(BTreeMap::new()));
     let (command_sender, command_receiver) = channel();
+    let mut recorder = Recorder::new(Some(config.data_dir.clone()), config.adc, live_data.clone());
+    if let Some(mqtt_config) = config.mqtt.clone() {
+        let (reading_sender, reading_receiver) = channel();
+        recorder.listeners.push(reading_sender);
+        let command_sender = command_sender.clone();
+        thread::spawn(move || mqtt::run(mqtt_config, reading_receiver, command_sender));
+    }
+    if config.http.enabled {
+        let http = config.http.clone();
+        let live_data = live_data.clone();
+        let connected = connected.clone();
+        thread::spawn(move || http_api::run(&http.address, http.port, live_data, connected));
+    }
+    let (remote_data_sender, remote_data_receiver) = channel();
+    let discovery_config = config.discovery.clone();
+    let server_config = config.server.clone();
+    let poll_schedule = config.poll.clone();
 
-    thread::spawn(move || udp_broadcast(connected_bc));
+    thread::spawn(move || udp_broadcast(connected_bc, discovery_config));
     thread::spawn(move || {
-        Server::run(Server::new(connected, remote_data_sender, command_receiver))
+        Server::run(
+            Server::new(connected, remote_data_sender, command_receiver)
+                .with_config(server_config)
+                .with_poll_schedule(poll_schedule),
+        )
     });
+    if headless {
+        headless::run(remote_data_receiver, connected_main_app, recorder);
+        return;
+    }
     State::run(Settings {
-        flags: (remote_data_receiver, command_sender, connected_main_app),
+        flags: (
+            remote_data_receiver,
+            command_sender,
+            connected_main_app,
+            recorder,
+            config,
+        ),
         id: Default::default(),
         window: Default::default(),
         fonts: Default::default(),
@@ -50,6 +135,14 @@ fn main() {
     .expect("Error: State::run");
 }
 
+/// value following `name` on the command line, e.g. `--data-dir /var/lib/epmon`
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|ix| args.get(ix + 1))
+        .map(String::as_str)
+}
+
 #[derive(Debug, Clone)]
 pub enum Message {
     Tick,
@@ -62,9 +155,12 @@ pub enum Message {
     MinIntegrationSubRange(f32),
     MaxIntegrationSubRange(f32),
     FontLoaded(Result<(), font::Error>),
-    AddressInput(String),
-    ReadHoldings { register_address: u16, size: u8 },
-    ReadRegisters { register_address: u16, size: u8 },
+    RegisterKindSelected(RegisterKind),
+    RegisterAddressInput(String),
+    RegisterCountInput(String),
+    RegisterViewSelected(RegisterView),
+    RegisterSelected(RegisterDef),
+    ReadRegisterRange,
     ReadRealtime,
     ReadRealtimeStatus,
     PauseUnpause,
@@ -73,6 +169,12 @@ pub enum Message {
     ReadVoltageSettings,
     ReadRated,
     ReadStats,
+    ReadLoadControl,
+    SetLoadOutput(bool),
+    LoadControlModeSelected(LoadControlMode),
+    DefaultLoadToggled(bool),
+    LoadFieldInput(LoadField, String),
+    ApplyLoadField(LoadField),
     BatteryTypeSelected(BatteryType),
     InputOverVoltageDisconnect(String),
     InputChargingLimitVoltage(String),
@@ -87,86 +189,168 @@ pub enum Message {
     InputLowVoltageDisconnectVoltage(String),
     InputDischargingLimitVoltage(String),
     SendServerMessage(ServerMessage),
+    ExportChartSelected(String),
+    DeviceSelected(DeviceId),
+    OverlayToggled(bool),
+    CalibrationChannelSelected(BufferType),
+    CalibrationReferenceSelected(Reference),
+    CalibrationManualReference(String),
+    CalibrationCapture,
+    CalibrationClearPoints,
+    CalibrationApply(CalibrationFit),
+    Export(ExportFormat),
+    LogFilterChanged(String),
 }
 
 struct State {
+    /// charts of the selected device
     charts: AllCharts,
+    /// charts of all other devices, swapped with `charts` when another device is selected
+    other_devices: BTreeMap<DeviceId, AllCharts>,
+    /// draw the charts of the other devices into the selected device's charts
+    overlay: bool,
     start_instant: Instant,
-    voltage_buffer_size: usize,
-    remote_data_receiver: Receiver<RemoteData>,
-    server_message_sender: Sender<ServerMessage>,
+    remote_data_receiver: Receiver<(DeviceId, RemoteData)>,
+    server_message_sender: Sender<DeviceMessage>,
+    connected: ConnectedDevices,
+    recorder: Recorder,
+    /// chart data that arrived while the charts were paused, shown when they continue
+    held_back: Vec<(DeviceId, RemoteData)>,
+    chart_height: f32,
+    realtime_tick_len: Option<f32>,
 }
 
 impl State {
-    fn tick_update(&mut self) {
+    /// Returns whether remote data arrived
+    fn tick_update(&mut self) -> bool {
+        let mut received = false;
         // receive all the remote data in the channel in a loop
-        while let Ok(remote_data) = self.remote_data_receiver.try_recv() {
-            self.update_remote_data(remote_data);
+        while let Ok((device, remote_data)) = self.remote_data_receiver.try_recv() {
+            self.update_remote_data(device, remote_data);
+            received = true;
         }
         self.charts.time_correctness = self.charts.pv.tick_len * self.charts.pv.data.len() as f32
-            / (self.voltage_buffer_size as f32 * self.charts.pv.tick_len
+            / (self.charts.voltage_buffer_size as f32 * self.charts.pv.tick_len
                 + (Instant::now() - self.start_instant).as_secs() as f32);
+        received
     }
 
-    fn update_remote_data(&mut self, mut remote_data: RemoteData) {
-        let mut bupdate_battery2 = false;
-        match remote_data {
-            RemoteData::NoData => {}
-            RemoteData::BatteryVoltage(_) => {
-                self.charts
-                    .battery1
-                    .update_voltages_from_remote(&mut remote_data);
-                bupdate_battery2 = true;
-            }
-            RemoteData::BatteryPackVoltage(_) => {
-                self.charts
-                    .battery_pack
-                    .update_voltages_from_remote(&mut remote_data);
-                bupdate_battery2 = true;
-            }
-            RemoteData::PVVoltage(_) => {
-                self.charts.pv.update_voltages_from_remote(&mut remote_data);
-            }
-            RemoteData::PVPower(_) => {
-                self.charts
-                    .pv_power
-                    .update_power_from_remote(&mut remote_data);
-            }
-            RemoteData::VoltageBufferSize(s) => self.voltage_buffer_size = s,
-            RemoteData::VoltageIntervalms(interval) => {
-                let tick_len = interval as f32 / 1000.0;
-                self.charts.battery1.tick_len = tick_len;
-                self.charts.battery2.tick_len = tick_len;
-                self.charts.battery_pack.tick_len = tick_len;
-                self.charts.pv.tick_len = tick_len;
-            }
-            RemoteData::PowerIntervalms(interval) => {
-                let tick_len = interval as f32 / 1000.0;
-                println!("power chart tick_len : {}", tick_len);
-                self.charts.pv_power.tick_len = tick_len;
-                self.charts.inverter_power.tick_len = tick_len;
-            }
-            RemoteData::Holdings(val) | RemoteData::InputRegisters(val) => {
-                self.charts.modbus_val = val;
-            }
-            RemoteData::Realtime(realtime) => self.charts.realtime_data = realtime,
-            RemoteData::RealtimeStatus(realtime_status) => {
-                self.charts.realtime_status_data = realtime_status
-            }
-            RemoteData::VoltageSettings(voltage_settings) => {
-                self.charts.voltage_settings = voltage_settings;
-                self.charts.change_voltage_settings = voltage_settings;
-            }
-            RemoteData::Rated(rated) => {
-                self.charts.rated_data = rated;
-            }
-            RemoteData::Stats(stats) => {
-                self.charts.stats = stats;
+    /// Sends `message` to the selected device
+    fn send(&self, message: ServerMessage) {
+        self.server_message_sender
+            .send((self.charts.device.clone(), message))
+            .expect("command sender: could not send command");
+    }
+
+    /// Writes `setting` to the selected device and reads the load control back
+    fn write_load_setting(&self, setting: HoldingSetting) {
+        self.send(ServerMessage::Command(setting.generate_set_command()));
+        self.send(ServerMessage::ReadLoadControl);
+    }
+
+    /// Charts for a device that sent data for the first time, filled with its stored history
+    fn new_charts(&mut self, device: &DeviceId) -> AllCharts {
+        self.recorder.add_device(device);
+        let mut charts = AllCharts {
+            connected: self.connected.clone(),
+            device: Some(device.clone()),
+            chart_height: self.chart_height,
+            ..Default::default()
+        };
+        if let Ok(live_data) = self.recorder.live_data.lock() {
+            if let Some(live_data) = live_data.get(device) {
+                charts.calibration = live_data.calibration.clone();
             }
         }
-        if bupdate_battery2 {
-            self.charts.update_battery2();
+        if let Some(store) = self.recorder.store(device) {
+            charts.load_history(store);
+        }
+        charts.export_dir = self
+            .recorder
+            .device_dir(device)
+            .unwrap_or_else(|| PathBuf::from("."));
+        if let Some(tick_len) = self.realtime_tick_len {
+            charts.set_realtime_tick_len(tick_len);
+        }
+        charts
+    }
+
+    /// The charts of `device`, the first device that connects gets selected
+    fn charts_mut(&mut self, device: &DeviceId) -> &mut AllCharts {
+        if self.charts.device.is_none() {
+            let mut charts = self.new_charts(device);
+            charts.selected_tab = self.charts.selected_tab;
+            self.charts = charts;
+        }
+        if self.charts.device.as_ref() == Some(device) {
+            return &mut self.charts;
+        }
+        if !self.other_devices.contains_key(device) {
+            let charts = self.new_charts(device);
+            self.other_devices.insert(device.clone(), charts);
         }
+        self.other_devices
+            .get_mut(device)
+            .expect("charts were just inserted")
+    }
+
+    fn select_device(&mut self, device: DeviceId) {
+        let Some(mut charts) = self.other_devices.remove(&device) else {
+            return;
+        };
+        charts.selected_tab = self.charts.selected_tab;
+        charts.chart_controls = self.charts.chart_controls;
+        charts.paused = self.charts.paused;
+        let previous = std::mem::replace(&mut self.charts, charts);
+        if let Some(previous_device) = previous.device.clone() {
+            self.other_devices.insert(previous_device, previous);
+        }
+        self.refresh_overlays();
+    }
+
+    fn refresh_overlays(&mut self) {
+        if self.overlay {
+            self.charts.overlay(&mut self.other_devices);
+        } else {
+            self.charts.overlay(&mut BTreeMap::new());
+        }
+    }
+
+    /// Hands the calibration edited in the gui to the recorder and writes it to the device's data dir
+    fn save_calibration(&mut self) {
+        let Some(device) = self.charts.device.clone() else {
+            self.charts.calibration_wizard.status = "no device connected".to_string();
+            return;
+        };
+        self.charts.calibration_wizard.status = match self
+            .recorder
+            .save_calibration(&device, &self.charts.calibration)
+        {
+            Ok(path) => format!("saved to {}", path.display()),
+            Err(e) => e,
+        };
+    }
+
+    /// Records `remote_data` at once, pausing only holds back what the charts show
+    fn update_remote_data(&mut self, device: DeviceId, mut remote_data: RemoteData) {
+        // the charts load the stored history before new samples are stored
+        self.charts_mut(&device);
+        self.recorder.record(&device, &mut remote_data);
+        let chart_data =
+            remote_data.readings().is_some() || matches!(remote_data, RemoteData::Realtime(_));
+        if self.charts.paused && chart_data {
+            self.held_back.push((device, remote_data));
+        } else {
+            self.update_charts(device, remote_data);
+        }
+    }
+
+    fn update_charts(&mut self, device: DeviceId, remote_data: RemoteData) {
+        let charts = self.charts_mut(&device);
+        charts
+            .calibration_wizard
+            .observe(&remote_data, &charts.calibration.adc);
+        charts.update_remote_data(remote_data);
     }
 }
 
@@ -178,24 +362,38 @@ impl Application for State {
     type Theme = iced::Theme;
 
     type Flags = (
-        Receiver<RemoteData>,
-        Sender<ServerMessage>,
-        Arc<Mutex<bool>>,
+        Receiver<(DeviceId, RemoteData)>,
+        Sender<DeviceMessage>,
+        ConnectedDevices,
+        Recorder,
+        Config,
     );
 
     fn new(
-        (remote_data_receiver, command_sender, connected): Self::Flags,
+        (remote_data_receiver, command_sender, connected, recorder, config): Self::Flags,
     ) -> (Self, iced::Command<Self::Message>) {
+        let charts = AllCharts {
+            connected: connected.clone(),
+            chart_height: config.gui.chart_height,
+            ..Default::default()
+        };
+        let realtime_tick_len = match config.poll.realtime {
+            PollInterval::Every(interval) => Some(interval.as_secs_f32()),
+            _ => None,
+        };
         (
             Self {
-                charts: AllCharts {
-                    connected,
-                    ..Default::default()
-                },
+                charts,
+                other_devices: BTreeMap::new(),
+                overlay: false,
                 start_instant: Instant::now(),
-                voltage_buffer_size: 0,
                 remote_data_receiver,
                 server_message_sender: command_sender,
+                connected,
+                recorder,
+                held_back: Vec::new(),
+                chart_height: config.gui.chart_height,
+                realtime_tick_len,
             },
             iced::Command::none(),
         )
@@ -210,8 +408,13 @@ impl Application for State {
     }
 
     fn update(&mut self, message: Self::Message) -> iced::Command<Self::Message> {
-        match message {
+        // overlays are only recomputed when the displayed data or time window changed
+        let refresh_overlays = match message {
             Message::Tick => self.tick_update(),
+            _ => true,
+        };
+        match message {
+            Message::Tick => {}
             Message::TimeIntervallSelected(interval) => self.charts.adjust_time_interval(interval),
             Message::MaxTimeDaySelected(t) => {
                 self.charts.max_time_day = t;
@@ -235,69 +438,72 @@ impl Application for State {
             }
             Message::MinIntegrationSubRange(min) => {
                 self.charts.pv_power.integration_sub_range.start = min;
+                self.charts.inverter_power.integration_sub_range.start = min;
             }
             Message::MaxIntegrationSubRange(max) => {
                 self.charts.pv_power.integration_sub_range.end = max;
-            }
-            Message::PauseUnpause => self.charts.paused = !self.charts.paused,
-            Message::AddressInput(s) => {
-                if let Ok(address) = u16::from_str_radix(&s, 16) {
-                    self.charts.register_address = address;
-                    self.charts.register_address_string = s;
+                self.charts.inverter_power.integration_sub_range.end = max;
+            }
+            Message::PauseUnpause => {
+                self.charts.paused = !self.charts.paused;
+                if !self.charts.paused {
+                    for (device, remote_data) in std::mem::take(&mut self.held_back) {
+                        self.update_charts(device, remote_data);
+                    }
                 }
             }
-            Message::ReadHoldings {
-                register_address,
-                size,
-            } => {
-                self.server_message_sender
-                    .send(ServerMessage::Command(Command::ModbusGetHoldings {
-                        register_address,
-                        size,
-                    }))
-                    .expect("command_sender: could not send command");
-            }
-            Message::ReadRegisters {
-                register_address,
-                size,
-            } => {
-                self.server_message_sender
-                    .send(ServerMessage::Command(Command::ModbusGetInputRegisters {
-                        register_address,
-                        size,
-                    }))
-                    .expect("command_sender: could not send command");
+            Message::RegisterKindSelected(kind) => self.charts.register_browser.kind = kind,
+            Message::RegisterAddressInput(input) => {
+                self.charts.register_browser.address_input = input
             }
+            Message::RegisterCountInput(input) => self.charts.register_browser.count_input = input,
+            Message::RegisterViewSelected(view) => self.charts.register_browser.view = view,
+            Message::RegisterSelected(def) => self.charts.register_browser.select(&def),
+            Message::ReadRegisterRange => match self.charts.register_browser.read() {
+                Ok(command) => self.send(ServerMessage::Command(command)),
+                Err(e) => self.charts.register_browser.status = e,
+            },
             Message::ReadRealtime => {
-                self.server_message_sender
-                    .send(ServerMessage::ReadRealtime)
-                    .expect("command sender: could not send command");
+                self.send(ServerMessage::ReadRealtime);
             }
             Message::ReadRealtimeStatus => {
-                self.server_message_sender
-                    .send(ServerMessage::ReadRealtimeStatus)
-                    .expect("command sender: could not send command");
+                self.send(ServerMessage::ReadRealtimeStatus);
             }
             Message::ReadVoltageSettings => {
-                self.server_message_sender
-                    .send(ServerMessage::ReadVoltageSettings)
-                    .expect("command sender: could not send command");
+                self.send(ServerMessage::ReadVoltageSettings);
             }
             Message::ReadRated => {
-                self.server_message_sender
-                    .send(ServerMessage::ReadRated)
-                    .expect("command sender: could not send command");
+                self.send(ServerMessage::ReadRated);
             }
             Message::ReadStats => {
-                self.server_message_sender
-                    .send(ServerMessage::ReadStats)
-                    .expect("command sender: could not send command");
+                self.send(ServerMessage::ReadStats);
+            }
+            Message::ReadLoadControl => self.send(ServerMessage::ReadLoadControl),
+            Message::SetLoadOutput(on) => self.send(ServerMessage::SetLoadOutput(on)),
+            Message::LoadControlModeSelected(mode) => {
+                self.write_load_setting(HoldingSetting::LoadControlMode(mode))
+            }
+            Message::DefaultLoadToggled(on) => {
+                self.write_load_setting(HoldingSetting::DefaultLoadOn(on))
+            }
+            Message::LoadFieldInput(field, input) => {
+                self.charts.load_panel.inputs.insert(field, input);
+            }
+            Message::ApplyLoadField(field) => {
+                match field.parse(self.charts.load_panel.input(field)) {
+                    Ok(setting) => self.write_load_setting(setting),
+                    Err(e) => self.charts.write_status = e,
+                }
             }
             Message::TabSelected(ix) => match ix {
                 0 => self.charts.selected_tab = SelectedTab::VoltageCharts,
                 1 => self.charts.selected_tab = SelectedTab::PowerCharts,
-                2 => self.charts.selected_tab = SelectedTab::Stats,
-                _ => self.charts.selected_tab = SelectedTab::Settings,
+                2 => self.charts.selected_tab = SelectedTab::RealtimeCharts,
+                3 => self.charts.selected_tab = SelectedTab::Stats,
+                4 => self.charts.selected_tab = SelectedTab::Settings,
+                5 => self.charts.selected_tab = SelectedTab::Connection,
+                6 => self.charts.selected_tab = SelectedTab::DeviceLog,
+                _ => self.charts.selected_tab = SelectedTab::Load,
             },
             Message::ToggleChartControls => {
                 self.charts.chart_controls = !self.charts.chart_controls
@@ -397,24 +603,69 @@ impl Application for State {
                         .discharging_limit_voltage = f;
                 }
             }
-            Message::SendServerMessage(message) => self
-                .server_message_sender
-                .send(message)
-                .expect("could not send server message"),
+            Message::SendServerMessage(message) => self.send(message),
+            Message::DeviceSelected(device) => self.select_device(device),
+            Message::OverlayToggled(overlay) => self.overlay = overlay,
 
+            Message::ExportChartSelected(selection) => self.charts.export_selection = selection,
+            Message::LogFilterChanged(filter) => self.charts.log_filter = filter,
+            Message::Export(format) => self.charts.export(format),
+            Message::CalibrationChannelSelected(channel) => {
+                self.charts.calibration_wizard.select_channel(channel)
+            }
+            Message::CalibrationReferenceSelected(reference) => {
+                self.charts.calibration_wizard.reference = reference
+            }
+            Message::CalibrationManualReference(input) => {
+                self.charts.calibration_wizard.manual_reference = input
+            }
+            Message::CalibrationCapture => {
+                let charts = &mut self.charts;
+                charts
+                    .calibration_wizard
+                    .capture(&charts.realtime_data, &charts.stats);
+            }
+            Message::CalibrationClearPoints => self.charts.calibration_wizard.points.clear(),
+            Message::CalibrationApply(fit) => {
+                if self.charts.apply_calibration(fit) {
+                    self.save_calibration();
+                }
+            }
             Message::FontLoaded(_) => {}
         }
+        if refresh_overlays {
+            self.refresh_overlays();
+        }
         self.charts.clear_caches();
         iced::Command::none()
     }
 
     fn view(&self) -> iced::Element<'_, Self::Message, Self::Theme, iced::Renderer> {
-        let content = Column::new()
+        let mut content = Column::new()
             .spacing(20)
             .align_items(Alignment::Start)
             .width(Length::Fill)
-            .height(Length::Fill)
-            .push(self.charts.view());
+            .height(Length::Fill);
+        if !self.other_devices.is_empty() {
+            let mut devices: Vec<DeviceId> = self.other_devices.keys().cloned().collect();
+            devices.extend(self.charts.device.clone());
+            devices.sort();
+            content = content.push(
+                Row::new()
+                    .spacing(20)
+                    .align_items(Alignment::Center)
+                    .push(PickList::new(
+                        devices,
+                        self.charts.device.clone(),
+                        Message::DeviceSelected,
+                    ))
+                    .push(
+                        Checkbox::new("overlay other devices", self.overlay)
+                            .on_toggle(Message::OverlayToggled),
+                    ),
+            );
+        }
+        let content = content.push(self.charts.view());
 
         Container::new(content)
             //.style(style::Container)
@@ -426,15 +677,8 @@ impl Application for State {
             .into()
     }
 
+    /// keeps ticking while the charts are paused, remote data is recorded all the time
     fn subscription(&self) -> Subscription<Self::Message> {
-        if !self.charts.paused {
-            iced::time::every(iced::time::Duration::from_millis(100)).map(|_| Message::Tick)
-        } else {
-            Subscription::none()
-        }
+        iced::time::every(iced::time::Duration::from_millis(100)).map(|_| Message::Tick)
     }
 }
-
-fn adc_reading_to_voltage(adc_reading: u16) -> f32 {
-    (20700.0 / 124.0) * 1.1 * adc_reading as f32 / 4081.0
-}
@@ -0,0 +1,223 @@
+use crate::{
+    device::DeviceId,
+    live_data::LiveData,
+    server_error::ErrorKind,
+    tracer_an::{Realtime, Stats},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const PREFIX: &str = "epmon";
+
+/// Renders the live data of all devices in the OpenMetrics text format,
+/// every sample is labeled with its device
+pub fn render(devices: &BTreeMap<DeviceId, LiveData>, connected: &BTreeSet<DeviceId>) -> String {
+    let mut out = String::new();
+    family(&mut out, "connected", "gauge", None, "device is connected");
+    for device in devices.keys() {
+        let value = bool_value(connected.contains(device));
+        sample(&mut out, "connected", &labels(device, None), value);
+    }
+
+    family(
+        &mut out,
+        "connection_errors",
+        "counter",
+        None,
+        "connection errors by kind",
+    );
+    for (device, live_data) in devices {
+        for kind in ErrorKind::ALL {
+            let count = live_data.error_counts.get(&kind).copied().unwrap_or(0);
+            let labels = labels(device, Some(("kind", kind.name())));
+            sample(&mut out, "connection_errors_total", &labels, count as f32);
+        }
+    }
+
+    if devices.is_empty() {
+        out.push_str("# EOF\n");
+        return out;
+    }
+    // readings a device did not report yet are left out instead of showing zeros
+    for (index, (name, unit, _)) in Realtime::default().values().into_iter().enumerate() {
+        let metric = format!("{name}_{unit}");
+        family(&mut out, &metric, "gauge", Some(unit), "realtime reading");
+        for (device, live_data) in devices {
+            let Some(realtime) = &live_data.realtime else {
+                continue;
+            };
+            let value = realtime.values()[index].2;
+            sample(&mut out, &metric, &labels(device, None), value);
+        }
+    }
+
+    // only the total energy is a counter, the day, month and year energy start again from zero
+    for kind in ["consumed", "generated"] {
+        let prefix = format!("{kind}_energy_");
+        let counter = format!("{kind}_energy_kwh");
+        let gauge = format!("{kind}_period_energy_kwh");
+        family(&mut out, &counter, "counter", Some("kwh"), "energy counter");
+        for (device, live_data) in devices {
+            let Some(stats) = &live_data.stats else {
+                continue;
+            };
+            for (name, value) in stats.values() {
+                if name.strip_prefix(&prefix) == Some("total") {
+                    sample(
+                        &mut out,
+                        &format!("{counter}_total"),
+                        &labels(device, None),
+                        value,
+                    );
+                }
+            }
+        }
+        family(
+            &mut out,
+            &gauge,
+            "gauge",
+            Some("kwh"),
+            "energy in the current period",
+        );
+        for (device, live_data) in devices {
+            let Some(stats) = &live_data.stats else {
+                continue;
+            };
+            for (name, value) in stats.values() {
+                match name.strip_prefix(&prefix) {
+                    Some("total") | None => {}
+                    Some(period) => {
+                        let labels = labels(device, Some(("period", period)));
+                        sample(&mut out, &gauge, &labels, value);
+                    }
+                }
+            }
+        }
+    }
+    for (index, (name, _)) in Stats::default().values().into_iter().enumerate() {
+        if name.contains("_energy_") {
+            continue;
+        }
+        let unit = if name.ends_with("current") {
+            "amperes"
+        } else {
+            "volts"
+        };
+        let metric = format!("stats_{name}_{unit}");
+        family(&mut out, &metric, "gauge", Some(unit), "statistic");
+        for (device, live_data) in devices {
+            let Some(stats) = &live_data.stats else {
+                continue;
+            };
+            let value = stats.values()[index].1;
+            sample(&mut out, &metric, &labels(device, None), value);
+        }
+    }
+
+    family(
+        &mut out,
+        "status_flag",
+        "gauge",
+        None,
+        "realtime status bit",
+    );
+    for (device, live_data) in devices {
+        let Some(realtime_status) = &live_data.realtime_status else {
+            continue;
+        };
+        for (name, value) in realtime_status.flags() {
+            let labels = labels(device, Some(("flag", name)));
+            sample(&mut out, "status_flag", &labels, bool_value(value));
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn labels(device: &DeviceId, extra: Option<(&str, &str)>) -> String {
+    match extra {
+        Some((name, value)) => format!("{{device=\"{device}\",{name}=\"{value}\"}}"),
+        None => format!("{{device=\"{device}\"}}"),
+    }
+}
+
+fn bool_value(b: bool) -> f32 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn family(out: &mut String, name: &str, metric_type: &str, unit: Option<&str>, help: &str) {
+    // writing into a String can not fail
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} {metric_type}");
+    if let Some(unit) = unit {
+        let _ = writeln!(out, "# UNIT {PREFIX}_{name} {unit}");
+    }
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: f32) {
+    let _ = writeln!(out, "{PREFIX}_{name}{labels} {value}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{remote_data::RemoteData, server_error::ConnectionEvent};
+
+    #[test]
+    fn render_contains_all_families() {
+        let a = DeviceId("10.0.0.2".to_string());
+        let b = DeviceId("10.0.0.3".to_string());
+        let mut live_data = LiveData::default();
+        live_data.update(RemoteData::ConnectionEvent(ConnectionEvent::Error {
+            kind: ErrorKind::Timeout,
+            message: String::new(),
+        }));
+        live_data.update(RemoteData::Realtime(Realtime::default()));
+        live_data.update(RemoteData::RealtimeStatus(Default::default()));
+        live_data.update(RemoteData::Stats(Stats::default()));
+        let devices = BTreeMap::from([(a.clone(), live_data), (b, LiveData::default())]);
+        let text = render(&devices, &BTreeSet::from([a]));
+        assert!(text.contains("epmon_connected{device=\"10.0.0.2\"} 1\n"));
+        assert!(text.contains("epmon_connected{device=\"10.0.0.3\"} 0\n"));
+        assert!(text
+            .contains("epmon_connection_errors_total{device=\"10.0.0.2\",kind=\"timeout\"} 1\n"));
+        assert_eq!(
+            text.matches("# TYPE epmon_pv_voltage_volts gauge\n")
+                .count(),
+            1
+        );
+        assert!(text.contains("epmon_remaining_battery_capacity_percent{device=\"10.0.0.2\"} 0\n"));
+        assert!(text.contains("# TYPE epmon_generated_energy_kwh counter\n"));
+        assert!(text.contains("epmon_generated_energy_kwh_total{device=\"10.0.0.2\"} 0\n"));
+        assert!(text.contains("# TYPE epmon_generated_period_energy_kwh gauge\n"));
+        assert!(text
+            .contains("epmon_generated_period_energy_kwh{device=\"10.0.0.2\",period=\"day\"} 0\n"));
+        assert!(!text.contains("period=\"total\""));
+        assert!(text.contains("epmon_stats_battery_current_amperes{device=\"10.0.0.2\"} 0\n"));
+        assert!(text.contains("epmon_status_flag{device=\"10.0.0.2\",flag=\"charging_fault\"} 0\n"));
+        // nothing but the connection was reported for the second device
+        assert_eq!(
+            text.matches("device=\"10.0.0.3\"").count(),
+            1 + ErrorKind::ALL.len()
+        );
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn render_without_devices() {
+        let text = render(&BTreeMap::new(), &BTreeSet::new());
+        assert_eq!(
+            text,
+            "# TYPE epmon_connected gauge\n# HELP epmon_connected device is connected\n\
+             # TYPE epmon_connection_errors counter\n\
+             # HELP epmon_connection_errors connection errors by kind\n# EOF\n"
+        );
+    }
+}
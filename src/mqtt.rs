@@ -0,0 +1,603 @@
+use crate::{
+    device::DeviceId,
+    remote_data::RemoteData,
+    server_error::ConnectionEvent,
+    server_task::{DeviceMessage, ServerMessage},
+    tracer_an::{Realtime, RealtimeStatus, Stats, VoltageSettings},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::mpsc::{Receiver, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// how long to wait for an incoming packet before publishing queued readings again
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+/// how long to wait for the rest of a packet once its first byte arrived
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// host:port of the broker
+    pub address: String,
+    #[serde(default = "default_base_topic")]
+    pub client_id: String,
+    /// readings are published to `<base_topic>/<device>/realtime` etc.,
+    /// commands are received on `<base_topic>/<device>/command`
+    /// and on `<base_topic>/command` for all devices, which only accepts reads
+    #[serde(default = "default_base_topic")]
+    pub base_topic: String,
+    /// Home Assistant listens for discovery configs below this prefix
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// sent in the CONNECT packet, the password only together with a username
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+fn default_base_topic() -> String {
+    "epmon".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+impl MqttConfig {
+    /// `--mqtt host:port [--mqtt-topic epmon] [--mqtt-discovery-prefix homeassistant]`,
+    /// `--mqtt` enables publishing if the config file has no `[mqtt]` section
+    pub fn apply_args(config: &mut Option<MqttConfig>, args: &[String]) {
+        if let Some(address) = crate::arg_value(args, "--mqtt") {
+            let config = config.get_or_insert_with(|| MqttConfig {
+                address: String::new(),
+                client_id: default_base_topic(),
+                base_topic: default_base_topic(),
+                discovery_prefix: default_discovery_prefix(),
+                username: None,
+                password: None,
+            });
+            config.address = address.to_string();
+        }
+        let Some(config) = config else {
+            return;
+        };
+        if let Some(base_topic) = crate::arg_value(args, "--mqtt-topic") {
+            config.client_id = base_topic.replace('/', "_");
+            config.base_topic = base_topic.to_string();
+        }
+        if let Some(prefix) = crate::arg_value(args, "--mqtt-discovery-prefix") {
+            config.discovery_prefix = prefix.to_string();
+        }
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{name}", self.base_topic)
+    }
+
+    fn device_topic(&self, device: &DeviceId, name: &str) -> String {
+        format!("{}/{}/{name}", self.base_topic, device.slug())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Packet {
+    ConnAck { return_code: u8 },
+    SubAck,
+    Publish { topic: String, payload: Vec<u8> },
+    PingResp,
+    Other(u8),
+}
+
+impl Packet {
+    fn decode(header: u8, body: &[u8]) -> io::Result<Packet> {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "malformed mqtt packet");
+        Ok(match header & 0xF0 {
+            CONNACK => Packet::ConnAck {
+                return_code: *body.get(1).ok_or_else(invalid)?,
+            },
+            SUBACK => Packet::SubAck,
+            PINGRESP => Packet::PingResp,
+            PUBLISH => {
+                let (topic, rest) = decode_string(body).ok_or_else(invalid)?;
+                let qos = (header >> 1) & 0b11;
+                // QoS 1 and 2 messages carry a packet identifier before the payload
+                let payload = if qos > 0 { rest.get(2..) } else { Some(rest) };
+                Packet::Publish {
+                    topic,
+                    payload: payload.ok_or_else(invalid)?.to_vec(),
+                }
+            }
+            other => Packet::Other(other),
+        })
+    }
+}
+
+fn encode_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn decode_string(bytes: &[u8]) -> Option<(String, &[u8])> {
+    let len = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]) as usize;
+    let s = String::from_utf8(bytes.get(2..2 + len)?.to_vec()).ok()?;
+    Some((s, &bytes[2 + len..]))
+}
+
+/// fixed header + variable length encoded remaining length + body
+fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut res = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        res.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    res.extend_from_slice(body);
+    res
+}
+
+/// reads the remaining length and the body of a packet whose first byte was already read
+fn read_body<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = 0;
+    let mut multiplier = 1;
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        len += (byte[0] & 0x7F) as usize * multiplier;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        multiplier *= 128;
+        if multiplier > 128 * 128 * 128 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "malformed remaining length",
+            ));
+        }
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// blocking read of one packet, returns the first byte and the body
+pub fn read_packet<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0];
+    reader.read_exact(&mut header)?;
+    Ok((header[0], read_body(reader)?))
+}
+
+pub struct MqttClient {
+    stream: TcpStream,
+    next_packet_id: u16,
+}
+
+impl MqttClient {
+    pub fn connect(config: &MqttConfig) -> io::Result<MqttClient> {
+        let mut stream = TcpStream::connect(&config.address)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut body = Vec::new();
+        encode_string(&mut body, "MQTT");
+        // protocol level 4 (3.1.1), clean session, username and password flags
+        let password = config.username.as_ref().and(config.password.as_ref());
+        let mut flags = 0x02;
+        if config.username.is_some() {
+            flags |= 0x80;
+        }
+        if password.is_some() {
+            flags |= 0x40;
+        }
+        body.extend_from_slice(&[4, flags]);
+        body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        encode_string(&mut body, &config.client_id);
+        for field in [config.username.as_ref(), password].into_iter().flatten() {
+            encode_string(&mut body, field);
+        }
+        stream.write_all(&encode_packet(CONNECT, &body))?;
+        let (header, body) = read_packet(&mut stream)?;
+        match Packet::decode(header, &body)? {
+            Packet::ConnAck { return_code: 0 } => Ok(MqttClient {
+                stream,
+                next_packet_id: 1,
+            }),
+            Packet::ConnAck { return_code } => Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("broker refused connection: {return_code}"),
+            )),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("expected CONNACK, got {other:?}"),
+            )),
+        }
+    }
+
+    /// QoS 0
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let mut body = Vec::new();
+        encode_string(&mut body, topic);
+        body.extend_from_slice(payload);
+        let header = if retain { PUBLISH | 0x01 } else { PUBLISH };
+        self.stream.write_all(&encode_packet(header, &body))
+    }
+
+    pub fn subscribe(&mut self, topic: &str) -> io::Result<()> {
+        let mut body = self.next_packet_id.to_be_bytes().to_vec();
+        self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+        encode_string(&mut body, topic);
+        body.push(0);
+        self.stream.write_all(&encode_packet(SUBSCRIBE, &body))
+    }
+
+    pub fn ping(&mut self) -> io::Result<()> {
+        self.stream.write_all(&encode_packet(PINGREQ, &[]))
+    }
+
+    /// waits at most `POLL_TIMEOUT` for an incoming packet
+    pub fn poll(&mut self) -> io::Result<Option<Packet>> {
+        self.stream.set_read_timeout(Some(POLL_TIMEOUT))?;
+        let mut header = [0];
+        let res = self.stream.read(&mut header);
+        self.stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        match res {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {
+                let body = read_body(&mut self.stream)?;
+                Packet::decode(header[0], &body).map(Some)
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Publishes readings until `readings` hangs up, reconnects to the broker when the connection breaks
+pub fn run(
+    config: MqttConfig,
+    readings: Receiver<(DeviceId, RemoteData)>,
+    server_message_sender: Sender<DeviceMessage>,
+) {
+    loop {
+        match MqttClient::connect(&config) {
+            Ok(mut client) => {
+                println!("mqtt: connected to {}", config.address);
+                match serve(&mut client, &config, &readings, &server_message_sender) {
+                    Ok(()) => return,
+                    Err(e) => println!("mqtt: connection lost: {e}"),
+                }
+            }
+            Err(e) => println!("mqtt: could not connect to {}: {e}", config.address),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+fn serve(
+    client: &mut MqttClient,
+    config: &MqttConfig,
+    readings: &Receiver<(DeviceId, RemoteData)>,
+    server_message_sender: &Sender<DeviceMessage>,
+) -> io::Result<()> {
+    let command_topic = config.topic("command");
+    client.subscribe(&command_topic)?;
+    client.subscribe(&config.topic("+/command"))?;
+    // devices are announced to Home Assistant with their first reading on this connection,
+    // commands for a device topic are routed by its slug
+    let mut announced: BTreeMap<String, DeviceId> = BTreeMap::new();
+    let mut last_ping = Instant::now();
+    loop {
+        loop {
+            match readings.try_recv() {
+                Ok((device, remote_data)) => {
+                    if let Entry::Vacant(entry) = announced.entry(device.slug()) {
+                        for (topic, payload) in discovery_messages(config, &device) {
+                            client.publish(&topic, payload.as_bytes(), true)?;
+                        }
+                        entry.insert(device.clone());
+                    }
+                    publish_reading(client, config, &device, &remote_data)?
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        if let Some(Packet::Publish { topic, payload }) = client.poll()? {
+            let device = if topic == command_topic {
+                Some(None)
+            } else {
+                topic
+                    .strip_prefix(&format!("{}/", config.base_topic))
+                    .and_then(|rest| rest.strip_suffix("/command"))
+                    .and_then(|slug| announced.get(slug))
+                    .map(|device| Some(device.clone()))
+            };
+            match (device, parse_command(&payload)) {
+                // anyone on the broker may publish there, so it must not switch every device
+                (Some(None), Ok(message)) if message.writes_device() => {
+                    println!("mqtt: ignoring {message:?} for all devices, use a device topic")
+                }
+                (Some(device), Ok(message)) => {
+                    if server_message_sender.send((device, message)).is_err() {
+                        return Ok(());
+                    }
+                }
+                (None, _) => println!("mqtt: ignoring command for unknown device: {topic}"),
+                (_, Err(e)) => println!("mqtt: ignoring command: {e}"),
+            }
+        }
+        if last_ping.elapsed() >= PING_INTERVAL {
+            client.ping()?;
+            last_ping = Instant::now();
+        }
+    }
+}
+
+fn publish_reading(
+    client: &mut MqttClient,
+    config: &MqttConfig,
+    device: &DeviceId,
+    remote_data: &RemoteData,
+) -> io::Result<()> {
+    fn to_json<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(io::Error::other)
+    }
+    let (name, payload) = match remote_data {
+        RemoteData::Realtime(realtime) => ("realtime", to_json(realtime)?),
+        RemoteData::RealtimeStatus(status) => ("realtime_status", to_json(status)?),
+        RemoteData::Stats(stats) => ("stats", to_json(stats)?),
+        RemoteData::Rated(rated) => ("rated", to_json(rated)?),
+        RemoteData::VoltageSettings(settings) => ("voltage_settings", to_json(settings)?),
+        RemoteData::LoadOutput(on) => ("load_output", to_json(on)?),
+        // not retained, it tells about a command that just failed
+        RemoteData::ConnectionEvent(ConnectionEvent::Error { message, .. }) => {
+            let topic = config.device_topic(device, "error");
+            return client.publish(&topic, message.as_bytes(), false);
+        }
+        _ => return Ok(()),
+    };
+    client.publish(&config.device_topic(device, name), &payload, true)
+}
+
+/// `read_realtime`, `read_realtime_status`, `read_rated`, `read_stats`, `read_voltage_settings`,
+/// `read_load_control`, `load_on`, `load_off` or a JSON encoded `VoltageSettings` preset to apply
+pub fn parse_command(payload: &[u8]) -> Result<ServerMessage, String> {
+    let payload = std::str::from_utf8(payload).map_err(|_| "payload is not utf8".to_string())?;
+    match payload.trim() {
+        "read_realtime" => Ok(ServerMessage::ReadRealtime),
+        "read_realtime_status" => Ok(ServerMessage::ReadRealtimeStatus),
+        "read_rated" => Ok(ServerMessage::ReadRated),
+        "read_stats" => Ok(ServerMessage::ReadStats),
+        "read_voltage_settings" => Ok(ServerMessage::ReadVoltageSettings),
+        "read_load_control" => Ok(ServerMessage::ReadLoadControl),
+        "load_on" => Ok(ServerMessage::SetLoadOutput(true)),
+        "load_off" => Ok(ServerMessage::SetLoadOutput(false)),
+        json if json.starts_with('{') => {
+            let settings: VoltageSettings =
+                serde_json::from_str(json).map_err(|e| format!("invalid voltage settings: {e}"))?;
+            // never write settings to the controller that the settings tab would flag
+            settings.check_settings_lifepo4()?;
+            Ok(ServerMessage::SetVoltageSettings(settings))
+        }
+        other => Err(format!("unknown command: {other}")),
+    }
+}
+
+/// (topic, retained payload) announcing every value published for `device` to Home Assistant
+pub fn discovery_messages(config: &MqttConfig, device_id: &DeviceId) -> Vec<(String, String)> {
+    let object_id = format!("{}_{}", config.client_id, device_id.slug());
+    let device = json!({
+        "identifiers": [object_id],
+        "name": format!("EpMon {device_id}"),
+        "model": "Tracer AN",
+    });
+    let mut res = Vec::new();
+    let mut push = |component: &str, name: &str, state: &str, extra: serde_json::Value| {
+        let mut payload = json!({
+            "name": name.replace('_', " "),
+            "unique_id": format!("{object_id}_{name}"),
+            "state_topic": config.device_topic(device_id, state),
+            "device": device,
+        });
+        if let (Some(payload), Some(extra)) = (payload.as_object_mut(), extra.as_object()) {
+            payload.extend(extra.clone());
+        }
+        res.push((
+            format!(
+                "{}/{component}/{object_id}/{name}/config",
+                config.discovery_prefix
+            ),
+            payload.to_string(),
+        ));
+    };
+
+    for (name, unit, _) in Realtime::default().values() {
+        let (unit, device_class) = match unit {
+            "volts" => ("V", "voltage"),
+            "amperes" => ("A", "current"),
+            "watts" => ("W", "power"),
+            "celsius" => ("°C", "temperature"),
+            _ => ("%", "battery"),
+        };
+        push(
+            "sensor",
+            name,
+            "realtime",
+            json!({
+                "value_template": format!("{{{{ value_json.{name} }}}}"),
+                "unit_of_measurement": unit,
+                "device_class": device_class,
+                "state_class": "measurement",
+            }),
+        );
+    }
+    for (name, _) in Stats::default().values() {
+        let extra = if name.contains("_energy_") {
+            json!({
+                "unit_of_measurement": "kWh",
+                "device_class": "energy",
+                "state_class": "total_increasing",
+            })
+        } else if name.ends_with("current") {
+            json!({ "unit_of_measurement": "A", "device_class": "current" })
+        } else {
+            json!({ "unit_of_measurement": "V", "device_class": "voltage" })
+        };
+        let mut extra = extra;
+        extra["value_template"] = json!(format!("{{{{ value_json.{name} }}}}"));
+        push("sensor", name, "stats", extra);
+    }
+    for (name, _) in RealtimeStatus::default().flags() {
+        let device_class = if name.ends_with("running") {
+            "running"
+        } else {
+            "problem"
+        };
+        push(
+            "binary_sensor",
+            name,
+            "realtime_status",
+            json!({
+                "value_template": format!("{{{{ 'ON' if value_json.{name} else 'OFF' }}}}"),
+                "device_class": device_class,
+            }),
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_an::Realtime;
+    use std::{net::TcpListener, sync::mpsc::channel};
+
+    #[test]
+    fn remaining_length_encoding() {
+        let packet = encode_packet(PUBLISH, &[0; 321]);
+        assert_eq!(&packet[..3], &[PUBLISH, 0xC1, 0x02]);
+        let (header, body) = read_packet(&mut packet.as_slice()).unwrap();
+        assert_eq!((header, body.len()), (PUBLISH, 321));
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_command(b"read_stats\n"), Ok(ServerMessage::ReadStats));
+        assert_eq!(
+            parse_command(b"load_off"),
+            Ok(ServerMessage::SetLoadOutput(false))
+        );
+        assert!(parse_command(b"format_disk").is_err());
+        assert!(parse_command(b"{\"battery_type\": 3}").is_err());
+    }
+
+    /// a broker stand-in that accepts one client and checks the packets it sends
+    #[test]
+    fn publish_and_receive_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = MqttConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            client_id: "test".to_string(),
+            base_topic: "test".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            username: Some("epmon".to_string()),
+            password: Some("secret".to_string()),
+        };
+        let (reading_sender, reading_receiver) = channel();
+        let (server_message_sender, server_message_receiver) = channel();
+        let client_config = config.clone();
+        let client =
+            thread::spawn(move || run(client_config, reading_receiver, server_message_sender));
+
+        let (mut broker, _) = listener.accept().unwrap();
+        let (header, body) = read_packet(&mut broker).unwrap();
+        assert_eq!(header, CONNECT);
+        assert_eq!(body[7], 0xC2);
+        let (client_id, rest) = decode_string(&body[10..]).unwrap();
+        let (username, rest) = decode_string(rest).unwrap();
+        let (password, _) = decode_string(rest).unwrap();
+        assert_eq!(
+            (&*client_id, &*username, &*password),
+            ("test", "epmon", "secret")
+        );
+        broker.write_all(&encode_packet(CONNACK, &[0, 0])).unwrap();
+
+        for (packet_id, topic) in [(1, "test/command"), (2, "test/+/command")] {
+            let (header, body) = read_packet(&mut broker).unwrap();
+            assert_eq!(header, SUBSCRIBE);
+            assert_eq!(decode_string(&body[2..]).unwrap().0, topic);
+            broker
+                .write_all(&encode_packet(SUBACK, &[0, packet_id, 0]))
+                .unwrap();
+        }
+
+        let publish_command = |broker: &mut TcpStream, topic: &str, command: &[u8]| {
+            let mut body = Vec::new();
+            encode_string(&mut body, topic);
+            body.extend_from_slice(command);
+            broker.write_all(&encode_packet(PUBLISH, &body)).unwrap();
+        };
+        // writes for all devices are dropped, reads go through
+        publish_command(&mut broker, "test/command", b"load_off");
+        publish_command(&mut broker, "test/command", b"read_realtime");
+        assert_eq!(
+            server_message_receiver
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap(),
+            (None, ServerMessage::ReadRealtime)
+        );
+
+        let device = DeviceId("10.0.0.2".to_string());
+        reading_sender
+            .send((device.clone(), RemoteData::Realtime(Realtime::default())))
+            .unwrap();
+        let discovery = discovery_messages(&config, &device);
+        for (expected_topic, _) in &discovery {
+            let (header, body) = read_packet(&mut broker).unwrap();
+            assert_eq!(header, PUBLISH | 0x01);
+            assert_eq!(&decode_string(&body).unwrap().0, expected_topic);
+        }
+        assert!(discovery[0]
+            .0
+            .starts_with("homeassistant/sensor/test_10_0_0_2/"));
+        let (header, body) = read_packet(&mut broker).unwrap();
+        assert_eq!(header, PUBLISH | 0x01);
+        let (topic, payload) = decode_string(&body).unwrap();
+        assert_eq!(topic, "test/10_0_0_2/realtime");
+        assert!(payload.starts_with(b"{\"pv_voltage\":0.0"));
+
+        publish_command(&mut broker, "test/10_0_0_2/command", b"load_off");
+        assert_eq!(
+            server_message_receiver
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap(),
+            (Some(device), ServerMessage::SetLoadOutput(false))
+        );
+
+        drop(reading_sender);
+        client.join().unwrap();
+    }
+}
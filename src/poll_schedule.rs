@@ -0,0 +1,220 @@
+use crate::server_task::ServerMessage;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "PollIntervalValue", into = "PollIntervalValue")]
+pub enum PollInterval {
+    Never,
+    /// right after the connection is established
+    OncePerConnection,
+    Every(Duration),
+}
+
+impl PollInterval {
+    /// `never`, `once` or a number of seconds
+    pub fn parse(s: &str) -> Option<PollInterval> {
+        match s {
+            "never" => Some(PollInterval::Never),
+            "once" => Some(PollInterval::OncePerConnection),
+            secs => secs
+                .parse::<f32>()
+                .ok()
+                .filter(|secs| *secs > 0.0)
+                .map(|secs| PollInterval::Every(Duration::from_secs_f32(secs))),
+        }
+    }
+}
+
+/// how a `PollInterval` is written in the config file: `"never"`, `"once"` or seconds
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PollIntervalValue {
+    Seconds(f32),
+    Word(String),
+}
+
+impl TryFrom<PollIntervalValue> for PollInterval {
+    type Error = String;
+
+    fn try_from(value: PollIntervalValue) -> Result<Self, Self::Error> {
+        let parsed = match &value {
+            PollIntervalValue::Seconds(secs) => PollInterval::parse(&secs.to_string()),
+            PollIntervalValue::Word(word) => PollInterval::parse(word),
+        };
+        parsed.ok_or_else(|| "expected seconds, \"once\" or \"never\"".to_string())
+    }
+}
+
+impl From<PollInterval> for PollIntervalValue {
+    fn from(interval: PollInterval) -> Self {
+        match interval {
+            PollInterval::Never => PollIntervalValue::Word("never".to_string()),
+            PollInterval::OncePerConnection => PollIntervalValue::Word("once".to_string()),
+            PollInterval::Every(interval) => PollIntervalValue::Seconds(interval.as_secs_f32()),
+        }
+    }
+}
+
+/// How often the server reads each modbus block on its own
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollSchedule {
+    pub realtime: PollInterval,
+    pub realtime_status: PollInterval,
+    pub stats: PollInterval,
+    pub rated: PollInterval,
+    pub voltage_settings: PollInterval,
+    pub log_messages: PollInterval,
+}
+
+impl Default for PollSchedule {
+    fn default() -> Self {
+        PollSchedule {
+            realtime: PollInterval::Every(Duration::from_secs(5)),
+            realtime_status: PollInterval::Every(Duration::from_secs(5)),
+            stats: PollInterval::Every(Duration::from_secs(60)),
+            rated: PollInterval::OncePerConnection,
+            voltage_settings: PollInterval::OncePerConnection,
+            log_messages: PollInterval::Every(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl PollSchedule {
+    /// `--poll-realtime 5 --poll-stats 60 --poll-rated once --poll-realtime-status never ...`
+    pub fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        for (name, interval) in self.entries_mut() {
+            let flag = format!("--poll-{}", name.replace('_', "-"));
+            match crate::arg_value(args, &flag).map(PollInterval::parse) {
+                Some(Some(parsed)) => *interval = parsed,
+                Some(None) => {
+                    return Err(format!("invalid {flag}, expected seconds, once or never"))
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> [(ServerMessage, PollInterval); 6] {
+        [
+            (ServerMessage::ReadRealtime, self.realtime),
+            (ServerMessage::ReadRealtimeStatus, self.realtime_status),
+            (ServerMessage::ReadStats, self.stats),
+            (ServerMessage::ReadRated, self.rated),
+            (ServerMessage::ReadVoltageSettings, self.voltage_settings),
+            (ServerMessage::ReadLogMessages, self.log_messages),
+        ]
+    }
+
+    fn entries_mut(&mut self) -> [(&'static str, &mut PollInterval); 6] {
+        [
+            ("realtime", &mut self.realtime),
+            ("realtime_status", &mut self.realtime_status),
+            ("stats", &mut self.stats),
+            ("rated", &mut self.rated),
+            ("voltage_settings", &mut self.voltage_settings),
+            ("log_messages", &mut self.log_messages),
+        ]
+    }
+}
+
+/// Keeps track of when each block was last read
+#[derive(Debug, Default)]
+pub struct Poller {
+    pub schedule: PollSchedule,
+    last_polled: [Option<Instant>; 6],
+}
+
+impl Poller {
+    pub fn new(schedule: PollSchedule) -> Self {
+        Poller {
+            schedule,
+            last_polled: Default::default(),
+        }
+    }
+
+    /// has to be called for every new connection
+    pub fn reset(&mut self) {
+        self.last_polled = Default::default();
+    }
+
+    /// the reads that are due at `now`, they are considered done once returned
+    pub fn due(&mut self, now: Instant) -> Vec<ServerMessage> {
+        let mut res = Vec::new();
+        for ((message, interval), last_polled) in self
+            .schedule
+            .entries()
+            .into_iter()
+            .zip(&mut self.last_polled)
+        {
+            let due = match (interval, *last_polled) {
+                (PollInterval::Never, _) => false,
+                (_, None) => true,
+                (PollInterval::OncePerConnection, Some(_)) => false,
+                (PollInterval::Every(interval), Some(last)) => now.duration_since(last) >= interval,
+            };
+            if due {
+                *last_polled = Some(now);
+                res.push(message);
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn due_reads() {
+        let mut poller = Poller::new(PollSchedule {
+            realtime_status: PollInterval::Never,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert_eq!(
+            poller.due(start),
+            vec![
+                ServerMessage::ReadRealtime,
+                ServerMessage::ReadStats,
+                ServerMessage::ReadRated,
+                ServerMessage::ReadVoltageSettings,
+                ServerMessage::ReadLogMessages
+            ]
+        );
+        assert!(poller.due(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(
+            poller.due(start + Duration::from_secs(5)),
+            vec![ServerMessage::ReadRealtime]
+        );
+        assert_eq!(
+            poller.due(start + Duration::from_secs(60)),
+            vec![
+                ServerMessage::ReadRealtime,
+                ServerMessage::ReadStats,
+                ServerMessage::ReadLogMessages
+            ]
+        );
+        poller.reset();
+        assert_eq!(poller.due(start + Duration::from_secs(61)).len(), 5);
+    }
+
+    #[test]
+    fn schedule_from_args() {
+        let args: Vec<String> = ["--poll-realtime", "2.5", "--poll-rated", "never"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut schedule = PollSchedule::default();
+        schedule.apply_args(&args).unwrap();
+        assert_eq!(
+            schedule.realtime,
+            PollInterval::Every(Duration::from_millis(2500))
+        );
+        assert_eq!(schedule.rated, PollInterval::Never);
+        assert_eq!(schedule.stats, PollSchedule::default().stats);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{
+    calibration::Calibration,
+    config::AdcConfig,
+    device::DeviceId,
+    device_log::LogFile,
+    live_data::LiveData,
+    remote_data::RemoteData,
+    server_error::ConnectionEvent,
+    storage::{self, Store},
+};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc, Mutex},
+};
+
+/// Live data of every device that connected so far
+pub type SharedLiveData = Arc<Mutex<BTreeMap<DeviceId, LiveData>>>;
+
+/// Everything that happens to remote data besides plotting it:
+/// writing samples to disk, updating the shared live data and forwarding modbus readings
+pub struct Recorder {
+    /// every device stores its samples and calibration in a sub directory,
+    /// nothing is stored if this is `None`
+    pub data_dir: Option<PathBuf>,
+    stores: BTreeMap<DeviceId, Store>,
+    /// log messages of the device, rotated by size
+    log_files: BTreeMap<DeviceId, LogFile>,
+    pub live_data: SharedLiveData,
+    /// receive decoded modbus readings and connection errors (not the sample buffers),
+    /// e.g. the mqtt publisher
+    pub listeners: Vec<Sender<(DeviceId, RemoteData)>>,
+    adc: AdcConfig,
+}
+
+impl Recorder {
+    pub fn new(data_dir: Option<PathBuf>, adc: AdcConfig, live_data: SharedLiveData) -> Self {
+        Recorder {
+            data_dir,
+            stores: BTreeMap::new(),
+            log_files: BTreeMap::new(),
+            live_data,
+            listeners: Vec::new(),
+            adc,
+        }
+    }
+
+    pub fn device_dir(&self, device: &DeviceId) -> Option<PathBuf> {
+        Some(storage::device_dir(self.data_dir.as_ref()?, device))
+    }
+
+    pub fn store(&self, device: &DeviceId) -> Option<&Store> {
+        self.stores.get(device)
+    }
+
+    /// Opens the store of a device that connected for the first time and loads its
+    /// history and calibration into a new `LiveData`, known devices are left alone
+    pub fn add_device(&mut self, device: &DeviceId) {
+        match self.live_data.lock() {
+            Ok(live_data) if !live_data.contains_key(device) => {}
+            _ => return,
+        }
+        // the store is opened without holding the lock
+        let dir = self.device_dir(device);
+        let calibration = match &dir {
+            Some(dir) => Calibration::load(dir, self.adc).unwrap_or_else(|e| {
+                println!(
+                    "{device}: could not load calibration: {e}, using uncalibrated adc conversion"
+                );
+                Calibration::new(self.adc)
+            }),
+            None => Calibration::new(self.adc),
+        };
+        let mut live_data = LiveData {
+            calibration,
+            ..Default::default()
+        };
+        if let Some(dir) = dir {
+            match LogFile::open(&dir) {
+                Ok(log_file) => {
+                    self.log_files.insert(device.clone(), log_file);
+                }
+                Err(e) => println!("{device}: could not open the device log: {e}"),
+            }
+            match Store::open(&dir) {
+                Ok(store) => {
+                    live_data.load_history(&store);
+                    self.stores.insert(device.clone(), store);
+                }
+                Err(e) => println!(
+                    "{device}: could not open data dir {}: {e}, samples will not be stored",
+                    dir.display()
+                ),
+            }
+        }
+        if let Ok(mut all_live_data) = self.live_data.lock() {
+            all_live_data.entry(device.clone()).or_insert(live_data);
+        }
+    }
+
+    /// Samples of a reply to `RetransmitBuffers` that were already stored are removed from
+    /// `remote_data`, every other reply is passed on unchanged
+    pub fn record(&mut self, device: &DeviceId, remote_data: &mut RemoteData) {
+        self.add_device(device);
+        let Ok(mut all_live_data) = self.live_data.lock() else {
+            println!("recorder: could not lock live data");
+            return;
+        };
+        let live_data = all_live_data.entry(device.clone()).or_default();
+        if let Some(store) = self.stores.get_mut(device) {
+            if let RemoteData::ConnectionEvent(ConnectionEvent::Retransmitting) = remote_data {
+                store.expect_retransmit();
+            }
+            match store.record(
+                remote_data,
+                live_data.pv.tick_len,
+                live_data.pv_power.tick_len,
+                &live_data.calibration,
+            ) {
+                Ok(stored_before) => {
+                    if let Some(readings) = remote_data.readings_mut() {
+                        readings.drain(..stored_before);
+                    }
+                }
+                Err(e) => println!("{device}: could not store samples: {e}"),
+            }
+        }
+        if let (RemoteData::LogMessage(message), Some(log_file)) =
+            (&remote_data, self.log_files.get_mut(device))
+        {
+            if let Err(e) = log_file.append(message) {
+                println!("{device}: could not write the device log: {e}");
+            }
+        }
+        live_data.update(remote_data.clone());
+        if matches!(
+            remote_data,
+            RemoteData::Realtime(_)
+                | RemoteData::RealtimeStatus(_)
+                | RemoteData::Stats(_)
+                | RemoteData::Rated(_)
+                | RemoteData::VoltageSettings(_)
+                | RemoteData::LoadOutput(_)
+                | RemoteData::ConnectionEvent(ConnectionEvent::Error { .. })
+        ) {
+            // listeners that hung up are dropped
+            self.listeners
+                .retain(|listener| listener.send((device.clone(), remote_data.clone())).is_ok());
+        }
+    }
+
+    /// Makes `calibration` effective for new samples of `device` and writes it to its data dir
+    pub fn save_calibration(
+        &mut self,
+        device: &DeviceId,
+        calibration: &Calibration,
+    ) -> Result<PathBuf, String> {
+        if let Ok(mut live_data) = self.live_data.lock() {
+            live_data.entry(device.clone()).or_default().calibration = calibration.clone();
+        }
+        let dir = self
+            .device_dir(device)
+            .ok_or_else(|| "applied, but not saved: no data dir".to_string())?;
+        calibration
+            .save(&dir)
+            .map(|()| Calibration::path(&dir))
+            .map_err(|e| format!("could not save calibration: {e}"))
+    }
+}
@@ -0,0 +1,439 @@
+use crate::{command::Command, remote_data::RemoteData};
+use std::fmt::Display;
+
+/// registers read by a single modbus request
+pub const MAX_REGISTERS_PER_READ: u8 = 125;
+
+/// Input registers are read only, holdings can also be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterKind {
+    Input,
+    Holding,
+}
+
+impl RegisterKind {
+    pub const ALL: [RegisterKind; 2] = [RegisterKind::Input, RegisterKind::Holding];
+
+    pub fn read_command(&self, register_address: u16, size: u8) -> Command {
+        match self {
+            RegisterKind::Input => Command::ModbusGetInputRegisters {
+                register_address,
+                size,
+            },
+            RegisterKind::Holding => Command::ModbusGetHoldings {
+                register_address,
+                size,
+            },
+        }
+    }
+}
+
+impl Display for RegisterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterKind::Input => write!(f, "input registers"),
+            RegisterKind::Holding => write!(f, "holdings"),
+        }
+    }
+}
+
+/// One value of the register map, 32 bit values span two registers with the low word first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterDef {
+    pub address: u16,
+    pub name: &'static str,
+    pub unit: &'static str,
+    /// the raw value times `scale` is in `unit`
+    pub scale: f32,
+    pub wide: bool,
+    pub signed: bool,
+    pub kind: RegisterKind,
+}
+
+const fn input(address: u16, name: &'static str, unit: &'static str, scale: f32) -> RegisterDef {
+    RegisterDef {
+        address,
+        name,
+        unit,
+        scale,
+        wide: false,
+        signed: false,
+        kind: RegisterKind::Input,
+    }
+}
+
+const fn holding(address: u16, name: &'static str, unit: &'static str, scale: f32) -> RegisterDef {
+    RegisterDef {
+        kind: RegisterKind::Holding,
+        ..input(address, name, unit, scale)
+    }
+}
+
+impl RegisterDef {
+    const fn wide(self) -> Self {
+        RegisterDef { wide: true, ..self }
+    }
+
+    const fn signed(self) -> Self {
+        RegisterDef {
+            signed: true,
+            ..self
+        }
+    }
+
+    /// registers the value spans
+    pub fn words(&self) -> u16 {
+        if self.wide {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+/// The registers of the Tracer AN series, sorted by kind and address
+pub const TRACER_AN: &[RegisterDef] = &[
+    input(0x3000, "PV array rated voltage", "V", 0.01),
+    input(0x3001, "PV array rated current", "A", 0.01),
+    input(0x3002, "PV array rated power", "W", 0.01).wide(),
+    input(0x3004, "battery rated voltage", "V", 0.01),
+    input(0x3005, "battery rated current", "A", 0.01),
+    input(0x3006, "battery rated power", "W", 0.01).wide(),
+    input(0x3008, "charging mode", "", 1.0),
+    input(0x300E, "rated current of load", "A", 0.01),
+    input(0x3100, "PV voltage", "V", 0.01),
+    input(0x3101, "PV current", "A", 0.01),
+    input(0x3102, "PV power", "W", 0.01).wide(),
+    input(0x3106, "battery charging power", "W", 0.01).wide(),
+    input(0x310C, "load voltage", "V", 0.01),
+    input(0x310D, "load current", "A", 0.01),
+    input(0x310E, "load power", "W", 0.01).wide(),
+    input(0x3110, "battery temperature", "°C", 0.01).signed(),
+    input(0x3111, "equipment temperature", "°C", 0.01).signed(),
+    input(0x311A, "battery state of charge", "%", 1.0),
+    input(0x311B, "remote battery temperature", "°C", 0.01).signed(),
+    input(0x311D, "battery real rated voltage", "V", 0.01),
+    input(0x3200, "battery status", "", 1.0),
+    input(0x3201, "charging equipment status", "", 1.0),
+    input(0x3202, "discharging equipment status", "", 1.0),
+    input(0x3300, "max PV voltage today", "V", 0.01),
+    input(0x3301, "min PV voltage today", "V", 0.01),
+    input(0x3302, "max battery voltage today", "V", 0.01),
+    input(0x3303, "min battery voltage today", "V", 0.01),
+    input(0x3304, "consumed energy today", "kWh", 0.01).wide(),
+    input(0x3306, "consumed energy this month", "kWh", 0.01).wide(),
+    input(0x3308, "consumed energy this year", "kWh", 0.01).wide(),
+    input(0x330A, "total consumed energy", "kWh", 0.01).wide(),
+    input(0x330C, "generated energy today", "kWh", 0.01).wide(),
+    input(0x330E, "generated energy this month", "kWh", 0.01).wide(),
+    input(0x3310, "generated energy this year", "kWh", 0.01).wide(),
+    input(0x3312, "total generated energy", "kWh", 0.01).wide(),
+    input(0x331A, "battery voltage", "V", 0.01),
+    input(0x331B, "battery current", "A", 0.01).wide().signed(),
+    holding(0x9000, "battery type", "", 1.0),
+    holding(0x9001, "battery capacity", "Ah", 1.0),
+    holding(
+        0x9002,
+        "temperature compensation coefficient",
+        "mV/°C/2V",
+        0.01,
+    ),
+    holding(0x9003, "high voltage disconnect", "V", 0.01),
+    holding(0x9004, "charging limit voltage", "V", 0.01),
+    holding(0x9005, "over voltage reconnect", "V", 0.01),
+    holding(0x9006, "equalization voltage", "V", 0.01),
+    holding(0x9007, "boost voltage", "V", 0.01),
+    holding(0x9008, "float voltage", "V", 0.01),
+    holding(0x9009, "boost reconnect voltage", "V", 0.01),
+    holding(0x900A, "low voltage reconnect", "V", 0.01),
+    holding(0x900B, "under voltage recover", "V", 0.01),
+    holding(0x900C, "under voltage warning", "V", 0.01),
+    holding(0x900D, "low voltage disconnect", "V", 0.01),
+    holding(0x900E, "discharging limit voltage", "V", 0.01),
+];
+
+impl Display for RegisterDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x} {}", self.address, self.name)
+    }
+}
+
+pub fn lookup(kind: RegisterKind, address: u16) -> Option<&'static RegisterDef> {
+    TRACER_AN
+        .iter()
+        .find(|def| def.kind == kind && def.address == address)
+}
+
+/// How the browser shows register values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterView {
+    Hex,
+    Unsigned,
+    Signed,
+    /// signed as defined and multiplied by the scale, unknown registers are shown unsigned
+    Scaled,
+}
+
+impl RegisterView {
+    pub const ALL: [RegisterView; 4] = [
+        RegisterView::Hex,
+        RegisterView::Unsigned,
+        RegisterView::Signed,
+        RegisterView::Scaled,
+    ];
+}
+
+impl Display for RegisterView {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterView::Hex => write!(f, "raw hex"),
+            RegisterView::Unsigned => write!(f, "unsigned"),
+            RegisterView::Signed => write!(f, "signed"),
+            RegisterView::Scaled => write!(f, "scaled"),
+        }
+    }
+}
+
+/// A register value read from the device, `def` is `None` for registers missing in the map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterValue {
+    pub address: u16,
+    pub raw: u32,
+    pub wide: bool,
+    pub def: Option<&'static RegisterDef>,
+}
+
+impl RegisterValue {
+    pub fn name(&self) -> &'static str {
+        self.def.map_or("unknown", |def| def.name)
+    }
+
+    pub fn unit(&self) -> &'static str {
+        self.def.map_or("", |def| def.unit)
+    }
+
+    fn signed(&self) -> i32 {
+        if self.wide {
+            self.raw as i32
+        } else {
+            self.raw as u16 as i16 as i32
+        }
+    }
+
+    /// the value in its unit
+    pub fn scaled(&self) -> Option<f32> {
+        let def = self.def?;
+        let value = if def.signed {
+            self.signed() as f32
+        } else {
+            self.raw as f32
+        };
+        Some(value * def.scale)
+    }
+
+    pub fn format(&self, view: RegisterView) -> String {
+        match view {
+            RegisterView::Hex if self.wide => format!("{:#010x}", self.raw),
+            RegisterView::Hex => format!("{:#06x}", self.raw),
+            RegisterView::Unsigned => self.raw.to_string(),
+            RegisterView::Signed => self.signed().to_string(),
+            RegisterView::Scaled => match self.scaled() {
+                Some(value) => format!("{value:.2}"),
+                None => self.raw.to_string(),
+            },
+        }
+    }
+}
+
+/// Splits the answer to a read starting at `address` into values by the register map.
+/// A 32 bit value cut off by the end of the range is shown as an unknown register.
+pub fn decode(kind: RegisterKind, address: u16, bytes: &[u8]) -> Vec<RegisterValue> {
+    let words: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+        .collect();
+    let mut res = Vec::new();
+    let mut ix = 0;
+    while ix < words.len() {
+        let address = address.wrapping_add(ix as u16);
+        let def = lookup(kind, address);
+        let value = match def {
+            Some(def) if def.wide && ix + 1 < words.len() => RegisterValue {
+                address,
+                raw: words[ix] as u32 | (words[ix + 1] as u32) << 16,
+                wide: true,
+                def: Some(def),
+            },
+            _ => RegisterValue {
+                address,
+                raw: words[ix] as u32,
+                wide: false,
+                def: def.filter(|def| !def.wide),
+            },
+        };
+        ix += if value.wide { 2 } else { 1 };
+        res.push(value);
+    }
+    res
+}
+
+/// State of the register browser panel, the last read range is decoded when its answer arrives
+#[derive(Debug, Clone)]
+pub struct RegisterBrowser {
+    pub kind: RegisterKind,
+    pub address_input: String,
+    pub count_input: String,
+    pub view: RegisterView,
+    pub values: Vec<RegisterValue>,
+    pub status: String,
+    /// kind and start address of the read waiting for its answer
+    pending: Option<(RegisterKind, u16)>,
+}
+
+impl Default for RegisterBrowser {
+    fn default() -> Self {
+        RegisterBrowser {
+            kind: RegisterKind::Input,
+            address_input: "3100".to_string(),
+            count_input: "16".to_string(),
+            view: RegisterView::Scaled,
+            values: Vec::new(),
+            status: String::new(),
+            pending: None,
+        }
+    }
+}
+
+impl RegisterBrowser {
+    /// Jumps to a register of the map
+    pub fn select(&mut self, def: &RegisterDef) {
+        self.kind = def.kind;
+        self.address_input = format!("{:04x}", def.address);
+    }
+
+    /// The command reading the entered range, `Err` explains a bad input
+    pub fn read(&mut self) -> Result<Command, String> {
+        let address = u16::from_str_radix(self.address_input.trim(), 16)
+            .map_err(|_| format!("{:?} is not a hex address", self.address_input))?;
+        let count = match self.count_input.trim().parse::<u8>() {
+            Ok(count) if (1..=MAX_REGISTERS_PER_READ).contains(&count) => count,
+            _ => {
+                return Err(format!(
+                    "the count must be between 1 and {MAX_REGISTERS_PER_READ}"
+                ))
+            }
+        };
+        self.pending = Some((self.kind, address));
+        self.status = format!("reading {count} {} at {address:#06x}", self.kind);
+        Ok(self.kind.read_command(address, count))
+    }
+
+    pub fn update(&mut self, remote_data: &RemoteData) {
+        let (kind, bytes) = match remote_data {
+            RemoteData::InputRegisters(bytes) => (RegisterKind::Input, bytes),
+            RemoteData::Holdings(bytes) => (RegisterKind::Holding, bytes),
+            _ => return,
+        };
+        let Some((pending_kind, address)) = self.pending else {
+            return;
+        };
+        if pending_kind != kind {
+            return;
+        }
+        self.pending = None;
+        self.values = decode(kind, address, bytes);
+        self.status = format!("read {} registers", bytes.len() / 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        simulator::{SimulatorConfig, VirtualDevice},
+        tracer_an::Realtime,
+    };
+
+    #[test]
+    fn register_map_is_sorted_without_overlaps() {
+        for pair in TRACER_AN.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if a.kind == b.kind {
+                assert!(a.address + a.words() <= b.address, "{a:?} overlaps {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn decode_by_definition() {
+        // battery voltage, battery current of -1.5 A and a cut off value
+        let bytes = [0x0a, 0x28, 0xff, 0x6a, 0xff, 0xff, 0x12, 0x34];
+        let values = decode(RegisterKind::Input, 0x331A, &bytes);
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0].name(), "battery voltage");
+        assert_eq!(values[0].format(RegisterView::Scaled), "26.00");
+        assert_eq!(values[1].name(), "battery current");
+        assert_eq!(values[1].format(RegisterView::Hex), "0xffffff6a");
+        assert_eq!(values[1].format(RegisterView::Signed), "-150");
+        assert_eq!(values[1].format(RegisterView::Scaled), "-1.50");
+        assert_eq!(values[2].address, 0x331D);
+        assert_eq!(values[2].name(), "unknown");
+        assert_eq!(values[2].format(RegisterView::Unsigned), "4660");
+
+        // the low word of a wide value at the end of the range
+        let values = decode(RegisterKind::Input, 0x3102, &[0x12, 0x34]);
+        assert_eq!(values[0].def, None);
+        assert_eq!(values[0].format(RegisterView::Hex), "0x1234");
+    }
+
+    #[test]
+    fn browse_the_simulated_device() {
+        let mut device = VirtualDevice::new(SimulatorConfig {
+            start_hour: 12.0,
+            speed: 0.0,
+            ..Default::default()
+        });
+        let mut browser = RegisterBrowser {
+            count_input: "30".to_string(),
+            ..Default::default()
+        };
+        let command = browser.read().unwrap();
+        let answer = device.answer(command).unwrap();
+        browser.update(&RemoteData::Holdings(answer.clone()));
+        assert!(browser.values.is_empty());
+        browser.update(&RemoteData::InputRegisters(answer));
+
+        let realtime = Realtime::generate_commands()
+            .into_iter()
+            .flat_map(|command| device.answer(command).unwrap())
+            .collect::<Vec<u8>>();
+        let realtime = Realtime::from_bytes(&realtime).unwrap();
+        let browsed = |name: &str| {
+            browser
+                .values
+                .iter()
+                .find(|value| value.name() == name)
+                .and_then(RegisterValue::scaled)
+                .unwrap()
+        };
+        let decoded = |name: &str| {
+            let values = realtime.values();
+            values.iter().find(|value| value.0 == name).unwrap().2
+        };
+        for (browsed_name, decoded_name) in [
+            ("PV voltage", "pv_voltage"),
+            ("PV power", "pv_power"),
+            ("load power", "load_power"),
+            ("battery state of charge", "remaining_battery_capacity"),
+        ] {
+            let (browsed, decoded) = (browsed(browsed_name), decoded(decoded_name));
+            assert!(
+                (browsed - decoded).abs() < 0.01,
+                "{browsed_name} {browsed} {decoded}"
+            );
+        }
+
+        browser.count_input = "0".to_string();
+        assert!(browser.read().is_err());
+    }
+}
@@ -1,11 +1,55 @@
 use crate::{
-    command::{BufferType, Command},
+    command::{self, BufferType, Command, FRAME_HEADER_SIZE, FRAME_MAGIC, PROTOCOL_VERSION},
+    device::DeviceInfo,
+    device_log::LogMessage,
+    device_stream::DeviceStream,
+    load_control::LoadControl,
+    server_error::{ConnectionEvent, ServerError},
     tracer_an::{Rated, Realtime, RealtimeStatus, Stats, VoltageSettings},
 };
-use std::{
-    io::{ErrorKind, Read, Write},
-    net::TcpStream,
-};
+
+/// largest buffer answer accepted, 2M samples, more means the stream lost its framing
+pub const MAX_BUFFER_BYTES: usize = 4 << 20;
+/// largest log message accepted
+pub const MAX_LOG_MESSAGE_BYTES: usize = 64 << 10;
+
+/// Header of a v2 answer, the payload holds what the legacy protocol sends bare
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    /// sequence number of the command this answers
+    pub sequence: u8,
+    pub len: u16,
+    crc: u16,
+}
+
+impl FrameHeader {
+    pub fn parse(bytes: &[u8; FRAME_HEADER_SIZE]) -> Result<FrameHeader, String> {
+        if bytes[..2] != FRAME_MAGIC {
+            return Err(format!("bad frame magic {:?}", &bytes[..2]));
+        }
+        if bytes[2] != PROTOCOL_VERSION {
+            return Err(format!("unsupported protocol version {}", bytes[2]));
+        }
+        Ok(FrameHeader {
+            sequence: bytes[3],
+            len: u16::from_be_bytes([bytes[4], bytes[5]]),
+            crc: u16::from_be_bytes([bytes[6], bytes[7]]),
+        })
+    }
+
+    pub fn check(&self, payload: &[u8]) -> Result<(), String> {
+        let [len1, len2] = self.len.to_be_bytes();
+        let header = [PROTOCOL_VERSION, self.sequence, len1, len2];
+        let crc = command::crc16(&header, payload);
+        if crc != self.crc {
+            return Err(format!(
+                "crc mismatch, got {:#06x} expected {crc:#06x}",
+                self.crc
+            ));
+        }
+        Ok(())
+    }
+}
 
 #[derive(PartialEq, Debug, Clone, Default)]
 pub enum RemoteData {
@@ -15,6 +59,7 @@ pub enum RemoteData {
     BatteryPackVoltage(Vec<u16>),
     PVVoltage(Vec<u16>),
     PVPower(Vec<u16>),
+    InverterPower(Vec<u16>),
     VoltageBufferSize(usize),
     VoltageIntervalms(u16),
     PowerIntervalms(u16),
@@ -25,173 +70,295 @@ pub enum RemoteData {
     VoltageSettings(VoltageSettings),
     Rated(Rated),
     Stats(Stats),
+    DeviceInfo(DeviceInfo),
+    LogMessage(LogMessage),
+    /// holdings written at `register_address` and what reading them back returned
+    HoldingsWritten {
+        register_address: u16,
+        written: Vec<u16>,
+        read_back: Vec<u16>,
+    },
+    /// the holdings deciding when the load output is on
+    LoadControl(LoadControl),
+    /// whether the load output is switched on
+    LoadOutput(bool),
+    /// not read from the device, tells the gui what happened to the connection
+    ConnectionEvent(ConnectionEvent),
 }
 
 impl RemoteData {
-    pub fn read_battery_voltage(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        tcp_stream.write_all(&Command::GetBuffer(BufferType::Battery1Voltage).to_bytes())?;
-        let voltages = Self::read_buffer(tcp_stream)?;
+    pub async fn read_battery_voltage(
+        stream: &mut DeviceStream,
+    ) -> Result<RemoteData, ServerError> {
+        stream
+            .send_command(Command::GetBuffer(BufferType::Battery1Voltage))
+            .await?;
+        let voltages = Self::read_buffer(stream).await?;
         Ok(RemoteData::BatteryVoltage(voltages))
     }
 
-    pub fn read_battery_pack_voltage(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        tcp_stream.write_all(&Command::GetBuffer(BufferType::BatteryPackVoltage).to_bytes())?;
-        let voltages = Self::read_buffer(tcp_stream)?;
+    pub async fn read_battery_pack_voltage(
+        stream: &mut DeviceStream,
+    ) -> Result<RemoteData, ServerError> {
+        stream
+            .send_command(Command::GetBuffer(BufferType::BatteryPackVoltage))
+            .await?;
+        let voltages = Self::read_buffer(stream).await?;
         Ok(RemoteData::BatteryPackVoltage(voltages))
     }
 
-    pub fn read_pv_voltage(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        tcp_stream.write_all(&Command::GetBuffer(BufferType::PVVoltage).to_bytes())?;
-        let voltages = Self::read_buffer(tcp_stream)?;
+    pub async fn read_pv_voltage(stream: &mut DeviceStream) -> Result<RemoteData, ServerError> {
+        stream
+            .send_command(Command::GetBuffer(BufferType::PVVoltage))
+            .await?;
+        let voltages = Self::read_buffer(stream).await?;
         Ok(RemoteData::PVVoltage(voltages))
     }
 
-    pub fn read_pv_power(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        tcp_stream.write_all(&Command::GetBuffer(BufferType::PVPower).to_bytes())?;
-        let power_data = Self::read_buffer(tcp_stream)?;
+    pub async fn read_pv_power(stream: &mut DeviceStream) -> Result<RemoteData, ServerError> {
+        stream
+            .send_command(Command::GetBuffer(BufferType::PVPower))
+            .await?;
+        let power_data = Self::read_buffer(stream).await?;
         Ok(RemoteData::PVPower(power_data))
     }
 
-    pub fn read_buffer(tcp_stream: &mut TcpStream) -> std::io::Result<Vec<u16>> {
+    pub async fn read_inverter_power(stream: &mut DeviceStream) -> Result<RemoteData, ServerError> {
+        stream
+            .send_command(Command::GetBuffer(BufferType::InverterPower))
+            .await?;
+        let power_data = Self::read_buffer(stream).await?;
+        Ok(RemoteData::InverterPower(power_data))
+    }
+
+    pub async fn read_buffer(stream: &mut DeviceStream) -> Result<Vec<u16>, ServerError> {
         let mut size_buf = [0; 4];
-        tcp_stream.read_exact(&mut size_buf)?;
+        stream.read_exact(&mut size_buf).await?;
         let buffer_size = u32::from_be_bytes(size_buf) as usize;
         if buffer_size == 0 {
             return Ok(Vec::new());
         }
+        if !buffer_size.is_multiple_of(2) {
+            return Err(stream.protocol_error(format!("odd buffer size {buffer_size}")));
+        }
+        if buffer_size > MAX_BUFFER_BYTES {
+            return Err(stream.protocol_error(format!("buffer size {buffer_size} too large")));
+        }
         let mut buf = vec![0; buffer_size];
-        tcp_stream.read_exact(&mut buf)?;
-        Ok(bytemuck::cast_slice(&buf).to_vec())
+        stream.read_exact(&mut buf).await?;
+        Ok(samples_from_bytes(&buf))
     }
 
-    pub fn read_interval_ms_voltage(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        let write_buf = Command::GetVoltageIntervalms.to_bytes();
-        println!("tcp_stream.write({write_buf:?})");
-        tcp_stream.write_all(&write_buf)?;
+    pub async fn read_interval_ms_voltage(
+        stream: &mut DeviceStream,
+    ) -> Result<RemoteData, ServerError> {
+        let command = Command::GetVoltageIntervalms;
+        println!("stream.write({command:?})");
+        stream.send_command(command).await?;
         let mut read_buf = [0; 2];
-        println!("tcp_stream read GetIntervalms");
-        tcp_stream.read_exact(&mut read_buf)?;
+        println!("stream read GetIntervalms");
+        stream.read_exact(&mut read_buf).await?;
         println!("GetIntervalms :: {read_buf:?}");
         Ok(RemoteData::VoltageIntervalms(u16::from_be_bytes(read_buf)))
     }
 
-    pub fn read_interval_ms_power(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        let write_buf = Command::GetPowerIntervalms.to_bytes();
-        println!("tcp_stream.write({write_buf:?})");
-        tcp_stream.write_all(&write_buf)?;
+    pub async fn read_interval_ms_power(
+        stream: &mut DeviceStream,
+    ) -> Result<RemoteData, ServerError> {
+        let command = Command::GetPowerIntervalms;
+        println!("stream.write({command:?})");
+        stream.send_command(command).await?;
         let mut read_buf = [0; 2];
-        println!("tcp_stream read GetIntervalms");
-        tcp_stream.read_exact(&mut read_buf)?;
+        println!("stream read GetIntervalms");
+        stream.read_exact(&mut read_buf).await?;
         println!("GetIntervalms :: {read_buf:?}");
         Ok(RemoteData::PowerIntervalms(u16::from_be_bytes(read_buf)))
     }
 
-    pub fn read_voltage_buffer_size(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        let write_buf = Command::GetVoltageBufferSize.to_bytes();
-        tcp_stream.write_all(&write_buf)?;
+    pub async fn read_device_info(stream: &mut DeviceStream) -> Result<DeviceInfo, ServerError> {
+        stream.send_command(Command::GetDeviceInfo).await?;
+        let mut read_buf = [0; DeviceInfo::SIZE];
+        stream.read_exact(&mut read_buf).await?;
+        Ok(DeviceInfo::from_bytes(&read_buf))
+    }
+
+    /// The device hands out its oldest unread message, `None` when there is none.
+    /// The answer is a length prefixed utf-8 text like a buffer.
+    pub async fn read_log_message(
+        stream: &mut DeviceStream,
+    ) -> Result<Option<LogMessage>, ServerError> {
+        stream.send_command(Command::GetLastLogMessage).await?;
+        let mut size_buf = [0; 4];
+        stream.read_exact(&mut size_buf).await?;
+        let size = u32::from_be_bytes(size_buf) as usize;
+        if size == 0 {
+            return Ok(None);
+        }
+        if size > MAX_LOG_MESSAGE_BYTES {
+            return Err(stream.protocol_error(format!("log message size {size} too large")));
+        }
+        let mut buf = vec![0; size];
+        stream.read_exact(&mut buf).await?;
+        let text = String::from_utf8_lossy(&buf).trim_end().to_string();
+        Ok(Some(LogMessage::new(text)))
+    }
+
+    pub async fn read_voltage_buffer_size(
+        stream: &mut DeviceStream,
+    ) -> Result<RemoteData, ServerError> {
+        stream.send_command(Command::GetVoltageBufferSize).await?;
         let mut read_buf = [0; 4];
-        tcp_stream.read_exact(&mut read_buf)?;
+        stream.read_exact(&mut read_buf).await?;
         Ok(RemoteData::VoltageBufferSize(
             u32::from_be_bytes(read_buf) as usize
         ))
     }
 
-    pub fn get_holdings(
-        tcp_stream: &mut TcpStream,
+    pub async fn get_holdings(
+        stream: &mut DeviceStream,
         command: Command,
-    ) -> std::io::Result<RemoteData> {
-        let write_buf = command.to_bytes();
+    ) -> Result<RemoteData, ServerError> {
         if let Command::ModbusGetHoldings {
             register_address: _,
             size,
         } = command
         {
-            tcp_stream.write_all(&write_buf)?;
+            stream.send_command(command).await?;
             let mut read_buf = vec![0; (size * 2) as usize];
-            tcp_stream.read_exact(&mut read_buf)?;
+            stream.read_exact(&mut read_buf).await?;
             Ok(RemoteData::Holdings(read_buf))
         } else {
-            Err(ErrorKind::InvalidInput.into())
+            Err(stream.protocol_error(format!("{command:?} does not read holdings")))
         }
     }
 
-    pub fn get_input_registers(
-        tcp_stream: &mut TcpStream,
+    /// Writes the holdings of `command` and reads them back, the device does not answer writes
+    pub async fn write_holdings(
+        stream: &mut DeviceStream,
+        command: Command,
+    ) -> Result<RemoteData, ServerError> {
+        let Some((register_address, written)) = command.written_holdings() else {
+            return Err(stream.protocol_error(format!("{command:?} does not write holdings")));
+        };
+        let written = written.to_vec();
+        stream.send_command(command).await?;
+        let read_back = Command::ModbusGetHoldings {
+            register_address,
+            size: written.len() as u8,
+        };
+        let RemoteData::Holdings(bytes) = RemoteData::get_holdings(stream, read_back).await? else {
+            unreachable!("get_holdings answers with holdings")
+        };
+        let read_back = bytes
+            .chunks_exact(2)
+            .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+            .collect();
+        Ok(RemoteData::HoldingsWritten {
+            register_address,
+            written,
+            read_back,
+        })
+    }
+
+    pub async fn get_input_registers(
+        stream: &mut DeviceStream,
         command: Command,
-    ) -> std::io::Result<RemoteData> {
-        let write_buf = command.to_bytes();
+    ) -> Result<RemoteData, ServerError> {
         println!("Sending Command: {:?}", command);
         if let Command::ModbusGetInputRegisters {
             register_address: _,
             size,
         } = command
         {
-            tcp_stream.write_all(&write_buf)?;
+            stream.send_command(command).await?;
             let mut read_buf = vec![0; (size * 2) as usize];
-            tcp_stream.read_exact(&mut read_buf)?;
+            stream.read_exact(&mut read_buf).await?;
             Ok(RemoteData::InputRegisters(read_buf))
         } else {
-            Err(ErrorKind::InvalidInput.into())
+            Err(stream.protocol_error(format!("{command:?} does not read input registers")))
         }
     }
 
-    pub fn read_realtime(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        let mut write_buf;
+    pub async fn read_realtime(stream: &mut DeviceStream) -> Result<RemoteData, ServerError> {
         let mut bytes = Vec::new();
         for command in Realtime::generate_commands() {
             println!("read_realtime => sending command: {:?}", command);
-            write_buf = command.to_bytes();
-            println!("write_buf: {:?}", write_buf);
-            tcp_stream.write_all(&write_buf)?;
+            stream.send_command(command).await?;
             let mut read_buf = vec![0; (command.size() * 2) as usize];
-            tcp_stream.read_exact(&mut read_buf)?;
+            stream.read_exact(&mut read_buf).await?;
             bytes.extend_from_slice(&read_buf[..]);
         }
-        Ok(Self::Realtime(Realtime::from_bytes(&bytes)))
+        let realtime = Realtime::from_bytes(&bytes).map_err(|e| stream.protocol_error(e))?;
+        Ok(Self::Realtime(realtime))
     }
 
-    pub fn read_realtime_status(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
+    pub async fn read_realtime_status(
+        stream: &mut DeviceStream,
+    ) -> Result<RemoteData, ServerError> {
         let command = RealtimeStatus::generate_command();
-        tcp_stream.write_all(&command.to_bytes())?;
+        stream.send_command(command).await?;
         let mut read_buf = vec![0; (command.size() * 2) as usize];
-        tcp_stream.read_exact(&mut read_buf)?;
-        Ok(Self::RealtimeStatus(RealtimeStatus::from_bytes(&read_buf)))
+        stream.read_exact(&mut read_buf).await?;
+        let realtime_status =
+            RealtimeStatus::from_bytes(&read_buf).map_err(|e| stream.protocol_error(e))?;
+        Ok(Self::RealtimeStatus(realtime_status))
     }
 
-    pub fn read_voltage_settings(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
+    pub async fn read_voltage_settings(
+        stream: &mut DeviceStream,
+    ) -> Result<RemoteData, ServerError> {
         let command = VoltageSettings::generate_get_command();
-        tcp_stream.write_all(&command.to_bytes())?;
+        stream.send_command(command).await?;
         let mut read_buf = vec![0; (command.size() * 2) as usize];
-        tcp_stream.read_exact(&mut read_buf)?;
-        Ok(Self::VoltageSettings(VoltageSettings::from_bytes(
-            &read_buf,
-        )))
+        stream.read_exact(&mut read_buf).await?;
+        let voltage_settings =
+            VoltageSettings::from_bytes(&read_buf).map_err(|e| stream.protocol_error(e))?;
+        Ok(Self::VoltageSettings(voltage_settings))
     }
 
-    pub fn read_rated(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        let mut write_buf;
+    pub async fn read_rated(stream: &mut DeviceStream) -> Result<RemoteData, ServerError> {
         let mut bytes = Vec::new();
         for command in Rated::generate_commands() {
-            write_buf = command.to_bytes();
-            tcp_stream.write_all(&write_buf)?;
+            stream.send_command(command).await?;
+            let mut read_buf = vec![0; (command.size() * 2) as usize];
+            stream.read_exact(&mut read_buf).await?;
+            bytes.extend_from_slice(&read_buf[..]);
+        }
+        let rated = Rated::from_bytes(&bytes).map_err(|e| stream.protocol_error(e))?;
+        Ok(Self::Rated(rated))
+    }
+
+    pub async fn read_load_control(stream: &mut DeviceStream) -> Result<RemoteData, ServerError> {
+        let mut bytes = Vec::new();
+        for command in LoadControl::generate_get_commands() {
+            stream.send_command(command).await?;
             let mut read_buf = vec![0; (command.size() * 2) as usize];
-            tcp_stream.read_exact(&mut read_buf)?;
+            stream.read_exact(&mut read_buf).await?;
             bytes.extend_from_slice(&read_buf[..]);
         }
-        Ok(Self::Rated(Rated::from_bytes(&bytes)))
+        let load_control = LoadControl::from_bytes(&bytes).map_err(|e| stream.protocol_error(e))?;
+        Ok(Self::LoadControl(load_control))
+    }
+
+    pub async fn read_coil(stream: &mut DeviceStream, address: u16) -> Result<bool, ServerError> {
+        stream.send_command(Command::ModbusGetCoil(address)).await?;
+        let mut read_buf = [0; 1];
+        stream.read_exact(&mut read_buf).await?;
+        match read_buf[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(stream.protocol_error(format!("coil state {other}"))),
+        }
     }
 
-    pub fn read_stats(tcp_stream: &mut TcpStream) -> std::io::Result<RemoteData> {
-        let mut write_buf;
+    pub async fn read_stats(stream: &mut DeviceStream) -> Result<RemoteData, ServerError> {
         let commands = Stats::generate_get_commands();
-        write_buf = commands[0].to_bytes();
-        tcp_stream.write_all(&write_buf)?;
+        stream.send_command(commands[0]).await?;
         let mut ev_buf = vec![0; (commands[0].size() * 2) as usize];
-        tcp_stream.read_exact(&mut ev_buf)?;
-        write_buf = commands[1].to_bytes();
-        tcp_stream.write_all(&write_buf)?;
+        stream.read_exact(&mut ev_buf).await?;
+        stream.send_command(commands[1]).await?;
         let mut battery_buf = vec![0; (commands[1].size() * 2) as usize];
-        tcp_stream.read_exact(&mut battery_buf)?;
+        stream.read_exact(&mut battery_buf).await?;
         let mut energy_voltage_data: [u16; 20] = [0; 20];
         for (ix, chunk) in ev_buf.chunks(2).enumerate() {
             energy_voltage_data[ix] = u16::from_be_bytes([chunk[0], chunk[1]]);
@@ -208,6 +375,41 @@ impl RemoteData {
         }))
     }
 
+    /// buffer this data was read from
+    pub fn buffer_type(&self) -> Option<BufferType> {
+        match self {
+            RemoteData::BatteryVoltage(_) => Some(BufferType::Battery1Voltage),
+            RemoteData::BatteryPackVoltage(_) => Some(BufferType::BatteryPackVoltage),
+            RemoteData::PVVoltage(_) => Some(BufferType::PVVoltage),
+            RemoteData::PVPower(_) => Some(BufferType::PVPower),
+            RemoteData::InverterPower(_) => Some(BufferType::InverterPower),
+            _ => None,
+        }
+    }
+
+    /// raw buffer contents
+    pub fn readings(&self) -> Option<&[u16]> {
+        match self {
+            RemoteData::BatteryVoltage(v)
+            | RemoteData::BatteryPackVoltage(v)
+            | RemoteData::PVVoltage(v)
+            | RemoteData::PVPower(v)
+            | RemoteData::InverterPower(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn readings_mut(&mut self) -> Option<&mut Vec<u16>> {
+        match self {
+            RemoteData::BatteryVoltage(v)
+            | RemoteData::BatteryPackVoltage(v)
+            | RemoteData::PVVoltage(v)
+            | RemoteData::PVPower(v)
+            | RemoteData::InverterPower(v) => Some(v),
+            _ => None,
+        }
+    }
+
     pub fn take_adc_readings(&mut self) -> Vec<u16> {
         let mut res = Vec::new();
         match self {
@@ -222,10 +424,56 @@ impl RemoteData {
     }
     pub fn take_power_readings(&mut self) -> Vec<u16> {
         let mut res = Vec::new();
-        if let RemoteData::PVPower(v) = self {
+        if let RemoteData::PVPower(v) | RemoteData::InverterPower(v) = self {
             res = std::mem::take(v);
         }
         *self = RemoteData::NoData;
         res
     }
 }
+
+/// The samples of a buffer, the device sends them little endian.
+/// A trailing odd byte is ignored.
+pub fn samples_from_bytes(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn frames_parse_back(
+            sequence: u8,
+            payload in prop::collection::vec(any::<u8>(), 0..300),
+        ) {
+            let frame = command::frame(sequence, &payload);
+            let header = frame[..FRAME_HEADER_SIZE].try_into().unwrap();
+            let header = FrameHeader::parse(header).unwrap();
+            prop_assert_eq!(header.sequence, sequence);
+            prop_assert_eq!(header.len as usize, payload.len());
+            prop_assert!(header.check(&frame[FRAME_HEADER_SIZE..]).is_ok());
+        }
+
+        #[test]
+        fn arbitrary_frame_headers_are_checked(
+            header: [u8; FRAME_HEADER_SIZE],
+            payload in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            if let Ok(header) = FrameHeader::parse(&header) {
+                let _ = header.check(&payload);
+            }
+        }
+
+        #[test]
+        fn arbitrary_bytes_are_samples(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let samples = samples_from_bytes(&bytes);
+            prop_assert_eq!(samples.len(), bytes.len() / 2);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use crate::command::{Command, Protocol};
+use serde::Serialize;
+use std::{fmt::Display, io, sync::mpsc::SendError};
+
+/// What went wrong on a device connection, errors are counted per kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Timeout,
+    ShortRead,
+    Protocol,
+    Io,
+    ChannelClosed,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 5] = [
+        ErrorKind::Timeout,
+        ErrorKind::ShortRead,
+        ErrorKind::Protocol,
+        ErrorKind::Io,
+        ErrorKind::ChannelClosed,
+    ];
+
+    /// used as metrics label
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::ShortRead => "short_read",
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Io => "io",
+            ErrorKind::ChannelClosed => "channel_closed",
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// `command` is the last command sent to the device, the answer to it failed
+#[derive(Debug)]
+pub enum ServerError {
+    /// no complete answer within the read timeout or a write that did not finish in time
+    Timeout {
+        command: Option<Command>,
+        bytes_read: usize,
+        expected: usize,
+    },
+    /// the device closed the connection in the middle of an answer
+    ShortRead {
+        command: Option<Command>,
+        bytes_read: usize,
+        expected: usize,
+    },
+    /// the answer does not fit the command
+    Protocol {
+        command: Option<Command>,
+        message: String,
+    },
+    Io {
+        command: Option<Command>,
+        source: io::Error,
+    },
+    /// nobody receives remote data anymore
+    ChannelClosed,
+}
+
+impl ServerError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ServerError::Timeout { .. } => ErrorKind::Timeout,
+            ServerError::ShortRead { .. } => ErrorKind::ShortRead,
+            ServerError::Protocol { .. } => ErrorKind::Protocol,
+            ServerError::Io { .. } => ErrorKind::Io,
+            ServerError::ChannelClosed => ErrorKind::ChannelClosed,
+        }
+    }
+
+    pub fn command(&self) -> Option<Command> {
+        match self {
+            ServerError::Timeout { command, .. }
+            | ServerError::ShortRead { command, .. }
+            | ServerError::Protocol { command, .. }
+            | ServerError::Io { command, .. } => *command,
+            ServerError::ChannelClosed => None,
+        }
+    }
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Timeout {
+                bytes_read,
+                expected,
+                ..
+            } => write!(f, "timeout after {bytes_read} of {expected} bytes")?,
+            ServerError::ShortRead {
+                bytes_read,
+                expected,
+                ..
+            } => write!(
+                f,
+                "connection closed after {bytes_read} of {expected} bytes"
+            )?,
+            ServerError::Protocol { message, .. } => write!(f, "protocol error: {message}")?,
+            ServerError::Io { source, .. } => write!(f, "io error: {source}")?,
+            ServerError::ChannelClosed => write!(f, "remote data channel closed")?,
+        }
+        match self.command() {
+            Some(command) => write!(f, " ({command:?})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for ServerError {
+    fn from(_value: SendError<T>) -> Self {
+        ServerError::ChannelClosed
+    }
+}
+
+/// Shown in the connection log of the gui
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    Connected,
+    Negotiated(Protocol),
+    /// the device was asked to send its whole buffers again, the next replies repeat samples
+    Retransmitting,
+    Closed,
+    Error {
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+impl From<&ServerError> for ConnectionEvent {
+    fn from(error: &ServerError) -> Self {
+        ConnectionEvent::Error {
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl Display for ConnectionEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionEvent::Connected => write!(f, "connected"),
+            ConnectionEvent::Negotiated(protocol) => write!(f, "speaking {protocol}"),
+            ConnectionEvent::Retransmitting => write!(f, "retransmitting buffers"),
+            ConnectionEvent::Closed => write!(f, "connection closed"),
+            ConnectionEvent::Error { message, .. } => write!(f, "{message}"),
+        }
+    }
+}
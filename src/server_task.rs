@@ -1,153 +1,501 @@
 use crate::{
-    command::{self, Command},
+    command::{self, BufferType, Command, Protocol},
+    config::ServerConfig,
+    device::{ConnectedDevices, DeviceId, DeviceInfo},
+    device_stream::DeviceStream,
+    poll_schedule::{PollSchedule, Poller},
     remote_data::RemoteData,
-    tracer_an::VoltageSettings,
+    server_error::{ConnectionEvent, ErrorKind, ServerError},
+    tracer_an::{VoltageSettings, LOAD_COIL_ADDRESS},
 };
-use mpsc::{Receiver, SendError, Sender};
 use std::{
-    io::Write,
-    net::{TcpListener, TcpStream},
-    sync::*,
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    task::AbortHandle,
+};
+
+/// a device with a longer backlog hands out the rest on the next poll
+const MAX_LOG_MESSAGES_PER_POLL: usize = 16;
+
+/// the idle time of a device is checked at least this often
+const MIN_HEARTBEAT_CHECK: Duration = Duration::from_millis(100);
 
+/// a message for one device, or for every connected device if the target is `None`
+pub type DeviceMessage = (Option<DeviceId>, ServerMessage);
+
+/// Accepts device connections and serves each of them in its own task
 pub struct Server {
-    connected: Arc<Mutex<bool>>,
-    remote_data_sender: Sender<RemoteData>,
-    server_message_receiver: Receiver<ServerMessage>,
-    retransmit_buffers: bool,
+    connected: ConnectedDevices,
+    remote_data_sender: Sender<(DeviceId, RemoteData)>,
+    server_message_receiver: Receiver<DeviceMessage>,
+    poll_schedule: PollSchedule,
+    config: ServerConfig,
 }
+
+/// how messages reach the task serving a device
+struct Route {
+    sender: mpsc::UnboundedSender<ServerMessage>,
+    task: AbortHandle,
+    /// tells a reconnected device apart from its previous connection
+    connection_id: u64,
+}
+
+type Routes = Arc<Mutex<BTreeMap<DeviceId, Route>>>;
+
 impl Server {
     pub fn new(
-        connected: Arc<Mutex<bool>>,
-        remote_data_sender: Sender<RemoteData>,
-        server_message_receiver: Receiver<ServerMessage>,
+        connected: ConnectedDevices,
+        remote_data_sender: Sender<(DeviceId, RemoteData)>,
+        server_message_receiver: Receiver<DeviceMessage>,
     ) -> Self {
         Server {
             connected,
             remote_data_sender,
             server_message_receiver,
-            retransmit_buffers: true,
+            poll_schedule: Default::default(),
+            config: Default::default(),
         }
     }
 
-    pub fn run(mut self) {
-        if let Ok(tcp_listener) = TcpListener::bind("0.0.0.0:8900") {
-            for result in tcp_listener.incoming() {
-                let mut tcp_stream = result.expect("tcp_stream error");
-                if let Ok(mut mgc) = self.connected.lock() {
-                    *mgc = true;
-                }
-                if self.connection_established(&mut tcp_stream).is_err() {
-                    if let Ok(mut mgc) = self.connected.lock() {
-                        *mgc = false;
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_poll_schedule(mut self, poll_schedule: PollSchedule) -> Self {
+        self.poll_schedule = poll_schedule;
+        self
+    }
+
+    /// Blocks until the server message sender hangs up, every connection is cancelled then
+    pub fn run(self) {
+        let runtime = match tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                println!("could not start the server runtime: {e}");
+                return;
+            }
+        };
+        runtime.block_on(async {
+            match TcpListener::bind(("0.0.0.0", self.config.tcp_port)).await {
+                Ok(tcp_listener) => self.serve(tcp_listener).await,
+                Err(e) => println!("could not bind tcp port {}: {e}", self.config.tcp_port),
+            }
+        });
+    }
+
+    /// Serves the devices connecting to `tcp_listener`
+    async fn serve(self, tcp_listener: TcpListener) {
+        let routes = Routes::default();
+        let (shutdown_sender, mut shutdown) = oneshot::channel();
+        let dispatch_routes = routes.clone();
+        let server_message_receiver = self.server_message_receiver;
+        // the gui and mqtt send on a blocking channel
+        thread::spawn(move || {
+            dispatch(server_message_receiver, &dispatch_routes);
+            shutdown_sender.send(()).ok();
+        });
+
+        // buffers are retransmitted once per device and process
+        let mut retransmitted = BTreeSet::new();
+        for connection_id in 0.. {
+            let (tcp_stream, peer) = tokio::select! {
+                accepted = tcp_listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        println!("could not accept connection: {e}");
+                        continue;
                     }
-                    continue;
+                },
+                _ = &mut shutdown => break,
+            };
+            let device = DeviceId::from_peer(peer);
+            let (message_sender, message_receiver) = mpsc::unbounded_channel();
+            let connection = Connection {
+                retransmit_buffers: retransmitted.insert(device.clone()),
+                device: device.clone(),
+                remote_data_sender: self.remote_data_sender.clone(),
+                server_message_receiver: message_receiver,
+                poller: Poller::new(self.poll_schedule.clone()),
+                config: self.config.clone(),
+                heartbeat: 0,
+                info: DeviceInfo::legacy(),
+            };
+            // the route is inserted before the task can remove it
+            let Ok(mut locked_routes) = routes.lock() else {
+                break;
+            };
+            if let Ok(mut connected) = self.connected.lock() {
+                connected.insert(device.clone());
+            }
+            let task = tokio::spawn(connection.run(
+                DeviceStream::new(tcp_stream).with_timeouts(
+                    Duration::from_millis(self.config.read_timeout_ms),
+                    Duration::from_millis(self.config.write_timeout_ms),
+                ),
+                connection_id,
+                routes.clone(),
+                self.connected.clone(),
+            ));
+            let route = Route {
+                sender: message_sender,
+                task: task.abort_handle(),
+                connection_id,
+            };
+            if let Some(previous) = locked_routes.insert(device.clone(), route) {
+                println!("{device}: reconnected, cancelling the previous connection");
+                previous.task.abort();
+            }
+        }
+        println!("server stopped");
+    }
+}
+
+/// forwards the messages to the connection tasks of their devices
+fn dispatch(
+    server_message_receiver: Receiver<DeviceMessage>,
+    routes: &Mutex<BTreeMap<DeviceId, Route>>,
+) {
+    for (target, message) in server_message_receiver.iter() {
+        let Ok(routes) = routes.lock() else {
+            break;
+        };
+        match target {
+            Some(device) => match routes.get(&device) {
+                Some(route) => {
+                    // a closed connection is removed from the routes by its task
+                    route.sender.send(message).ok();
                 }
+                None => println!("{device} is not connected, dropping {message:?}"),
+            },
+            None => routes.values().for_each(|route| {
+                route.sender.send(message).ok();
+            }),
+        }
+    }
+}
 
-                // get buffers in a loop
-                loop {
-                    if self.server_loop(&mut tcp_stream).is_err() {
-                        if let Ok(mut mgc) = self.connected.lock() {
-                            *mgc = false;
-                        }
-                        break;
+/// One connected device
+struct Connection {
+    device: DeviceId,
+    remote_data_sender: Sender<(DeviceId, RemoteData)>,
+    server_message_receiver: mpsc::UnboundedReceiver<ServerMessage>,
+    retransmit_buffers: bool,
+    poller: Poller,
+    config: ServerConfig,
+    /// sequence number of the last heartbeat
+    heartbeat: u8,
+    /// buffers the device does not have are not requested
+    info: DeviceInfo,
+}
+
+impl Connection {
+    async fn run(
+        mut self,
+        mut stream: DeviceStream,
+        connection_id: u64,
+        routes: Routes,
+        connected: ConnectedDevices,
+    ) {
+        self.send(RemoteData::ConnectionEvent(ConnectionEvent::Connected))
+            .ok();
+        if let Err(e) = self.serve(&mut stream).await {
+            println!(
+                "{}: connection closed, discovery restarts: {e}",
+                self.device
+            );
+            self.send(RemoteData::ConnectionEvent((&e).into())).ok();
+        }
+        self.send(RemoteData::ConnectionEvent(ConnectionEvent::Closed))
+            .ok();
+        if let Ok(mut routes) = routes.lock() {
+            // a reconnect may already have replaced this connection
+            if routes
+                .get(&self.device)
+                .is_some_and(|route| route.connection_id == connection_id)
+            {
+                routes.remove(&self.device);
+                if let Ok(mut connected) = connected.lock() {
+                    connected.remove(&self.device);
+                }
+            }
+        }
+    }
+
+    /// Reads the buffers in rounds and serves messages as soon as they arrive in between.
+    /// A heartbeat checks devices that were silent for `heartbeat_interval_ms`.
+    async fn serve(&mut self, stream: &mut DeviceStream) -> Result<(), ServerError> {
+        self.connection_established(stream).await?;
+        let round_pause = Duration::from_millis(self.config.loop_sleep_ms);
+        let next_round = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(next_round);
+        let heartbeat_interval = Duration::from_millis(self.config.heartbeat_interval_ms);
+        let mut heartbeat_check =
+            tokio::time::interval(heartbeat_interval.max(MIN_HEARTBEAT_CHECK));
+        loop {
+            tokio::select! {
+                () = &mut next_round => {
+                    self.read_buffers(stream).await?;
+                    for message in self.poller.due(Instant::now()) {
+                        self.serve_message(message, stream).await?;
+                    }
+                    next_round
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + round_pause);
+                }
+                message = self.server_message_receiver.recv() => match message {
+                    Some(message) => self.serve_message(message, stream).await?,
+                    // the server stopped
+                    None => return Ok(()),
+                },
+                _ = heartbeat_check.tick(), if !heartbeat_interval.is_zero() => {
+                    if stream.idle() >= heartbeat_interval {
+                        self.send_heartbeat(stream).await?;
                     }
                 }
             }
         }
     }
 
-    fn connection_established(&mut self, tcp_stream: &mut TcpStream) -> Result<(), ServerError> {
-        let remote_data_sender = &self.remote_data_sender;
-        println!("connection established");
-        if let Ok(intervalms) = RemoteData::read_interval_ms_voltage(tcp_stream) {
-            println!("Interval : {intervalms:?} ms");
-            remote_data_sender.send(intervalms).unwrap();
+    /// A half-open connection times out, a device that lost track of the protocol
+    /// answers with the wrong sequence number. Legacy firmware does not know the heartbeat
+    /// and is asked for its buffer size instead.
+    async fn send_heartbeat(&mut self, stream: &mut DeviceStream) -> Result<(), ServerError> {
+        if stream.protocol() == Protocol::Legacy {
+            RemoteData::read_voltage_buffer_size(stream).await?;
+            return Ok(());
+        }
+        self.heartbeat = self.heartbeat.wrapping_add(1);
+        stream
+            .send_command(Command::Heartbeat(self.heartbeat))
+            .await?;
+        let mut echo = [0];
+        stream.read_exact(&mut echo).await?;
+        if echo[0] != self.heartbeat {
+            return Err(stream.protocol_error(format!("heartbeat answered with {}", echo[0])));
+        }
+        Ok(())
+    }
+
+    fn send(&self, remote_data: RemoteData) -> Result<(), ServerError> {
+        self.remote_data_sender
+            .send((self.device.clone(), remote_data))?;
+        Ok(())
+    }
+
+    async fn connection_established(
+        &mut self,
+        stream: &mut DeviceStream,
+    ) -> Result<(), ServerError> {
+        println!("{}: connection established", self.device);
+        if self.config.negotiation_timeout_ms > 0 {
+            let timeout = Duration::from_millis(self.config.negotiation_timeout_ms);
+            let protocol = stream.negotiate_protocol(timeout).await?;
+            println!("{}: speaking {protocol}", self.device);
+            self.send(RemoteData::ConnectionEvent(ConnectionEvent::Negotiated(
+                protocol,
+            )))?;
         }
-        if let Ok(intervalms) = RemoteData::read_interval_ms_power(tcp_stream) {
-            println!("Interval : {intervalms:?} ms");
-            remote_data_sender.send(intervalms).unwrap();
+        if stream.protocol() == Protocol::V2 {
+            self.info = RemoteData::read_device_info(stream).await?;
+            println!(
+                "{}: firmware {}, buffers {:?}",
+                self.device,
+                self.info.firmware(),
+                self.info.buffers
+            );
+            self.send(RemoteData::VoltageIntervalms(self.info.voltage_interval_ms))?;
+            self.send(RemoteData::PowerIntervalms(self.info.power_interval_ms))?;
+        } else {
+            self.info = DeviceInfo::legacy();
+            self.read_intervals(stream).await?;
         }
+        self.send(RemoteData::DeviceInfo(self.info.clone()))?;
 
-        let voltage_buffer_size = RemoteData::read_voltage_buffer_size(tcp_stream)?;
-        remote_data_sender.send(voltage_buffer_size)?;
+        let voltage_buffer_size = RemoteData::read_voltage_buffer_size(stream).await?;
+        self.send(voltage_buffer_size)?;
 
         if self.retransmit_buffers {
-            let command_bytes = command::Command::RetransmitBuffers.to_bytes();
-            tcp_stream.write_all(&command_bytes)?;
+            stream
+                .send_command(command::Command::RetransmitBuffers)
+                .await?;
+            self.send(RemoteData::ConnectionEvent(ConnectionEvent::Retransmitting))?;
             self.retransmit_buffers = false;
         }
+        self.poller.reset();
         Ok(())
     }
 
-    fn server_loop(&mut self, tcp_stream: &mut TcpStream) -> Result<(), ServerError> {
-        let battery_voltage = RemoteData::read_battery_voltage(tcp_stream)?;
-        self.remote_data_sender.send(battery_voltage)?;
-        let battery_pack_voltage = RemoteData::read_battery_pack_voltage(tcp_stream)?;
-        self.remote_data_sender.send(battery_pack_voltage)?;
-        let pv_voltage = RemoteData::read_pv_voltage(tcp_stream)?;
-        self.remote_data_sender.send(pv_voltage)?;
-        let pv_power = RemoteData::read_pv_power(tcp_stream)?;
-        self.remote_data_sender.send(pv_power)?;
-        while let Ok(message) = self.server_message_receiver.try_recv() {
-            match message {
-                ServerMessage::Command(command) => {
-                    self.serve_command(command, tcp_stream)?;
-                }
-                ServerMessage::ReadRealtime => {
-                    let remote_data = RemoteData::read_realtime(tcp_stream)?;
-                    self.remote_data_sender.send(remote_data)?;
-                }
-                ServerMessage::ReadRealtimeStatus => {
-                    let remote_data = RemoteData::read_realtime_status(tcp_stream)?;
-                    self.remote_data_sender.send(remote_data)?;
-                }
-                ServerMessage::ReadVoltageSettings => {
-                    let remote_data = RemoteData::read_voltage_settings(tcp_stream)?;
-                    self.remote_data_sender.send(remote_data)?;
+    /// firmware without the handshake is asked for the intervals one by one
+    async fn read_intervals(&mut self, stream: &mut DeviceStream) -> Result<(), ServerError> {
+        match RemoteData::read_interval_ms_voltage(stream).await {
+            Ok(intervalms) => {
+                println!("Interval : {intervalms:?} ms");
+                self.send(intervalms)?;
+            }
+            Err(e) => self.send(RemoteData::ConnectionEvent((&e).into()))?,
+        }
+        match RemoteData::read_interval_ms_power(stream).await {
+            Ok(intervalms) => {
+                println!("Interval : {intervalms:?} ms");
+                self.send(intervalms)?;
+            }
+            Err(e) => self.send(RemoteData::ConnectionEvent((&e).into()))?,
+        }
+        Ok(())
+    }
+
+    async fn read_buffers(&mut self, stream: &mut DeviceStream) -> Result<(), ServerError> {
+        if self.info.supports(BufferType::Battery1Voltage) {
+            let battery_voltage = RemoteData::read_battery_voltage(stream).await?;
+            self.send(battery_voltage)?;
+        }
+        if self.info.supports(BufferType::BatteryPackVoltage) {
+            let battery_pack_voltage = RemoteData::read_battery_pack_voltage(stream).await?;
+            self.send(battery_pack_voltage)?;
+        }
+        if self.info.supports(BufferType::PVVoltage) {
+            let pv_voltage = RemoteData::read_pv_voltage(stream).await?;
+            self.send(pv_voltage)?;
+        }
+        if self.info.supports(BufferType::PVPower) {
+            let pv_power = RemoteData::read_pv_power(stream).await?;
+            self.send(pv_power)?;
+        }
+        if self.info.supports(BufferType::InverterPower) {
+            let inverter_power = RemoteData::read_inverter_power(stream).await?;
+            self.send(inverter_power)?;
+        }
+        Ok(())
+    }
+
+    async fn serve_message(
+        &mut self,
+        message: ServerMessage,
+        stream: &mut DeviceStream,
+    ) -> Result<(), ServerError> {
+        match message {
+            ServerMessage::Command(command) => {
+                self.serve_command(command, stream).await?;
+            }
+            ServerMessage::ReadRealtime => {
+                let remote_data = RemoteData::read_realtime(stream).await?;
+                self.send(remote_data)?;
+            }
+            ServerMessage::ReadRealtimeStatus => {
+                let remote_data = RemoteData::read_realtime_status(stream).await?;
+                self.send(remote_data)?;
+            }
+            ServerMessage::ReadVoltageSettings => {
+                let remote_data = RemoteData::read_voltage_settings(stream).await?;
+                self.send(remote_data)?;
+            }
+            ServerMessage::ReadRated => {
+                let remote_data = RemoteData::read_rated(stream).await?;
+                self.send(remote_data)?;
+            }
+            ServerMessage::ReadStats => {
+                let remote_data = RemoteData::read_stats(stream).await?;
+                self.send(remote_data)?;
+            }
+            ServerMessage::ReadLogMessages => {
+                for _ in 0..MAX_LOG_MESSAGES_PER_POLL {
+                    match RemoteData::read_log_message(stream).await? {
+                        Some(message) => self.send(RemoteData::LogMessage(message))?,
+                        None => break,
+                    }
                 }
-                ServerMessage::ReadRated => {
-                    let remote_data = RemoteData::read_rated(tcp_stream)?;
-                    self.remote_data_sender.send(remote_data)?;
+            }
+            ServerMessage::SetVoltageSettings(cs) => {
+                self.serve_command(cs.generate_set_command(), stream)
+                    .await?
+            }
+            ServerMessage::ReadLoadControl => {
+                let remote_data = RemoteData::read_load_control(stream).await?;
+                self.send(remote_data)?;
+                // the legacy firmware can not read coils
+                if stream.protocol() == Protocol::V2 {
+                    let on = RemoteData::read_coil(stream, LOAD_COIL_ADDRESS).await?;
+                    self.send(RemoteData::LoadOutput(on))?;
                 }
-                ServerMessage::ReadStats => {
-                    let remote_data = RemoteData::read_stats(tcp_stream)?;
-                    self.remote_data_sender.send(remote_data)?;
+            }
+            ServerMessage::SetLoadOutput(on) => {
+                // the gui and mqtt learn that nothing was switched
+                if stream.protocol() != Protocol::V2 {
+                    let message = format!(
+                        "switching the load output needs firmware speaking {}",
+                        Protocol::V2
+                    );
+                    println!("{}: {message}", self.device);
+                    return self.send(RemoteData::ConnectionEvent(ConnectionEvent::Error {
+                        kind: ErrorKind::Protocol,
+                        message,
+                    }));
                 }
-                ServerMessage::SetVoltageSettings(cs) => {
-                    Self::send_command(cs.generate_set_command(), tcp_stream)?
+                stream
+                    .send_command(Command::ModbusSetCoil {
+                        register_address: LOAD_COIL_ADDRESS,
+                        on,
+                    })
+                    .await?;
+                let read_back = RemoteData::read_coil(stream, LOAD_COIL_ADDRESS).await?;
+                if read_back != on {
+                    println!(
+                        "{}: load output stayed {read_back} after switching it to {on}",
+                        self.device
+                    );
                 }
+                self.send(RemoteData::LoadOutput(read_back))?;
             }
         }
-        thread::sleep(Duration::from_millis(500));
         Ok(())
     }
 
-    pub fn send_command(
-        command: Command,
-        tcp_stream: &mut TcpStream,
-    ) -> Result<(), std::io::Error> {
-        tcp_stream.write_all(&command.to_bytes())
-    }
-
-    pub fn serve_command(
+    async fn serve_command(
         &mut self,
         command: Command,
-        tcp_stream: &mut TcpStream,
+        stream: &mut DeviceStream,
     ) -> Result<(), ServerError> {
         match command {
             command::Command::ModbusGetHoldings { .. } => {
-                let val = RemoteData::get_holdings(tcp_stream, command)?;
+                let val = RemoteData::get_holdings(stream, command).await?;
                 println!("holding val: {:?}", &val);
-                self.remote_data_sender.send(val)?;
+                self.send(val)?;
             }
             command::Command::ModbusGetInputRegisters { .. } => {
                 println!("getting_input_registers");
-                let val = RemoteData::get_input_registers(tcp_stream, command)?;
+                let val = RemoteData::get_input_registers(stream, command).await?;
                 println!("input reg val: {:?}", &val);
-                self.remote_data_sender.send(val)?;
+                self.send(val)?;
+            }
+            command::Command::ModbusSetHoldings { .. }
+            | command::Command::ModbusWriteHoldings { .. } => {
+                let written = RemoteData::write_holdings(stream, command).await?;
+                if let RemoteData::HoldingsWritten {
+                    register_address,
+                    written,
+                    read_back,
+                } = &written
+                {
+                    if written != read_back {
+                        println!(
+                            "holdings at {register_address:#06x} read back as {read_back:?} after writing {written:?}"
+                        );
+                    }
+                }
+                self.send(written)?;
             }
             _ => {}
         }
@@ -155,23 +503,6 @@ impl Server {
     }
 }
 
-pub enum ServerError {
-    IoError,
-    SendError,
-}
-
-impl From<std::io::Error> for ServerError {
-    fn from(_value: std::io::Error) -> Self {
-        ServerError::IoError
-    }
-}
-
-impl From<SendError<RemoteData>> for ServerError {
-    fn from(_value: SendError<RemoteData>) -> Self {
-        ServerError::SendError
-    }
-}
-
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ServerMessage {
     Command(Command),
@@ -180,5 +511,582 @@ pub enum ServerMessage {
     ReadVoltageSettings,
     ReadRated,
     ReadStats,
+    /// fetches the messages the device logged since the last read
+    ReadLogMessages,
     SetVoltageSettings(VoltageSettings),
+    /// reads the load control holdings and, with v2 firmware, the load coil
+    ReadLoadControl,
+    /// switches the load output and reads the coil back
+    SetLoadOutput(bool),
+}
+
+impl ServerMessage {
+    /// whether the message changes the device instead of only reading it
+    pub fn writes_device(&self) -> bool {
+        match self {
+            ServerMessage::Command(command) => match command {
+                Command::GetVoltageIntervalms
+                | Command::GetPowerIntervalms
+                | Command::GetVoltageBufferSize
+                | Command::GetBuffer(_)
+                | Command::ModbusGetHoldings { .. }
+                | Command::ModbusGetInputRegisters { .. }
+                | Command::GetLastLogMessage
+                | Command::Heartbeat(_)
+                | Command::NegotiateProtocol(_)
+                | Command::GetDeviceInfo
+                | Command::ModbusGetCoil(_) => false,
+                Command::RetransmitBuffers
+                | Command::ModbusSetHoldings { .. }
+                | Command::ModbusWriteHoldings { .. }
+                | Command::ModbusSetCoil { .. } => true,
+            },
+            ServerMessage::ReadRealtime
+            | ServerMessage::ReadRealtimeStatus
+            | ServerMessage::ReadVoltageSettings
+            | ServerMessage::ReadRated
+            | ServerMessage::ReadStats
+            | ServerMessage::ReadLogMessages
+            | ServerMessage::ReadLoadControl => false,
+            ServerMessage::SetVoltageSettings(_) | ServerMessage::SetLoadOutput(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{COMMAND_SIZE, FRAME_HEADER_SIZE, PROTOCOL_VERSION},
+        config::AdcConfig,
+        poll_schedule::PollInterval,
+        remote_data::FrameHeader,
+        simulator::{self, SimulatorConfig, VirtualDevice},
+        tracer_an::{
+            HoldingSetting, LoadControlMode, Realtime, Stats, LOAD_CONTROL_MODE_ADDRESS,
+            STATS_BASE_ADDRESS, VOLTAGE_SETTINGS_BASE_ADDRESS,
+        },
+    };
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        runtime::Runtime,
+        task::JoinHandle,
+    };
+
+    fn connection() -> (Connection, mpsc::UnboundedSender<ServerMessage>) {
+        let (message_sender, server_message_receiver) = mpsc::unbounded_channel();
+        let (remote_data_sender, _) = std::sync::mpsc::channel();
+        let connection = Connection {
+            device: DeviceId("10.0.0.2".to_string()),
+            remote_data_sender,
+            server_message_receiver,
+            retransmit_buffers: false,
+            poller: Poller::new(PollSchedule::default()),
+            config: ServerConfig::default(),
+            heartbeat: 0,
+            info: DeviceInfo::legacy(),
+        };
+        (connection, message_sender)
+    }
+
+    /// sequence number and command of the next v2 frame the server sent
+    async fn read_frame(device_side: &mut tokio::io::DuplexStream) -> (u8, Command) {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        device_side.read_exact(&mut header).await.unwrap();
+        let header = FrameHeader::parse(&header).unwrap();
+        let mut payload = vec![0; header.len as usize];
+        device_side.read_exact(&mut payload).await.unwrap();
+        (header.sequence, Command::try_from(&payload[..]).unwrap())
+    }
+
+    #[tokio::test]
+    async fn heartbeat_detects_silent_and_confused_devices() {
+        let (mut connection, _message_sender) = connection();
+        let (device, mut device_side) = tokio::io::duplex(64);
+        let timeout = Duration::from_millis(50);
+        let mut stream = DeviceStream::new(device).with_timeouts(timeout, timeout);
+
+        let echo = tokio::spawn(async move {
+            let mut command = [0; COMMAND_SIZE];
+            device_side.read_exact(&mut command).await.unwrap();
+            device_side.write_all(&[PROTOCOL_VERSION]).await.unwrap();
+            let (sequence, heartbeat) = read_frame(&mut device_side).await;
+            assert_eq!(heartbeat, Command::Heartbeat(1));
+            device_side
+                .write_all(&command::frame(sequence, &[1]))
+                .await
+                .unwrap();
+            let (sequence, _) = read_frame(&mut device_side).await;
+            device_side
+                .write_all(&command::frame(sequence, &[0]))
+                .await
+                .unwrap();
+            read_frame(&mut device_side).await;
+            // silent from now on
+            device_side
+        });
+        assert_eq!(
+            stream.negotiate_protocol(timeout).await.unwrap(),
+            Protocol::V2
+        );
+        assert!(connection.send_heartbeat(&mut stream).await.is_ok());
+        assert!(connection.send_heartbeat(&mut stream).await.is_err());
+        assert!(connection.send_heartbeat(&mut stream).await.is_err());
+        echo.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn legacy_devices_are_probed_without_heartbeat() {
+        let (mut connection, _message_sender) = connection();
+        let (device, device_side) = tokio::io::duplex(64);
+        let timeout = Duration::from_millis(50);
+        let mut stream = DeviceStream::new(device).with_timeouts(timeout, timeout);
+        let mut virtual_device = VirtualDevice::new(SimulatorConfig {
+            legacy: true,
+            ..still_day()
+        });
+        let device = tokio::spawn(async move {
+            let _ = simulator::serve(&mut virtual_device, device_side).await;
+        });
+        assert_eq!(
+            stream.negotiate_protocol(timeout).await.unwrap(),
+            Protocol::Legacy
+        );
+        for _ in 0..3 {
+            assert!(connection.send_heartbeat(&mut stream).await.is_ok());
+        }
+        device.abort();
+    }
+
+    /// A `Server` on a free localhost port, stopped when this is dropped
+    struct TestServer {
+        addr: SocketAddr,
+        remote_data: Receiver<(DeviceId, RemoteData)>,
+        messages: Sender<DeviceMessage>,
+        connected: ConnectedDevices,
+    }
+
+    fn start_server() -> TestServer {
+        let config = ServerConfig {
+            loop_sleep_ms: 50,
+            read_timeout_ms: 1000,
+            write_timeout_ms: 1000,
+            heartbeat_interval_ms: 0,
+            negotiation_timeout_ms: 200,
+            ..Default::default()
+        };
+        let poll_schedule = PollSchedule {
+            realtime: PollInterval::Every(Duration::from_millis(100)),
+            realtime_status: PollInterval::Never,
+            stats: PollInterval::OncePerConnection,
+            rated: PollInterval::Never,
+            voltage_settings: PollInterval::OncePerConnection,
+            log_messages: PollInterval::Every(Duration::from_millis(100)),
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connected = ConnectedDevices::default();
+        let (remote_data_sender, remote_data) = std::sync::mpsc::channel();
+        let (messages, message_receiver) = std::sync::mpsc::channel();
+        let server = Server::new(connected.clone(), remote_data_sender, message_receiver)
+            .with_config(config)
+            .with_poll_schedule(poll_schedule);
+        thread::spawn(move || {
+            let runtime = Runtime::new().unwrap();
+            runtime.block_on(async {
+                let listener = TcpListener::from_std(listener).unwrap();
+                server.serve(listener).await
+            });
+        });
+        TestServer {
+            addr,
+            remote_data,
+            messages,
+            connected,
+        }
+    }
+
+    impl TestServer {
+        /// Collects remote data until `done` is satisfied with it
+        fn receive_until(&self, done: impl Fn(&[RemoteData]) -> bool) -> Vec<RemoteData> {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let mut received = Vec::new();
+            while !done(&received) {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match self.remote_data.recv_timeout(timeout) {
+                    Ok((device, remote_data)) => {
+                        assert_eq!(device, DeviceId("127.0.0.1".to_string()));
+                        received.push(remote_data);
+                    }
+                    Err(e) => panic!("{e}, received so far: {:?}", events(&received)),
+                }
+            }
+            received
+        }
+
+        fn wait_until_disconnected(&self) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while crate::device::connected_count(&self.connected) > 0 {
+                assert!(Instant::now() < deadline, "device still connected");
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    fn events(received: &[RemoteData]) -> Vec<&ConnectionEvent> {
+        received
+            .iter()
+            .filter_map(|remote_data| match remote_data {
+                RemoteData::ConnectionEvent(event) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn has_error(received: &[RemoteData], kind: ErrorKind) -> bool {
+        events(received)
+            .iter()
+            .any(|event| matches!(event, ConnectionEvent::Error { kind: k, .. } if *k == kind))
+    }
+
+    /// a device whose readings do not change over time
+    fn still_day() -> SimulatorConfig {
+        SimulatorConfig {
+            start_hour: 12.0,
+            speed: 0.0,
+            voltage_interval_ms: 50,
+            power_interval_ms: 50,
+            ..Default::default()
+        }
+    }
+
+    fn simulated_device(
+        runtime: &Runtime,
+        addr: SocketAddr,
+        config: SimulatorConfig,
+    ) -> JoinHandle<std::io::Result<()>> {
+        runtime.spawn(async move {
+            let stream = TcpStream::connect(addr).await?;
+            simulator::serve(&mut VirtualDevice::new(config), stream).await
+        })
+    }
+
+    /// Answers like old firmware, except that `tamper` may replace an answer
+    async fn tampering_device(
+        addr: SocketAddr,
+        tamper: fn(Command, Vec<u8>) -> Vec<u8>,
+    ) -> std::io::Result<()> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut device = VirtualDevice::new(SimulatorConfig {
+            legacy: true,
+            ..still_day()
+        });
+        loop {
+            let mut command = [0; COMMAND_SIZE];
+            stream.read_exact(&mut command).await?;
+            let Ok(command) = Command::try_from(&command[..]) else {
+                continue;
+            };
+            if let Some(answer) = device.answer(command) {
+                stream.write_all(&tamper(command, answer)).await?;
+            }
+        }
+    }
+
+    #[test]
+    fn readings_arrive_decoded() {
+        let server = start_server();
+        let runtime = Runtime::new().unwrap();
+        let _device = simulated_device(&runtime, server.addr, still_day());
+        let received = server.receive_until(|received| {
+            let has = |f: fn(&RemoteData) -> bool| received.iter().any(f);
+            has(|data| matches!(data, RemoteData::InverterPower(v) if !v.is_empty()))
+                && has(|data| matches!(data, RemoteData::BatteryVoltage(v) if !v.is_empty()))
+                && has(|data| matches!(data, RemoteData::Realtime(_)))
+                && has(|data| matches!(data, RemoteData::Stats(_)))
+                && has(|data| matches!(data, RemoteData::VoltageSettings(_)))
+                && has(|data| matches!(data, RemoteData::LogMessage(_)))
+        });
+        assert_eq!(
+            events(&received)[..2],
+            [
+                &ConnectionEvent::Connected,
+                &ConnectionEvent::Negotiated(Protocol::V2)
+            ]
+        );
+
+        // the readings of a device with the same config
+        let mut expected = VirtualDevice::new(still_day());
+        let realtime_bytes: Vec<u8> = Realtime::generate_commands()
+            .into_iter()
+            .flat_map(|command| expected.answer(command).unwrap())
+            .collect();
+        let register = |offset: u16| expected.input_registers[&(STATS_BASE_ADDRESS + offset)];
+        let stats = Stats {
+            energy_voltage_data: std::array::from_fn(|ix| register(ix as u16)),
+            battery_data: std::array::from_fn(|ix| register(26 + ix as u16)),
+        };
+        let voltage_settings = VoltageSettings::from_bytes(
+            &expected
+                .answer(VoltageSettings::generate_get_command())
+                .unwrap(),
+        )
+        .unwrap();
+        for remote_data in &received {
+            match remote_data {
+                RemoteData::Realtime(realtime) => {
+                    assert_eq!(*realtime, Realtime::from_bytes(&realtime_bytes).unwrap())
+                }
+                RemoteData::Stats(received_stats) => assert_eq!(*received_stats, stats),
+                RemoteData::VoltageSettings(settings) => assert_eq!(*settings, voltage_settings),
+                RemoteData::BatteryVoltage(readings) => {
+                    for reading in readings {
+                        let voltage = AdcConfig::default().reading_to_voltage(*reading);
+                        assert!((voltage - 12.75).abs() < 0.1, "{voltage} V");
+                    }
+                }
+                RemoteData::PVPower(readings) | RemoteData::InverterPower(readings) => {
+                    assert!(readings.iter().all(|power| *power > 0))
+                }
+                _ => {}
+            }
+        }
+
+        // messages reach the device and the answer comes back
+        server
+            .messages
+            .send((
+                None,
+                ServerMessage::Command(Command::ModbusGetHoldings {
+                    register_address: 0x9000,
+                    size: 2,
+                }),
+            ))
+            .unwrap();
+        let received = server
+            .receive_until(|received| received.contains(&RemoteData::Holdings(vec![0, 1, 0, 200])));
+        assert!(!has_error(&received, ErrorKind::Protocol));
+    }
+
+    #[test]
+    fn holding_writes_are_read_back() {
+        let server = start_server();
+        let runtime = Runtime::new().unwrap();
+        let written = |received: &[RemoteData]| {
+            received
+                .iter()
+                .filter(|data| matches!(data, RemoteData::HoldingsWritten { .. }))
+                .count()
+        };
+        let mut device = simulated_device(&runtime, server.addr, still_day());
+        for config in [
+            still_day(),
+            SimulatorConfig {
+                legacy: true,
+                ..still_day()
+            },
+        ] {
+            let legacy = config.legacy;
+            if legacy {
+                device.abort();
+                server.wait_until_disconnected();
+                device = simulated_device(&runtime, server.addr, config);
+            }
+            server.receive_until(|received| {
+                received
+                    .iter()
+                    .any(|data| matches!(data, RemoteData::VoltageSettings(_)))
+            });
+            let mut settings = VoltageSettings::from_bytes(
+                &VirtualDevice::new(still_day())
+                    .answer(VoltageSettings::generate_get_command())
+                    .unwrap(),
+            )
+            .unwrap();
+            settings.float_voltage = 27.2;
+            let mode = HoldingSetting::LoadControlMode(LoadControlMode::TimeControl);
+            for message in [
+                ServerMessage::SetVoltageSettings(settings),
+                ServerMessage::Command(mode.generate_set_command()),
+            ] {
+                server.messages.send((None, message)).unwrap();
+            }
+            let received = server.receive_until(|received| written(received) == 2);
+            let writes: Vec<_> = received
+                .iter()
+                .filter_map(|data| match data {
+                    RemoteData::HoldingsWritten {
+                        register_address,
+                        written,
+                        read_back,
+                    } => Some((*register_address, written == read_back)),
+                    _ => None,
+                })
+                .collect();
+            // old firmware only knows the write of all 15 holdings
+            assert_eq!(
+                writes,
+                [
+                    (VOLTAGE_SETTINGS_BASE_ADDRESS, true),
+                    (LOAD_CONTROL_MODE_ADDRESS, !legacy)
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn load_output_is_switched() {
+        let server = start_server();
+        let runtime = Runtime::new().unwrap();
+        let load_output = |received: &[RemoteData]| {
+            received.iter().find_map(|data| match data {
+                RemoteData::LoadOutput(on) => Some(*on),
+                _ => None,
+            })
+        };
+        let load_control = |received: &[RemoteData]| {
+            received.iter().find_map(|data| match data {
+                RemoteData::LoadControl(load_control) => Some(*load_control),
+                _ => None,
+            })
+        };
+        let mut device = simulated_device(&runtime, server.addr, still_day());
+        server.receive_until(|received| {
+            received
+                .iter()
+                .any(|data| matches!(data, RemoteData::DeviceInfo(_)))
+        });
+        server
+            .messages
+            .send((None, ServerMessage::ReadLoadControl))
+            .unwrap();
+        let received = server.receive_until(|received| load_output(received).is_some());
+        assert_eq!(load_output(&received), Some(true));
+        let read = load_control(&received).unwrap();
+        assert_eq!(read.mode, Some(LoadControlMode::Manual));
+
+        let mode = HoldingSetting::LoadControlMode(LoadControlMode::LightOnOff);
+        for message in [
+            ServerMessage::SetLoadOutput(false),
+            ServerMessage::Command(mode.generate_set_command()),
+            ServerMessage::ReadLoadControl,
+        ] {
+            server.messages.send((None, message)).unwrap();
+        }
+        let received = server.receive_until(|received| load_control(received).is_some());
+        assert_eq!(load_output(&received), Some(false));
+        assert_eq!(
+            load_control(&received).map(|read| read.mode),
+            Some(Some(LoadControlMode::LightOnOff))
+        );
+
+        // old firmware still reads the holdings but can not switch the coil
+        device.abort();
+        server.wait_until_disconnected();
+        device = simulated_device(
+            &runtime,
+            server.addr,
+            SimulatorConfig {
+                legacy: true,
+                ..still_day()
+            },
+        );
+        server.receive_until(|received| {
+            received
+                .iter()
+                .any(|data| matches!(data, RemoteData::DeviceInfo(_)))
+        });
+        for message in [
+            ServerMessage::SetLoadOutput(true),
+            ServerMessage::ReadLoadControl,
+        ] {
+            server.messages.send((None, message)).unwrap();
+        }
+        let received = server.receive_until(|received| load_control(received).is_some());
+        assert_eq!(load_output(&received), None);
+        assert!(events(&received).iter().any(|event| matches!(
+            event,
+            ConnectionEvent::Error { message, .. } if message.contains("load output")
+        )));
+        assert!(!events(&received).contains(&&ConnectionEvent::Closed));
+        device.abort();
+    }
+
+    #[test]
+    fn reconnect_after_disconnect() {
+        let server = start_server();
+        let runtime = Runtime::new().unwrap();
+        let has_info = |received: &[RemoteData]| {
+            received
+                .iter()
+                .any(|data| matches!(data, RemoteData::DeviceInfo(_)))
+        };
+        let device = simulated_device(&runtime, server.addr, still_day());
+        server.receive_until(has_info);
+        assert_eq!(crate::device::connected_count(&server.connected), 1);
+
+        device.abort();
+        let received =
+            server.receive_until(|received| events(received).contains(&&ConnectionEvent::Closed));
+        assert!(has_error(&received, ErrorKind::ShortRead) || has_error(&received, ErrorKind::Io));
+        server.wait_until_disconnected();
+
+        let _device = simulated_device(&runtime, server.addr, still_day());
+        let received = server.receive_until(|received| {
+            has_info(received)
+                && received
+                    .iter()
+                    .any(|data| matches!(data, RemoteData::PVVoltage(v) if !v.is_empty()))
+        });
+        assert_eq!(events(&received)[0], &ConnectionEvent::Connected);
+        assert_eq!(crate::device::connected_count(&server.connected), 1);
+    }
+
+    #[test]
+    fn malformed_replies_close_the_connection() {
+        let server = start_server();
+        let runtime = Runtime::new().unwrap();
+        // an odd number of bytes can not be a buffer of u16 samples
+        runtime.spawn(tampering_device(
+            server.addr,
+            |command, answer| match command {
+                Command::GetBuffer(_) => vec![0, 0, 0, 3, 1, 2, 3],
+                _ => answer,
+            },
+        ));
+        let received =
+            server.receive_until(|received| events(received).contains(&&ConnectionEvent::Closed));
+        assert!(events(&received).contains(&&ConnectionEvent::Negotiated(Protocol::Legacy)));
+        assert!(has_error(&received, ErrorKind::Protocol));
+        server.wait_until_disconnected();
+
+        // a size no buffer can have is refused before anything is allocated
+        runtime.spawn(tampering_device(
+            server.addr,
+            |command, answer| match command {
+                Command::GetBuffer(_) => (u32::MAX - 1).to_be_bytes().to_vec(),
+                _ => answer,
+            },
+        ));
+        let received =
+            server.receive_until(|received| events(received).contains(&&ConnectionEvent::Closed));
+        assert!(has_error(&received, ErrorKind::Protocol));
+        server.wait_until_disconnected();
+
+        // half an answer, then silence
+        runtime.spawn(tampering_device(
+            server.addr,
+            |command, answer| match command {
+                Command::GetBuffer(_) => answer[..answer.len() / 2].to_vec(),
+                _ => answer,
+            },
+        ));
+        let received =
+            server.receive_until(|received| events(received).contains(&&ConnectionEvent::Closed));
+        assert!(has_error(&received, ErrorKind::Timeout));
+        server.wait_until_disconnected();
+    }
 }
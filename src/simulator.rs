@@ -0,0 +1,736 @@
+use crate::{
+    command::{self, BufferType, Command, COMMAND_SIZE, FRAME_HEADER_SIZE, PROTOCOL_VERSION},
+    config::{AdcConfig, Config},
+    device::DeviceInfo,
+    remote_data::FrameHeader,
+    tracer_an::{
+        DEFAULT_LOAD_STATE_ADDRESS, LOAD_COIL_ADDRESS, LOAD_CONTROL_MODE_ADDRESS,
+        RATED_BASE_ADDRESS, REALTIME_BASE_ADDRESS, REALTIME_STATUS_BASE_ADDRESS,
+        STATS_BASE_ADDRESS, VOLTAGE_SETTINGS_BASE_ADDRESS,
+    },
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    f32::consts::PI,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+/// firmware version the simulator reports in the handshake
+pub const SIMULATOR_FIRMWARE: [u8; 3] = [0, 1, 0];
+/// usable energy of the simulated 24 V battery pack
+const BATTERY_CAPACITY_WH: f32 = 2400.0;
+/// a closed connection is retried after this time
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How much sun there is and how much the inverter draws over a simulated day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SolarProfile {
+    Sunny,
+    /// clouds passing by every few minutes
+    Cloudy,
+    /// short and weak daylight, heating in the evening
+    Winter,
+}
+
+impl SolarProfile {
+    pub const ALL: [SolarProfile; 3] = [
+        SolarProfile::Sunny,
+        SolarProfile::Cloudy,
+        SolarProfile::Winter,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SolarProfile::Sunny => "sunny",
+            SolarProfile::Cloudy => "cloudy",
+            SolarProfile::Winter => "winter",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SolarProfile> {
+        SolarProfile::ALL
+            .into_iter()
+            .find(|profile| profile.name() == name)
+    }
+
+    /// sunrise and sunset hour
+    fn daylight(&self) -> (f32, f32) {
+        match self {
+            SolarProfile::Sunny | SolarProfile::Cloudy => (6.0, 20.0),
+            SolarProfile::Winter => (8.5, 16.5),
+        }
+    }
+
+    /// PV power in watts at `hour` of the day
+    pub fn pv_power(&self, hour: f32) -> f32 {
+        let (sunrise, sunset) = self.daylight();
+        if hour <= sunrise || hour >= sunset {
+            return 0.0;
+        }
+        let sun = (PI * (hour - sunrise) / (sunset - sunrise)).sin();
+        match self {
+            SolarProfile::Sunny => 950.0 * sun,
+            SolarProfile::Cloudy => {
+                let clouds = ((hour * 7.3).sin() * (hour * 2.9).cos()).abs();
+                950.0 * sun * (1.0 - 0.7 * clouds)
+            }
+            SolarProfile::Winter => 400.0 * sun,
+        }
+    }
+
+    /// inverter power in watts at `hour` of the day
+    pub fn load_power(&self, hour: f32) -> f32 {
+        let evening = match self {
+            SolarProfile::Winter => 300.0,
+            _ => 150.0,
+        };
+        match hour {
+            h if (7.0..8.0).contains(&h) => 120.0,
+            h if (18.0..23.0).contains(&h) => evening,
+            _ => 40.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatorConfig {
+    pub profile: SolarProfile,
+    /// simulated seconds per real second
+    pub speed: f32,
+    pub start_hour: f32,
+    /// behave like old firmware: no v2 framing and no handshake
+    pub legacy: bool,
+    pub voltage_interval_ms: u16,
+    pub power_interval_ms: u16,
+    /// samples kept per buffer
+    pub buffer_size: usize,
+    /// voltages are turned into adc readings the way the server converts them back
+    pub adc: AdcConfig,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig {
+            profile: SolarProfile::Sunny,
+            speed: 60.0,
+            start_hour: 6.0,
+            legacy: false,
+            voltage_interval_ms: 1000,
+            power_interval_ms: 1000,
+            buffer_size: 3600,
+            adc: AdcConfig::default(),
+        }
+    }
+}
+
+/// Samples the device has not handed out yet are the newest `unread` ones
+#[derive(Debug, Default)]
+struct SampleBuffer {
+    samples: VecDeque<u16>,
+    unread: usize,
+}
+
+/// The device side of the protocol with a virtual Tracer AN behind it
+#[derive(Debug)]
+pub struct VirtualDevice {
+    pub config: SimulatorConfig,
+    pub holdings: BTreeMap<u16, u16>,
+    pub input_registers: BTreeMap<u16, u16>,
+    pub coils: BTreeMap<u16, bool>,
+    /// indexed by `BufferType as usize`
+    buffers: [SampleBuffer; 5],
+    log: VecDeque<String>,
+    /// hour of the simulated day
+    hour: f32,
+    /// state of charge of the battery pack, 0..=1
+    soc: f32,
+    pv_power: f32,
+    load_power: f32,
+    generated_wh: f32,
+    consumed_wh: f32,
+    last_update: Instant,
+    next_voltage_sample: Instant,
+    next_power_sample: Instant,
+}
+
+impl VirtualDevice {
+    pub fn new(config: SimulatorConfig) -> Self {
+        let now = Instant::now();
+        let mut device = VirtualDevice {
+            holdings: BTreeMap::new(),
+            input_registers: BTreeMap::new(),
+            coils: BTreeMap::new(),
+            buffers: Default::default(),
+            log: VecDeque::new(),
+            hour: config.start_hour % 24.0,
+            soc: 0.6,
+            pv_power: config.profile.pv_power(config.start_hour % 24.0),
+            load_power: config.profile.load_power(config.start_hour % 24.0),
+            generated_wh: 0.0,
+            consumed_wh: 0.0,
+            last_update: now,
+            next_voltage_sample: now,
+            next_power_sample: now,
+            config,
+        };
+        device.write_rated();
+        device.write_voltage_settings();
+        device.write_load_control();
+        device.update(now);
+        device.log(format!(
+            "simulator started, {} day at {:.1} h",
+            device.config.profile.name(),
+            device.hour
+        ));
+        device
+    }
+
+    pub fn hour(&self) -> f32 {
+        self.hour
+    }
+
+    pub fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            firmware_version: Some(SIMULATOR_FIRMWARE),
+            buffers: BufferType::ALL.to_vec(),
+            adc_channels: 3,
+            voltage_interval_ms: self.config.voltage_interval_ms,
+            power_interval_ms: self.config.power_interval_ms,
+        }
+    }
+
+    /// Advances the simulated day to `now` and samples into the buffers
+    pub fn update(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_update);
+        self.last_update = self.last_update.max(now);
+        let hours = elapsed.as_secs_f32() * self.config.speed / 3600.0;
+        let was_day = self.pv_power > 0.0;
+        let was_low = self.soc < 0.2;
+
+        self.hour = (self.hour + hours) % 24.0;
+        self.pv_power = self.config.profile.pv_power(self.hour);
+        self.load_power = self.config.profile.load_power(self.hour);
+        // the inverter is connected to the load output
+        if !self.coils[&LOAD_COIL_ADDRESS] {
+            self.load_power = 0.0;
+        }
+        self.generated_wh += self.pv_power * hours;
+        self.consumed_wh += self.load_power * hours;
+        let net_wh = self.pv_power * 0.95 - self.load_power;
+        self.soc = (self.soc + net_wh * hours / BATTERY_CAPACITY_WH).clamp(0.0, 1.0);
+
+        match (was_day, self.pv_power > 0.0) {
+            (false, true) => self.log(format!("sunrise at {:.1} h, charging", self.hour)),
+            (true, false) => self.log(format!("sunset at {:.1} h, charging stopped", self.hour)),
+            _ => {}
+        }
+        match (was_low, self.soc < 0.2) {
+            (false, true) => self.log("battery low".to_string()),
+            (true, false) => self.log("battery recovered".to_string()),
+            _ => {}
+        }
+
+        let voltages = [
+            (BufferType::PVVoltage, self.pv_voltage()),
+            (BufferType::Battery1Voltage, self.battery1_voltage()),
+            (BufferType::BatteryPackVoltage, self.battery_pack_voltage()),
+        ]
+        .map(|(buffer_type, voltage)| (buffer_type, self.adc_reading(voltage)));
+        let interval = Duration::from_millis(self.config.voltage_interval_ms.max(1) as u64);
+        for _ in 0..Self::due_samples(&mut self.next_voltage_sample, now, interval) {
+            for (buffer_type, reading) in voltages {
+                self.push_sample(buffer_type, reading);
+            }
+        }
+        let powers = [
+            (BufferType::PVPower, self.pv_power.round() as u16),
+            (BufferType::InverterPower, self.load_power.round() as u16),
+        ];
+        let interval = Duration::from_millis(self.config.power_interval_ms.max(1) as u64);
+        for _ in 0..Self::due_samples(&mut self.next_power_sample, now, interval) {
+            for (buffer_type, power) in powers {
+                self.push_sample(buffer_type, power);
+            }
+        }
+        self.write_realtime();
+    }
+
+    /// The answer to `command`, `None` for commands without one. Commands that old
+    /// firmware does not know are not answered when simulating it.
+    pub fn answer(&mut self, command: Command) -> Option<Vec<u8>> {
+        self.update(Instant::now());
+        match command {
+            Command::GetVoltageIntervalms => {
+                Some(self.config.voltage_interval_ms.to_be_bytes().to_vec())
+            }
+            Command::GetPowerIntervalms => {
+                Some(self.config.power_interval_ms.to_be_bytes().to_vec())
+            }
+            Command::GetVoltageBufferSize => {
+                Some((self.config.buffer_size as u32).to_be_bytes().to_vec())
+            }
+            Command::GetBuffer(buffer_type) => {
+                let buffer = &mut self.buffers[buffer_type as usize];
+                let first_unread = buffer.samples.len() - buffer.unread;
+                // the device sends its little endian samples as they are in memory
+                let samples: Vec<u8> = buffer
+                    .samples
+                    .range(first_unread..)
+                    .flat_map(|sample| sample.to_le_bytes())
+                    .collect();
+                buffer.unread = 0;
+                let mut res = (samples.len() as u32).to_be_bytes().to_vec();
+                res.extend(samples);
+                Some(res)
+            }
+            Command::RetransmitBuffers => {
+                for buffer in &mut self.buffers {
+                    buffer.unread = buffer.samples.len();
+                }
+                None
+            }
+            Command::ModbusGetHoldings {
+                register_address,
+                size,
+            } => Some(Self::read_registers(&self.holdings, register_address, size)),
+            Command::ModbusGetInputRegisters {
+                register_address,
+                size,
+            } => Some(Self::read_registers(
+                &self.input_registers,
+                register_address,
+                size,
+            )),
+            // old firmware does not know the variable length write
+            Command::ModbusWriteHoldings { .. } if self.config.legacy => None,
+            Command::ModbusSetHoldings { .. } | Command::ModbusWriteHoldings { .. } => {
+                let (register_address, values) = command.written_holdings()?;
+                for (address, value) in (register_address..).zip(values) {
+                    self.holdings.insert(address, *value);
+                }
+                self.log(format!("holdings written at {register_address:#06x}"));
+                None
+            }
+            Command::ModbusGetCoil(_) | Command::ModbusSetCoil { .. } if self.config.legacy => None,
+            Command::ModbusGetCoil(register_address) => {
+                let on = self.coils.get(&register_address).copied().unwrap_or(false);
+                Some(vec![on as u8])
+            }
+            Command::ModbusSetCoil {
+                register_address,
+                on,
+            } => {
+                self.coils.insert(register_address, on);
+                self.log(format!(
+                    "coil {register_address:#06x} switched {}",
+                    if on { "on" } else { "off" }
+                ));
+                None
+            }
+            Command::GetLastLogMessage => {
+                let text = self.log.pop_front().unwrap_or_default();
+                let mut res = (text.len() as u32).to_be_bytes().to_vec();
+                res.extend(text.into_bytes());
+                Some(res)
+            }
+            Command::Heartbeat(_) if self.config.legacy => None,
+            Command::Heartbeat(sequence) => Some(vec![sequence]),
+            Command::NegotiateProtocol(_) | Command::GetDeviceInfo if self.config.legacy => None,
+            Command::NegotiateProtocol(_) => Some(vec![PROTOCOL_VERSION]),
+            Command::GetDeviceInfo => {
+                let info = self.info();
+                let buffers = info
+                    .buffers
+                    .iter()
+                    .fold(0u8, |mask, buffer_type| mask | 1 << *buffer_type as u8);
+                let mut res = info.firmware_version.unwrap_or_default().to_vec();
+                res.extend([buffers, info.adc_channels]);
+                res.extend(info.voltage_interval_ms.to_be_bytes());
+                res.extend(info.power_interval_ms.to_be_bytes());
+                Some(res)
+            }
+        }
+    }
+
+    fn log(&mut self, text: String) {
+        println!("simulator: {text}");
+        self.log.push_back(text);
+    }
+
+    fn due_samples(next_sample: &mut Instant, now: Instant, interval: Duration) -> usize {
+        let mut due = 0;
+        while *next_sample <= now {
+            *next_sample += interval;
+            due += 1;
+        }
+        due
+    }
+
+    fn push_sample(&mut self, buffer_type: BufferType, value: u16) {
+        let buffer_size = self.config.buffer_size;
+        let buffer = &mut self.buffers[buffer_type as usize];
+        buffer.samples.push_back(value);
+        buffer.unread += 1;
+        while buffer.samples.len() > buffer_size {
+            buffer.samples.pop_front();
+        }
+        buffer.unread = buffer.unread.min(buffer.samples.len());
+    }
+
+    fn adc_reading(&self, voltage: f32) -> u16 {
+        let adc = &self.config.adc;
+        let full_scale = adc.divider_top / adc.divider_bottom * adc.reference_voltage;
+        (voltage / full_scale * adc.max_reading)
+            .round()
+            .clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    fn pv_voltage(&self) -> f32 {
+        match self.pv_power {
+            p if p > 0.0 => 30.0 + 6.0 * (p / 950.0).min(1.0),
+            _ => 0.4,
+        }
+    }
+
+    fn battery_pack_voltage(&self) -> f32 {
+        let charging = if self.pv_power > self.load_power {
+            0.6
+        } else {
+            0.0
+        };
+        2.0 * (11.8 + 1.0 * self.soc) + charging
+    }
+
+    fn battery1_voltage(&self) -> f32 {
+        self.battery_pack_voltage() / 2.0 + 0.05
+    }
+
+    fn read_registers(registers: &BTreeMap<u16, u16>, address: u16, size: u8) -> Vec<u8> {
+        (0..size as u16)
+            .flat_map(|offset| {
+                let value = registers.get(&address.wrapping_add(offset));
+                value.copied().unwrap_or(0).to_be_bytes()
+            })
+            .collect()
+    }
+
+    /// values are stored in hundredths
+    fn set(&mut self, address: u16, value: f32) {
+        self.input_registers
+            .insert(address, (value * 100.0).round() as u16);
+    }
+
+    /// low word first, like the Tracer
+    fn set_wide(&mut self, address: u16, value: f32) {
+        let value = (value * 100.0).round() as u32;
+        self.input_registers.insert(address, value as u16);
+        self.input_registers
+            .insert(address + 1, (value >> 16) as u16);
+    }
+
+    fn write_rated(&mut self) {
+        let base = RATED_BASE_ADDRESS;
+        self.set(base, 100.0);
+        self.set(base + 1, 40.0);
+        self.set_wide(base + 2, 1040.0);
+        self.set(base + 4, 24.0);
+        self.set(base + 5, 40.0);
+        self.set_wide(base + 6, 1040.0);
+        // mppt
+        self.input_registers.insert(base + 8, 2);
+        self.set(base + 0x0E, 40.0);
+    }
+
+    fn write_voltage_settings(&mut self) {
+        let values = [
+            1, 200, 300, 3200, 3000, 3000, 2920, 2880, 2760, 2640, 2520, 2440, 2400, 2220, 2120,
+        ];
+        for (address, value) in (VOLTAGE_SETTINGS_BASE_ADDRESS..).zip(values) {
+            self.holdings.insert(address, value);
+        }
+    }
+
+    /// manual load control with the load switched on, timers from dusk till dawn
+    fn write_load_control(&mut self) {
+        self.coils.insert(LOAD_COIL_ADDRESS, true);
+        self.holdings.insert(LOAD_CONTROL_MODE_ADDRESS, 0);
+        self.holdings.insert(DEFAULT_LOAD_STATE_ADDRESS, 1);
+        // night below 5 V, day above 6 V, both after 10 minutes
+        for (address, value) in (0x901E..).zip([500, 10, 600, 10]) {
+            self.holdings.insert(address, value);
+        }
+        // seconds, minutes and hours of turn on 1, turn off 1, turn on 2, turn off 2
+        let timings = [0, 0, 19, 0, 0, 6, 0, 0, 0, 0, 0, 0];
+        for (address, value) in (0x9042..).zip(timings) {
+            self.holdings.insert(address, value);
+        }
+    }
+
+    fn write_realtime(&mut self) {
+        let base = REALTIME_BASE_ADDRESS;
+        let pv_voltage = self.pv_voltage();
+        let battery_voltage = self.battery_pack_voltage();
+        self.set(base, pv_voltage);
+        self.set(base + 1, self.pv_power / pv_voltage);
+        self.set_wide(base + 2, self.pv_power);
+        self.set_wide(base + 6, self.pv_power * 0.95);
+        self.set(base + 0x0C, battery_voltage);
+        self.set(base + 0x0D, self.load_power / battery_voltage);
+        self.set_wide(base + 0x0E, self.load_power);
+        self.set(base + 0x10, 20.0 + 5.0 * self.soc);
+        self.set(base + 0x11, 25.0 + self.pv_power / 100.0);
+        // percent, not hundredths
+        self.input_registers
+            .insert(base + 0x1A, (self.soc * 100.0).round() as u16);
+        self.set(base + 0x1B, 20.0 + 5.0 * self.soc);
+        self.set(base + 0x1D, 24.0);
+
+        let status = REALTIME_STATUS_BASE_ADDRESS;
+        let battery_status = if self.soc < 0.2 { 2 } else { 0 };
+        let charging_status = if self.pv_power > 0.0 { 1 | 2 << 2 } else { 0 };
+        self.input_registers.insert(status, battery_status);
+        self.input_registers.insert(status + 1, charging_status);
+        self.input_registers.insert(status + 2, 1);
+
+        let stats = STATS_BASE_ADDRESS;
+        let max_pv = self.register_value(stats).max(pv_voltage);
+        let min_pv = day_min(self.register_value(stats + 1), pv_voltage);
+        let max_battery = self.register_value(stats + 2).max(battery_voltage);
+        let min_battery = day_min(self.register_value(stats + 3), battery_voltage);
+        self.set(stats, max_pv);
+        self.set(stats + 1, min_pv);
+        self.set(stats + 2, max_battery);
+        self.set(stats + 3, min_battery);
+        let consumed_kwh = self.consumed_wh / 1000.0;
+        let generated_kwh = self.generated_wh / 1000.0;
+        for period in 0..4 {
+            self.set_wide(stats + 4 + period * 2, consumed_kwh);
+            self.set_wide(stats + 12 + period * 2, generated_kwh);
+        }
+        self.set(stats + 26, battery_voltage);
+        let current = (self.pv_power * 0.95 - self.load_power).abs() / battery_voltage;
+        self.set_wide(stats + 27, current);
+    }
+
+    fn register_value(&self, address: u16) -> f32 {
+        self.input_registers.get(&address).copied().unwrap_or(0) as f32 / 100.0
+    }
+}
+
+/// an unset minimum register reads 0
+fn day_min(previous: f32, value: f32) -> f32 {
+    if previous == 0.0 {
+        value
+    } else {
+        previous.min(value)
+    }
+}
+
+/// Answers commands on `stream` until the server closes it. Starts with the legacy
+/// framing and switches to v2 once the server negotiated it.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    device: &mut VirtualDevice,
+    mut stream: S,
+) -> io::Result<()> {
+    let mut framed = false;
+    loop {
+        let (sequence, payload) = if framed {
+            let mut header = [0; FRAME_HEADER_SIZE];
+            stream.read_exact(&mut header).await?;
+            let header = FrameHeader::parse(&header)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let mut payload = vec![0; header.len as usize];
+            stream.read_exact(&mut payload).await?;
+            if let Err(e) = header.check(&payload) {
+                println!("simulator: dropping command: {e}");
+                continue;
+            }
+            (header.sequence, payload)
+        } else {
+            let mut payload = vec![0; COMMAND_SIZE];
+            stream.read_exact(&mut payload).await?;
+            (0, payload)
+        };
+        let Ok(command) = Command::try_from(&payload[..]) else {
+            println!("simulator: unknown command {:?}", &payload[..4]);
+            continue;
+        };
+        let Some(answer) = device.answer(command) else {
+            continue;
+        };
+        if framed {
+            stream.write_all(&command::frame(sequence, &answer)).await?;
+        } else {
+            stream.write_all(&answer).await?;
+        }
+        stream.flush().await?;
+        if let Command::NegotiateProtocol(_) = command {
+            framed = true;
+        }
+    }
+}
+
+/// Waits for a discovery broadcast, answers it and returns the address of the server
+pub async fn discover(udp_port: u16, tcp_port: u16) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(("0.0.0.0", udp_port)).await?;
+    let mut buf = [0];
+    let (_, server) = socket.recv_from(&mut buf).await?;
+    println!("simulator: discovery broadcast from {server}");
+    socket.send_to(&buf, (server.ip(), udp_port)).await?;
+    Ok(SocketAddr::new(server.ip(), tcp_port))
+}
+
+/// `epmon_server simulate [--server HOST:PORT] [--profile sunny|cloudy|winter] [--speed N]
+/// [--start-hour H] [--legacy]`
+/// Without `--server` the simulator waits for a discovery broadcast on the configured udp port.
+pub fn run_cli(args: &[String], config: &Config) -> Result<(), String> {
+    let arg_value = |name: &str| crate::arg_value(args, name);
+    fn parse<T: std::str::FromStr>(name: &str, value: Option<&str>) -> Result<Option<T>, String> {
+        value
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid {name}: {value}"))
+            })
+            .transpose()
+    }
+    let mut simulator_config = SimulatorConfig {
+        legacy: args.iter().any(|arg| arg == "--legacy"),
+        adc: config.adc,
+        ..Default::default()
+    };
+    if let Some(name) = arg_value("--profile") {
+        simulator_config.profile =
+            SolarProfile::from_name(name).ok_or(format!("unknown profile: {name}"))?;
+    }
+    if let Some(speed) = parse("--speed", arg_value("--speed"))? {
+        simulator_config.speed = speed;
+    }
+    if let Some(hour) = parse("--start-hour", arg_value("--start-hour"))? {
+        simulator_config.start_hour = hour;
+    }
+    let server: Option<SocketAddr> = parse("--server", arg_value("--server"))?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("could not start the simulator runtime: {e}"))?;
+    let mut device = VirtualDevice::new(simulator_config);
+    runtime.block_on(async {
+        loop {
+            let server = match server {
+                Some(server) => server,
+                None => match discover(config.discovery.udp_port, config.server.tcp_port).await {
+                    Ok(server) => server,
+                    Err(e) => {
+                        println!("simulator: discovery failed: {e}");
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                },
+            };
+            match TcpStream::connect(server).await {
+                Ok(stream) => {
+                    println!("simulator: connected to {server}");
+                    if let Err(e) = serve(&mut device, stream).await {
+                        println!("simulator: connection closed: {e}");
+                    }
+                }
+                Err(e) => println!("simulator: could not connect to {server}: {e}"),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::Protocol,
+        device_stream::DeviceStream,
+        remote_data::RemoteData,
+        tracer_an::{Realtime, VoltageSettings},
+    };
+
+    #[test]
+    fn solar_profiles() {
+        for profile in SolarProfile::ALL {
+            assert_eq!(profile.pv_power(2.0), 0.0);
+            assert!(profile.pv_power(12.5) > 0.0);
+            assert!(profile.load_power(20.0) > profile.load_power(3.0));
+        }
+        assert!(SolarProfile::Cloudy.pv_power(13.0) < SolarProfile::Sunny.pv_power(13.0));
+        assert_eq!(
+            SolarProfile::from_name("winter"),
+            Some(SolarProfile::Winter)
+        );
+    }
+
+    #[test]
+    fn registers_and_buffers() {
+        let mut device = VirtualDevice::new(SimulatorConfig {
+            start_hour: 12.0,
+            speed: 0.0,
+            ..Default::default()
+        });
+        let realtime = Realtime::generate_commands()
+            .into_iter()
+            .flat_map(|command| device.answer(command).unwrap())
+            .collect::<Vec<u8>>();
+        assert!(Realtime::from_bytes(&realtime).unwrap().pv_voltage() > 30.0);
+
+        let settings = VoltageSettings::from_bytes(
+            &device
+                .answer(VoltageSettings::generate_get_command())
+                .unwrap(),
+        )
+        .unwrap();
+        let mut changed = settings;
+        changed.float_voltage = 27.2;
+        assert_eq!(device.answer(changed.generate_set_command()), None);
+        let read_back = device
+            .answer(VoltageSettings::generate_get_command())
+            .unwrap();
+        assert_eq!(VoltageSettings::from_bytes(&read_back).unwrap(), changed);
+
+        let first = device
+            .answer(Command::GetBuffer(BufferType::PVPower))
+            .unwrap();
+        assert_eq!(first[..4], [0, 0, 0, 2]);
+        let again = device
+            .answer(Command::GetBuffer(BufferType::PVPower))
+            .unwrap();
+        assert_eq!(again, [0, 0, 0, 0]);
+        device.answer(Command::RetransmitBuffers);
+        assert_eq!(
+            device.answer(Command::GetBuffer(BufferType::PVPower)),
+            Some(first)
+        );
+    }
+
+    #[tokio::test]
+    async fn server_side_negotiates_with_the_simulator() {
+        let (server_side, device_side) = tokio::io::duplex(4096);
+        let mut device = VirtualDevice::new(SimulatorConfig::default());
+        let device_task = tokio::spawn(async move { serve(&mut device, device_side).await });
+        let timeout = Duration::from_millis(500);
+        let mut stream = DeviceStream::new(server_side).with_timeouts(timeout, timeout);
+        assert_eq!(
+            stream.negotiate_protocol(timeout).await.ok(),
+            Some(Protocol::V2)
+        );
+        let info = RemoteData::read_device_info(&mut stream).await.unwrap();
+        assert_eq!(info.firmware_version, Some(SIMULATOR_FIRMWARE));
+        assert!(info.supports(BufferType::InverterPower));
+        let message = RemoteData::read_log_message(&mut stream).await.unwrap();
+        assert!(message.unwrap().text.starts_with("simulator started"));
+        drop(stream);
+        assert!(device_task.await.unwrap().is_err());
+    }
+}
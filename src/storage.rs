@@ -0,0 +1,404 @@
+use crate::{calibration::Calibration, device::DeviceId, remote_data::RemoteData};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+pub const DEFAULT_DATA_DIR: &str = "epmon_data";
+const SEGMENT_EXTENSION: &str = "epm";
+/// series id (1 byte) + unix timestamp in ms (8 bytes) + value (4 bytes)
+const RECORD_SIZE: usize = 13;
+
+/// samples and calibration of a device are kept in `<data_dir>/<device slug>`
+pub fn device_dir(data_dir: &Path, device: &DeviceId) -> PathBuf {
+    data_dir.join(device.slug())
+}
+
+/// The dir of `device`, or the only device dir in `data_dir` if no device is given
+pub fn select_device_dir(data_dir: &Path, device: Option<&str>) -> Result<PathBuf, String> {
+    if let Some(device) = device {
+        return Ok(device_dir(data_dir, &DeviceId(device.to_string())));
+    }
+    let dirs: Vec<PathBuf> = fs::read_dir(data_dir)
+        .map_err(|e| format!("could not read {}: {e}", data_dir.display()))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    match &dirs[..] {
+        [dir] => Ok(dir.clone()),
+        [] => Err(format!("no device data in {}", data_dir.display())),
+        _ => Err(format!(
+            "several devices in {}, select one with --device: {}",
+            data_dir.display(),
+            dirs.iter()
+                .filter_map(|dir| dir.file_name()?.to_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Series {
+    BatteryVoltage = 0,
+    BatteryPackVoltage,
+    PVVoltage,
+    PVPower,
+    InverterPower,
+}
+
+impl Series {
+    pub const ALL: [Series; 5] = [
+        Series::BatteryVoltage,
+        Series::BatteryPackVoltage,
+        Series::PVVoltage,
+        Series::PVPower,
+        Series::InverterPower,
+    ];
+
+    fn from_u8(value: u8) -> Option<Series> {
+        Series::ALL.get(value as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Series::BatteryVoltage => "battery1",
+            Series::BatteryPackVoltage => "battery_pack",
+            Series::PVVoltage => "pv",
+            Series::PVPower => "pv_power",
+            Series::InverterPower => "inverter_power",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Series> {
+        Series::ALL.into_iter().find(|series| series.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub series: Series,
+    pub timestamp_ms: i64,
+    pub value: f32,
+}
+
+impl Sample {
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut res = [0; RECORD_SIZE];
+        res[0] = self.series as u8;
+        res[1..9].copy_from_slice(&self.timestamp_ms.to_be_bytes());
+        res[9..].copy_from_slice(&self.value.to_be_bytes());
+        res
+    }
+
+    fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Option<Sample> {
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[1..9]);
+        let mut value = [0; 4];
+        value.copy_from_slice(&bytes[9..]);
+        Some(Sample {
+            series: Series::from_u8(bytes[0])?,
+            timestamp_ms: i64::from_be_bytes(timestamp),
+            value: f32::from_be_bytes(value),
+        })
+    }
+}
+
+/// Uniformly spaced samples of one series, ready to be put into a chart
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeriesHistory {
+    pub values: Vec<f32>,
+    /// time between 2 values in seconds
+    pub tick_len: f32,
+    /// unix timestamp of the last value
+    pub end_ms: i64,
+}
+
+/// Append-only sample store with one segment file per (local) day
+pub struct Store {
+    dir: PathBuf,
+    segment: Option<(NaiveDate, BufWriter<File>)>,
+    last_timestamp_ms: [Option<i64>; Series::ALL.len()],
+    /// the next reply of these series answers `RetransmitBuffers` and may repeat stored samples
+    retransmitted: [bool; Series::ALL.len()],
+}
+
+impl Store {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Store> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut store = Store {
+            dir,
+            segment: None,
+            last_timestamp_ms: [None; Series::ALL.len()],
+            retransmitted: [false; Series::ALL.len()],
+        };
+        for sample in store.load_day(Local::now().date_naive())? {
+            let last = &mut store.last_timestamp_ms[sample.series as usize];
+            *last = Some(last.unwrap_or(i64::MIN).max(sample.timestamp_ms));
+        }
+        Ok(store)
+    }
+
+    /// The next buffer reply of every series answers `RetransmitBuffers`
+    pub fn expect_retransmit(&mut self) {
+        self.retransmitted = [true; Series::ALL.len()];
+    }
+
+    /// Writes the samples of a buffer reply to disk. The newest sample is stamped with the
+    /// current time. Only in the reply to `RetransmitBuffers` samples that are already stored
+    /// are skipped, returns how many leading samples were skipped.
+    pub fn record(
+        &mut self,
+        remote_data: &RemoteData,
+        voltage_tick_len: f32,
+        power_tick_len: f32,
+        calibration: &Calibration,
+    ) -> io::Result<usize> {
+        let now_ms = Utc::now().timestamp_millis();
+        let buffer_type = remote_data.buffer_type();
+        let (series, readings, tick_len) = match remote_data {
+            RemoteData::BatteryVoltage(v) => (Series::BatteryVoltage, v, voltage_tick_len),
+            RemoteData::BatteryPackVoltage(v) => (Series::BatteryPackVoltage, v, voltage_tick_len),
+            RemoteData::PVVoltage(v) => (Series::PVVoltage, v, voltage_tick_len),
+            RemoteData::PVPower(v) => (Series::PVPower, v, power_tick_len),
+            RemoteData::InverterPower(v) => (Series::InverterPower, v, power_tick_len),
+            _ => return Ok(0),
+        };
+        let retransmitted = std::mem::take(&mut self.retransmitted[series as usize]);
+        let tick_ms = (tick_len * 1000.0) as i64;
+        let num_readings = readings.len() as i64;
+        let timestamp = |ix: usize| now_ms - (num_readings - 1 - ix as i64) * tick_ms;
+        let first_new = match self.last_timestamp_ms[series as usize] {
+            Some(last) if retransmitted => (0..readings.len())
+                .find(|&ix| timestamp(ix) > last + tick_ms / 2)
+                .unwrap_or(readings.len()),
+            _ => 0,
+        };
+        for (ix, &reading) in readings.iter().enumerate().skip(first_new) {
+            let value = match (series, buffer_type) {
+                (Series::PVPower | Series::InverterPower, _) | (_, None) => reading as f32,
+                (_, Some(buffer_type)) => calibration.to_voltage(buffer_type, reading),
+            };
+            self.append(Sample {
+                series,
+                timestamp_ms: timestamp(ix),
+                value,
+            })?;
+        }
+        if let Some((_, writer)) = &mut self.segment {
+            writer.flush()?;
+        }
+        Ok(first_new)
+    }
+
+    pub fn append(&mut self, sample: Sample) -> io::Result<()> {
+        let day = local_day(sample.timestamp_ms);
+        let rotate = match &self.segment {
+            Some((segment_day, _)) => *segment_day != day,
+            None => true,
+        };
+        if rotate {
+            if let Some((_, mut writer)) = self.segment.take() {
+                writer.flush()?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.segment_path(day))?;
+            self.segment = Some((day, BufWriter::new(file)));
+        }
+        if let Some((_, writer)) = &mut self.segment {
+            writer.write_all(&sample.to_bytes())?;
+        }
+        self.last_timestamp_ms[sample.series as usize] = Some(sample.timestamp_ms);
+        Ok(())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn segment_path(&self, day: NaiveDate) -> PathBuf {
+        self.dir
+            .join(format!("{}.{SEGMENT_EXTENSION}", day.format("%Y-%m-%d")))
+    }
+
+    /// Reads all samples of one day. A missing segment is not an error.
+    /// A partially written record at the end of the file is ignored.
+    pub fn load_day(&self, day: NaiveDate) -> io::Result<Vec<Sample>> {
+        let file = match File::open(self.segment_path(day)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::new(file);
+        let mut res = Vec::new();
+        let mut record = [0; RECORD_SIZE];
+        loop {
+            match reader.read_exact(&mut record) {
+                Ok(()) => res.extend(Sample::from_bytes(&record)),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(res)
+    }
+
+    /// All samples with `from_ms <= timestamp_ms <= to_ms`, possibly spanning several segments
+    pub fn load_range(&self, from_ms: i64, to_ms: i64) -> io::Result<Vec<Sample>> {
+        let mut res = Vec::new();
+        let mut day = local_day(from_ms);
+        while day <= local_day(to_ms) {
+            res.extend(
+                self.load_day(day)?
+                    .into_iter()
+                    .filter(|s| (from_ms..=to_ms).contains(&s.timestamp_ms)),
+            );
+            match day.succ_opt() {
+                Some(next) => day = next,
+                None => break,
+            }
+        }
+        Ok(res)
+    }
+
+    /// Today's samples of one series, spaced evenly so they can be plotted like live data.
+    /// Gaps in the recording are filled with zeros.
+    pub fn load_history(&self, series: Series) -> io::Result<SeriesHistory> {
+        let samples: Vec<Sample> = self
+            .load_day(Local::now().date_naive())?
+            .into_iter()
+            .filter(|sample| sample.series == series)
+            .collect();
+        Ok(resample(&samples))
+    }
+}
+
+fn local_day(timestamp_ms: i64) -> NaiveDate {
+    Local
+        .timestamp_millis_opt(timestamp_ms)
+        .single()
+        .map(|t: DateTime<Local>| t.date_naive())
+        .unwrap_or_default()
+}
+
+fn resample(samples: &[Sample]) -> SeriesHistory {
+    let mut deltas: Vec<i64> = samples
+        .windows(2)
+        .map(|w| w[1].timestamp_ms - w[0].timestamp_ms)
+        .filter(|&d| d > 0)
+        .collect();
+    if deltas.is_empty() {
+        return SeriesHistory {
+            values: samples.iter().map(|s| s.value).collect(),
+            tick_len: 0.0,
+            end_ms: samples.last().map_or(0, |s| s.timestamp_ms),
+        };
+    }
+    deltas.sort_unstable();
+    let tick_ms = deltas[deltas.len() / 2];
+    let mut values = Vec::with_capacity(samples.len());
+    let mut last_timestamp = samples[0].timestamp_ms;
+    for sample in samples {
+        let missing = (sample.timestamp_ms - last_timestamp + tick_ms / 2) / tick_ms - 1;
+        values.extend((0..missing).map(|_| 0.0));
+        values.push(sample.value);
+        last_timestamp = sample.timestamp_ms;
+    }
+    SeriesHistory {
+        values,
+        tick_len: tick_ms as f32 / 1000.0,
+        end_ms: last_timestamp,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("epmon_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn record_skips_stored_samples() {
+        let dir = temp_dir("record");
+        let mut store = Store::open(&dir).unwrap();
+        let remote_data = RemoteData::PVPower(vec![1, 2, 3]);
+        let skipped = store
+            .record(&remote_data, 1.0, 1.0, &Calibration::default())
+            .unwrap();
+        assert_eq!(skipped, 0);
+
+        // reopening picks up the last timestamp, so a retransmitted buffer is not stored twice
+        let mut store = Store::open(&dir).unwrap();
+        store.expect_retransmit();
+        let skipped = store
+            .record(&remote_data, 1.0, 1.0, &Calibration::default())
+            .unwrap();
+        assert_eq!(skipped, 3);
+        let history = store.load_history(Series::PVPower).unwrap();
+        assert_eq!(history.values, vec![1.0, 2.0, 3.0]);
+        assert_eq!(history.tick_len, 1.0);
+
+        // a reply arriving earlier than its samples add up to is new data all the same
+        let skipped = store
+            .record(
+                &RemoteData::PVPower(vec![4]),
+                1.0,
+                1.0,
+                &Calibration::default(),
+            )
+            .unwrap();
+        assert_eq!(skipped, 0);
+        let history = store.load_history(Series::PVPower).unwrap();
+        assert_eq!(history.values.last(), Some(&4.0));
+
+        let remote_data = RemoteData::InverterPower(vec![4, 5]);
+        store
+            .record(&remote_data, 1.0, 1.0, &Calibration::default())
+            .unwrap();
+        let history = store.load_history(Series::InverterPower).unwrap();
+        assert_eq!(history.values, vec![4.0, 5.0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn device_dir_selection() {
+        let dir = temp_dir("devices");
+        assert!(select_device_dir(&dir, None).is_err());
+        let a = DeviceId("10.0.0.2".to_string());
+        fs::create_dir_all(device_dir(&dir, &a)).unwrap();
+        assert_eq!(select_device_dir(&dir, None).unwrap(), dir.join("10_0_0_2"));
+        fs::create_dir_all(dir.join("10_0_0_3")).unwrap();
+        assert!(select_device_dir(&dir, None).is_err());
+        assert_eq!(
+            select_device_dir(&dir, Some("10.0.0.3")).unwrap(),
+            dir.join("10_0_0_3")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resample_fills_gaps() {
+        let sample = |timestamp_ms, value| Sample {
+            series: Series::PVVoltage,
+            timestamp_ms,
+            value,
+        };
+        let history = resample(&[
+            sample(0, 1.0),
+            sample(500, 2.0),
+            sample(1000, 3.0),
+            sample(2500, 4.0),
+        ]);
+        assert_eq!(history.tick_len, 0.5);
+        assert_eq!(history.values, vec![1.0, 2.0, 3.0, 0.0, 0.0, 4.0]);
+    }
+}
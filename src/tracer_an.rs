@@ -1,4 +1,6 @@
 use crate::command::Command;
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Timelike};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
 use std::fmt::Display;
 
 #[derive(Debug, Copy, Clone)]
@@ -12,7 +14,7 @@ pub struct Tracer {
 
 pub const RATED_BASE_ADDRESS: u16 = 0x3000;
 
-#[derive(Default, Debug, Copy, Clone, PartialEq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize)]
 pub struct Rated {
     array_rated_voltage: f32,
     array_rated_current: f32,
@@ -25,9 +27,9 @@ pub struct Rated {
 }
 
 impl Rated {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(bytes.len() >= Rated::data_len());
-        Self {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_len("Rated", bytes, Rated::data_len())?;
+        Ok(Self {
             array_rated_voltage: two_bytes_to_f32([bytes[0], bytes[1]]),
             array_rated_current: two_bytes_to_f32([bytes[2], bytes[3]]),
             array_rated_power: four_bytes_to_f32([bytes[4], bytes[5], bytes[6], bytes[7]]),
@@ -40,7 +42,7 @@ impl Rated {
                 _ => ChargingMode::MPPT,
             },
             rated_current_load: two_bytes_to_f32([bytes[18], bytes[19]]),
-        }
+        })
     }
 
     pub fn generate_commands() -> [Command; 2] {
@@ -83,7 +85,7 @@ impl Display for Rated {
     }
 }
 
-#[derive(Default, Debug, Copy, Clone, PartialEq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize)]
 pub enum ChargingMode {
     ConnectDisconnect = 0x00,
     PWM,
@@ -93,7 +95,7 @@ pub enum ChargingMode {
 
 pub const REALTIME_BASE_ADDRESS: u16 = 0x3100;
 
-#[derive(Default, Debug, Copy, Clone, PartialEq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize)]
 pub struct Realtime {
     pv_voltage: f32,
     pv_current: f32,
@@ -110,8 +112,8 @@ pub struct Realtime {
 }
 
 impl Realtime {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(bytes.len() >= Realtime::data_len());
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_len("Realtime", bytes, Realtime::data_len())?;
         let mut buf4: [u8; 4] = [0; 4];
         buf4.copy_from_slice(&bytes[4..8]);
         let pv_power = four_bytes_to_f32(buf4);
@@ -119,7 +121,7 @@ impl Realtime {
         let battery_power = four_bytes_to_f32(buf4);
         buf4.copy_from_slice(&bytes[16..20]);
         let load_power = four_bytes_to_f32(buf4);
-        Realtime {
+        Ok(Realtime {
             pv_voltage: two_bytes_to_f32([bytes[0], bytes[1]]),
             pv_current: two_bytes_to_f32([bytes[2], bytes[3]]),
             pv_power,
@@ -132,13 +134,51 @@ impl Realtime {
             remaining_battery_capacity: two_bytes_to_f32([bytes[24], bytes[25]]) / 0.01,
             remote_battery_temperature: two_bytes_to_f32([bytes[26], bytes[27]]),
             battery_real_rated_power: two_bytes_to_f32([bytes[28], bytes[29]]),
-        }
+        })
     }
 
     pub fn data_len() -> usize {
         30
     }
 
+    pub fn pv_voltage(&self) -> f32 {
+        self.pv_voltage
+    }
+
+    /// (name, unit, value) of every field
+    pub fn values(&self) -> [(&'static str, &'static str, f32); 12] {
+        [
+            ("pv_voltage", "volts", self.pv_voltage),
+            ("pv_current", "amperes", self.pv_current),
+            ("pv_power", "watts", self.pv_power),
+            ("battery_power", "watts", self.battery_power),
+            ("load_voltage", "volts", self.load_voltage),
+            ("load_current", "amperes", self.load_current),
+            ("load_power", "watts", self.load_power),
+            ("battery_temperature", "celsius", self.battery_temperature),
+            (
+                "equipment_temperature",
+                "celsius",
+                self.equipment_temperature,
+            ),
+            (
+                "remaining_battery_capacity",
+                "percent",
+                self.remaining_battery_capacity,
+            ),
+            (
+                "remote_battery_temperature",
+                "celsius",
+                self.remote_battery_temperature,
+            ),
+            (
+                "battery_real_rated_power",
+                "watts",
+                self.battery_real_rated_power,
+            ),
+        ]
+    }
+
     pub fn generate_commands() -> [Command; 5] {
         [
             Command::ModbusGetInputRegisters {
@@ -199,6 +239,139 @@ impl Display for Realtime {
     }
 }
 
+pub const REAL_TIME_CLOCK_ADDRESS: u16 = 0x9013;
+pub const LOAD_CONTROL_MODE_ADDRESS: u16 = 0x903D;
+pub const DEFAULT_LOAD_STATE_ADDRESS: u16 = 0x906A;
+/// coil switching the load output in manual load control mode
+pub const LOAD_COIL_ADDRESS: u16 = 0x0002;
+
+/// How the controller switches its load output
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadControlMode {
+    /// switched by the manual load coil, starts in the default load state
+    Manual = 0,
+    /// on at night, off at day
+    LightOnOff = 1,
+    /// on at dusk for the turn on timers
+    LightOnTimer = 2,
+    /// on between the turn on and turn off timing
+    TimeControl = 3,
+}
+
+impl LoadControlMode {
+    pub const ALL: [LoadControlMode; 4] = [
+        LoadControlMode::Manual,
+        LoadControlMode::LightOnOff,
+        LoadControlMode::LightOnTimer,
+        LoadControlMode::TimeControl,
+    ];
+
+    pub fn from_register(value: u16) -> Option<LoadControlMode> {
+        LoadControlMode::ALL
+            .into_iter()
+            .find(|mode| *mode as u16 == value)
+    }
+}
+
+impl Display for LoadControlMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadControlMode::Manual => write!(f, "manual"),
+            LoadControlMode::LightOnOff => write!(f, "light on/off"),
+            LoadControlMode::LightOnTimer => write!(f, "light on + timer"),
+            LoadControlMode::TimeControl => write!(f, "time control"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoadTimer {
+    First,
+    Second,
+}
+
+/// A holding of the Tracer besides the `VoltageSettings`, written with `Command::ModbusWriteHoldings`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HoldingSetting {
+    RealTimeClock(NaiveDateTime),
+    /// in minutes
+    EqualizationDuration(u16),
+    /// in minutes
+    BoostDuration(u16),
+    LoadControlMode(LoadControlMode),
+    /// load state after a restart in manual mode
+    DefaultLoadOn(bool),
+    /// below this PV voltage it is night
+    NighttimeThresholdVoltage(f32),
+    /// in minutes
+    LightSignalStartupDelay(u16),
+    /// above this PV voltage it is day
+    DaytimeThresholdVoltage(f32),
+    /// in minutes
+    LightSignalCloseDelay(u16),
+    TurnOnTiming(LoadTimer, NaiveTime),
+    TurnOffTiming(LoadTimer, NaiveTime),
+}
+
+impl HoldingSetting {
+    pub fn register_address(&self) -> u16 {
+        match self {
+            HoldingSetting::RealTimeClock(_) => REAL_TIME_CLOCK_ADDRESS,
+            HoldingSetting::EqualizationDuration(_) => 0x906B,
+            HoldingSetting::BoostDuration(_) => 0x906C,
+            HoldingSetting::LoadControlMode(_) => LOAD_CONTROL_MODE_ADDRESS,
+            HoldingSetting::DefaultLoadOn(_) => DEFAULT_LOAD_STATE_ADDRESS,
+            HoldingSetting::NighttimeThresholdVoltage(_) => 0x901E,
+            HoldingSetting::LightSignalStartupDelay(_) => 0x901F,
+            HoldingSetting::DaytimeThresholdVoltage(_) => 0x9020,
+            HoldingSetting::LightSignalCloseDelay(_) => 0x9021,
+            HoldingSetting::TurnOnTiming(LoadTimer::First, _) => 0x9042,
+            HoldingSetting::TurnOffTiming(LoadTimer::First, _) => 0x9045,
+            HoldingSetting::TurnOnTiming(LoadTimer::Second, _) => 0x9048,
+            HoldingSetting::TurnOffTiming(LoadTimer::Second, _) => 0x904B,
+        }
+    }
+
+    pub fn values(&self) -> Vec<u16> {
+        match *self {
+            // seconds and minutes, hour and day, month and year each in one register
+            HoldingSetting::RealTimeClock(time) => vec![
+                time.second() as u16 | (time.minute() as u16) << 8,
+                time.hour() as u16 | (time.day() as u16) << 8,
+                time.month() as u16 | ((time.year() - 2000).clamp(0, 255) as u16) << 8,
+            ],
+            HoldingSetting::EqualizationDuration(minutes)
+            | HoldingSetting::BoostDuration(minutes)
+            | HoldingSetting::LightSignalStartupDelay(minutes)
+            | HoldingSetting::LightSignalCloseDelay(minutes) => vec![minutes],
+            HoldingSetting::LoadControlMode(mode) => vec![mode as u16],
+            HoldingSetting::DefaultLoadOn(on) => vec![on as u16],
+            HoldingSetting::NighttimeThresholdVoltage(voltage)
+            | HoldingSetting::DaytimeThresholdVoltage(voltage) => {
+                vec![u16::from_be_bytes(f32_to_two_bytes(voltage))]
+            }
+            HoldingSetting::TurnOnTiming(_, time) | HoldingSetting::TurnOffTiming(_, time) => vec![
+                time.second() as u16,
+                time.minute() as u16,
+                time.hour() as u16,
+            ],
+        }
+    }
+
+    pub fn generate_set_command(&self) -> Command {
+        Command::write_holdings(self.register_address(), &self.values())
+            .expect("a setting spans at most 3 holdings")
+    }
+}
+
+/// the answers of a device are untrusted, a short one must not panic
+fn check_len(name: &str, bytes: &[u8], len: usize) -> Result<(), String> {
+    if bytes.len() < len {
+        return Err(format!("{name} needs {len} bytes, got {}", bytes.len()));
+    }
+    Ok(())
+}
+
 // [b0, b1, b2, b3] => u32 => f32 => / 100
 fn four_bytes_to_f32([b0, b1, b2, b3]: [u8; 4]) -> f32 {
     let integer: u32 = u32::from_be_bytes([b2, b3, b0, b1]);
@@ -219,6 +392,48 @@ pub fn f32_to_two_bytes(f: f32) -> [u8; 2] {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn decoders_reject_short_input(bytes in prop::collection::vec(any::<u8>(), 0..40)) {
+            prop_assert_eq!(Rated::from_bytes(&bytes).is_ok(), bytes.len() >= Rated::data_len());
+            prop_assert_eq!(
+                Realtime::from_bytes(&bytes).is_ok(),
+                bytes.len() >= Realtime::data_len()
+            );
+            prop_assert_eq!(
+                RealtimeStatus::from_bytes(&bytes).is_ok(),
+                bytes.len() >= RealtimeStatus::data_len()
+            );
+            prop_assert_eq!(
+                VoltageSettings::from_bytes(&bytes).is_ok(),
+                bytes.len() >= VoltageSettings::data_len()
+            );
+        }
+    }
+
+    #[test]
+    fn holding_settings() {
+        let time =
+            NaiveDateTime::parse_from_str("2024-03-09 17:45:30", "%Y-%m-%d %H:%M:%S").unwrap();
+        let clock = HoldingSetting::RealTimeClock(time);
+        assert_eq!(clock.values(), vec![30 | 45 << 8, 17 | 9 << 8, 3 | 24 << 8]);
+        let timing = HoldingSetting::TurnOffTiming(
+            LoadTimer::Second,
+            NaiveTime::from_hms_opt(6, 30, 0).unwrap(),
+        );
+        assert_eq!(
+            timing.generate_set_command(),
+            Command::write_holdings(0x904B, &[0, 30, 6]).unwrap()
+        );
+        let threshold = HoldingSetting::NighttimeThresholdVoltage(5.0);
+        assert_eq!(threshold.values(), vec![500]);
+        assert_eq!(
+            LoadControlMode::from_register(LoadControlMode::TimeControl as u16),
+            Some(LoadControlMode::TimeControl)
+        );
+    }
 
     #[test]
     fn f32_bytes_f32() {
@@ -251,9 +466,9 @@ pub struct RealtimeStatus {
 }
 
 impl RealtimeStatus {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(bytes.len() >= Self::data_len());
-        RealtimeStatus {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_len("RealtimeStatus", bytes, Self::data_len())?;
+        Ok(RealtimeStatus {
             battery_status: BatteryStatus(u16::from_be_bytes([bytes[0], bytes[1]])),
             charging_equipment_status: ChargingEquipmentStatus(u16::from_be_bytes([
                 bytes[2], bytes[3],
@@ -261,7 +476,7 @@ impl RealtimeStatus {
             discharging_equipment_status: DischargingEquipmentStatus(u16::from_be_bytes([
                 bytes[4], bytes[5],
             ])),
-        }
+        })
     }
 
     pub fn data_len() -> usize {
@@ -274,6 +489,99 @@ impl RealtimeStatus {
             size: 3,
         }
     }
+
+    /// all single bit flags of the 3 status registers by name
+    pub fn flags(&self) -> [(&'static str, bool); 22] {
+        let b = self.battery_status;
+        let c = self.charging_equipment_status;
+        let d = self.discharging_equipment_status;
+        [
+            (
+                "battery_inner_resistance_abnormal",
+                b.is_inner_resistance_abnormal(),
+            ),
+            ("battery_wrong_rated_voltage", b.is_wrong_rated_voltage()),
+            ("charging_running", c.is_running()),
+            ("charging_fault", c.has_fault()),
+            ("charging_pv_input_short", c.is_pv_input_short()),
+            ("charging_load_mosfet_short", c.is_load_mosfet_short()),
+            ("charging_load_short", c.is_load_short()),
+            ("charging_load_over_current", c.is_load_over_current()),
+            ("charging_input_over_current", c.is_input_over_current()),
+            (
+                "charging_anti_reverse_mosfet_short",
+                c.is_anti_reverse_mosfet_short(),
+            ),
+            (
+                "charging_or_anti_reverse_mosfet_short",
+                c.is_charging_or_anti_reverse_mosfet_short(),
+            ),
+            ("charging_mosfet_short", c.is_charging_mosfet_short()),
+            ("discharging_running", d.is_running()),
+            ("discharging_fault", d.has_fault()),
+            (
+                "discharging_output_overpressure",
+                d.is_output_overpressure(),
+            ),
+            ("discharging_boost_overpressure", d.is_boost_overpressure()),
+            (
+                "discharging_high_voltage_side_short_circuit",
+                d.is_high_voltage_side_short_circuit(),
+            ),
+            (
+                "discharging_input_over_pressure",
+                d.is_input_over_pressure(),
+            ),
+            (
+                "discharging_output_voltage_abnormal",
+                d.is_output_voltage_abnormal(),
+            ),
+            (
+                "discharging_unable_to_stop_discharging",
+                d.is_unable_to_stop_discharging(),
+            ),
+            (
+                "discharging_unable_to_discharge",
+                d.is_unable_to_discharge(),
+            ),
+            ("discharging_short_circuit", d.is_short_circuit()),
+        ]
+    }
+}
+
+impl Serialize for RealtimeStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let flags = self.flags();
+        let mut map = serializer.serialize_map(Some(flags.len() + 6))?;
+        map.serialize_entry(
+            "battery_voltage_status",
+            &BatteryVoltageStatus::from(self.battery_status),
+        )?;
+        map.serialize_entry(
+            "battery_temperature_status",
+            &BatteryTemperatureStatus::from(self.battery_status),
+        )?;
+        map.serialize_entry(
+            "charging_status",
+            &ChargingStatus::from(self.charging_equipment_status),
+        )?;
+        map.serialize_entry(
+            "input_volt_status",
+            &InputVoltStatus::from(self.charging_equipment_status),
+        )?;
+        map.serialize_entry(
+            "output_power",
+            &OutputPower::from(self.discharging_equipment_status),
+        )?;
+        map.serialize_entry(
+            "discharge_status",
+            &DischargeStatus::from(self.discharging_equipment_status),
+        )?;
+        for (name, value) in flags {
+            map.serialize_entry(name, &value)?;
+        }
+        map.end()
+    }
 }
 
 impl Display for RealtimeStatus {
@@ -329,7 +637,7 @@ impl Display for BatteryStatus {
     }
 }
 
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, Serialize)]
 pub enum BatteryVoltageStatus {
     #[default]
     Normal,
@@ -351,7 +659,7 @@ impl From<BatteryStatus> for BatteryVoltageStatus {
     }
 }
 
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, Serialize)]
 pub enum BatteryTemperatureStatus {
     #[default]
     Normal,
@@ -463,7 +771,7 @@ impl Display for ChargingEquipmentStatus {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize)]
 pub enum ChargingStatus {
     Off,
     Float,
@@ -488,7 +796,7 @@ impl Display for ChargingStatus {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize)]
 pub enum InputVoltStatus {
     Normal,
     NoPowerConnected,
@@ -607,7 +915,7 @@ impl Display for DischargingEquipmentStatus {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize)]
 pub enum OutputPower {
     LightLoad,
     Moderate,
@@ -632,7 +940,7 @@ impl Display for OutputPower {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize)]
 pub enum DischargeStatus {
     Normal,
     Low,
@@ -741,6 +1049,39 @@ impl Stats {
     }
 }
 
+impl Stats {
+    /// (name, value) of every statistic
+    pub fn values(&self) -> [(&'static str, f32); 14] {
+        [
+            ("max_pv_voltage_day", self.max_pv_voltage_day()),
+            ("min_pv_voltage_day", self.min_pv_voltage_day()),
+            ("max_battery_voltage_day", self.max_battery_voltage_day()),
+            ("min_battery_voltage_day", self.min_battery_voltage_day()),
+            ("consumed_energy_day", self.consumed_energy_day()),
+            ("consumed_energy_month", self.consumed_energy_month()),
+            ("consumed_energy_year", self.consumed_energy_year()),
+            ("consumed_energy_total", self.consumed_energy_total()),
+            ("generated_energy_day", self.generated_energy_day()),
+            ("generated_energy_month", self.generated_energy_month()),
+            ("generated_energy_year", self.generated_energy_year()),
+            ("generated_energy_total", self.generated_energy_total()),
+            ("battery_voltage", self.battery_voltage()),
+            ("battery_current", self.battery_current()),
+        ]
+    }
+}
+
+impl Serialize for Stats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let values = self.values();
+        let mut map = serializer.serialize_map(Some(values.len()))?;
+        for (name, value) in values {
+            map.serialize_entry(name, &value)?;
+        }
+        map.end()
+    }
+}
+
 impl Display for Stats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Stats:")?;
@@ -804,7 +1145,7 @@ impl Display for Stats {
 
 pub const VOLTAGE_SETTINGS_BASE_ADDRESS: u16 = 0x9000;
 
-#[derive(Default, Debug, Copy, Clone, PartialEq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VoltageSettings {
     pub battery_type: BatteryType,
     pub battery_capacity: u16,
@@ -824,9 +1165,9 @@ pub struct VoltageSettings {
 }
 
 impl VoltageSettings {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(bytes.len() >= VoltageSettings::data_len());
-        VoltageSettings {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_len("VoltageSettings", bytes, VoltageSettings::data_len())?;
+        Ok(VoltageSettings {
             battery_type: BatteryType::from(u16::from_be_bytes([bytes[0], bytes[1]])),
             battery_capacity: u16::from_be_bytes([bytes[2], bytes[3]]),
             temperature_compensation_coefficient: u16::from_be_bytes([bytes[4], bytes[5]]),
@@ -842,7 +1183,7 @@ impl VoltageSettings {
             under_voltage_warning_voltage: two_bytes_to_f32([bytes[24], bytes[25]]),
             low_voltage_disconnect_voltage: two_bytes_to_f32([bytes[26], bytes[27]]),
             discharging_limit_voltage: two_bytes_to_f32([bytes[28], bytes[29]]),
-        }
+        })
     }
 
     pub fn data_len() -> usize {
@@ -943,7 +1284,7 @@ impl VoltageSettings {
     }
 }
 
-#[derive(Default, Debug, Copy, Clone, PartialEq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BatteryType {
     #[default]
     UserDefined = 0,
@@ -1,10 +1,22 @@
+use crate::{
+    config::DiscoveryConfig,
+    device::{connected_count, ConnectedDevices},
+};
 use local_ip_address::local_ip;
-use std::{net::UdpSocket, sync::*, thread, time::Duration};
+use std::{io::ErrorKind, net::UdpSocket, thread, time::Duration};
 
-pub fn udp_broadcast(connected: Arc<Mutex<bool>>) {
-    let recv_sock = UdpSocket::bind("0.0.0.0:9900").expect("could not bind recv_sock");
+/// a device that did not answer a broadcast within this time gets another one
+const DISCOVERY_RETRY: Duration = Duration::from_secs(1);
+
+/// Broadcasts our address until `config.expected_devices` devices are connected,
+/// broadcasting starts again when a device is lost
+pub fn udp_broadcast(connected: ConnectedDevices, config: DiscoveryConfig) {
+    let recv_sock =
+        UdpSocket::bind(("0.0.0.0", config.udp_port)).expect("could not bind recv_sock");
+    recv_sock
+        .set_read_timeout(Some(DISCOVERY_RETRY))
+        .expect("could not set recv_sock timeout");
     if let Ok(sock) = UdpSocket::bind("0.0.0.0:0") {
-        let mut bconnected;
         let last_addr_byte = match local_ip().expect("getting local ip failed") {
             std::net::IpAddr::V4(ip) => ip.octets()[3],
             std::net::IpAddr::V6(_) => panic!("expected ipv4 address but got ipv6"),
@@ -12,23 +24,29 @@ pub fn udp_broadcast(connected: Arc<Mutex<bool>>) {
         println!("last_addr_byte : {last_addr_byte}");
         sock.set_broadcast(true)
             .expect("could not set broadcast to true");
+        let mut discovering = true;
         loop {
-            // check if connected
-            if let Ok(mgc) = connected.lock() {
-                bconnected = *mgc;
-            } else {
-                println!("udp_broadcast: Could not lock mutex");
-                break;
-            }
-
-            // no connection => send broadcast packet
-            if !bconnected {
-                sock.send_to(&[last_addr_byte], "192.168.178.255:9900")
-                    .expect("can not send last_addr_byte");
+            // not all devices connected => send broadcast packet
+            if connected_count(&connected) < config.expected_devices {
+                if !discovering {
+                    println!("device lost, discovery restarts");
+                    discovering = true;
+                }
+                sock.send_to(
+                    &[last_addr_byte],
+                    (config.broadcast_address.as_str(), config.udp_port),
+                )
+                .expect("can not send last_addr_byte");
                 println!("packet sent");
                 let mut buf = [0];
-                recv_sock.recv(&mut buf).expect("recv failed");
-                println!("received: {buf:?}");
+                match recv_sock.recv(&mut buf) {
+                    Ok(_) => println!("received: {buf:?}"),
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(e) => println!("discovery: recv failed: {e}"),
+                }
+            } else if discovering {
+                println!("all expected devices connected, discovery paused");
+                discovering = false;
             }
             thread::sleep(Duration::from_millis(10));
         }
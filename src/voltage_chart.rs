@@ -1,5 +1,6 @@
 use crate::{
-    adc_reading_to_voltage, remote_data::RemoteData, time_interval::TimeInterval, Message,
+    calibration::Calibration, live_data::missing_ticks, remote_data::RemoteData,
+    time_interval::TimeInterval, Message,
 };
 use canvas::{Frame, Geometry};
 use iced::widget::canvas::Cache;
@@ -9,12 +10,30 @@ use plotters_iced::{Chart, ChartWidget};
 use std::{collections::VecDeque, ops::Range};
 
 const NUM_DISPLAY_DATAPOINTS: f32 = 1000.0;
+/// a gap longer than this many ticks between two readings is not filled completely
+const MAX_REPEATED_TICKS: usize = 24 * 3600;
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum ChartType {
     #[default]
     Voltage,
     Power,
+    Current,
+    Temperature,
+    /// state of charge
+    Percent,
+}
+
+impl ChartType {
+    pub fn unit(&self) -> &'static str {
+        match self {
+            ChartType::Voltage => "V",
+            ChartType::Power => "W",
+            ChartType::Current => "A",
+            ChartType::Temperature => "°C",
+            ChartType::Percent => "%",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -38,6 +57,10 @@ pub struct CustomChart {
     /// time between 2 voltage measurements in seconds
     pub tick_len: f32,
     pub chart_type: ChartType,
+    /// (label, display data) of other devices drawn as lines on top of this chart
+    pub overlays: Vec<(String, Vec<(f32, f32)>)>,
+    /// unix timestamp of the newest sample while it comes from the stored history
+    pub history_end_ms: Option<i64>,
 }
 
 impl Default for CustomChart {
@@ -56,17 +79,27 @@ impl Default for CustomChart {
             integration_sub_range: (0.0..100.0),
             tick_len: 0.02,
             chart_type: Default::default(),
+            overlays: Vec::new(),
+            history_end_ms: None,
         }
     }
 }
 
 impl CustomChart {
-    pub fn update_voltages_from_remote(&mut self, remote_data: &mut RemoteData) {
+    pub fn update_voltages_from_remote(
+        &mut self,
+        remote_data: &mut RemoteData,
+        calibration: &Calibration,
+    ) {
+        let Some(buffer_type) = remote_data.buffer_type() else {
+            return;
+        };
         let adc_readings = remote_data.take_adc_readings();
         let voltages: VecDeque<f32> = adc_readings
             .iter()
-            .map(|adc_reading| adc_reading_to_voltage(*adc_reading))
+            .map(|adc_reading| calibration.to_voltage(buffer_type, *adc_reading))
             .collect();
+        self.pad_history_gap(voltages.len());
         self.data.try_reserve(voltages.len()).ok();
         for voltage in voltages {
             self.data.push_back(voltage);
@@ -82,6 +115,7 @@ impl CustomChart {
             .iter()
             .map(|&power_reading| power_reading as f32)
             .collect();
+        self.pad_history_gap(power_values.len());
         self.data.try_reserve(power_values.len()).ok();
         for power_value in power_values {
             self.data.push_back(power_value);
@@ -91,6 +125,33 @@ impl CustomChart {
         self.cache.clear();
     }
 
+    /// The first samples after the stored history follow a gap as long as the downtime
+    fn pad_history_gap(&mut self, new_values: usize) {
+        if new_values == 0 {
+            return;
+        }
+        if let Some(history_end_ms) = self.history_end_ms.take() {
+            let missing = missing_ticks(history_end_ms, self.tick_len, new_values);
+            self.data.extend(std::iter::repeat_n(0.0, missing));
+        }
+    }
+
+    /// Appends a single reading that arrived `elapsed` seconds after the previous one.
+    /// Readings are repeated or replaced so that consecutive values stay `tick_len` apart.
+    pub fn push_reading(&mut self, value: f32, elapsed: Option<f32>) {
+        let ticks = match elapsed {
+            Some(elapsed) => ((elapsed / self.tick_len).round() as usize).min(MAX_REPEATED_TICKS),
+            None => 1,
+        };
+        match self.data.back_mut() {
+            Some(last) if ticks == 0 => *last = value,
+            _ => self.data.extend(std::iter::repeat_n(value, ticks.max(1))),
+        }
+
+        self.accumulate_into_view_buffer();
+        self.cache.clear();
+    }
+
     pub fn kilo_watt_hours(&self) -> f32 {
         let integration_lower_ix =
             self.index_for_time(self.integration_sub_range.start + self.min_time);
@@ -165,7 +226,7 @@ impl CustomChart {
         }
     }
 
-    pub fn view(&self, _idx: usize, chart_height: f32) -> Element<Message> {
+    pub fn view(&self, _idx: usize, chart_height: f32) -> Element<'_, Message> {
         Column::new()
             .width(Length::Fill)
             .height(Length::Shrink)
@@ -206,11 +267,14 @@ impl Chart<Message> for CustomChart {
         use plotters::prelude::*;
         const PLOT_LINE_COLOR: RGBColor = RGBColor(0, 175, 255);
         const INTEGRATION_LINE_COLOR: RGBColor = RGBColor(120, 50, 0);
+        const OVERLAY_COLORS: [RGBColor; 4] = [
+            RGBColor(255, 175, 0),
+            RGBColor(200, 80, 255),
+            RGBColor(80, 220, 80),
+            RGBColor(255, 80, 80),
+        ];
 
-        let y_unit_text = match self.chart_type {
-            ChartType::Voltage => "V",
-            ChartType::Power => "W",
-        };
+        let y_unit_text = self.chart_type.unit();
 
         let mut chart = builder
             .x_label_area_size(28)
@@ -290,5 +354,45 @@ impl Chart<Message> for CustomChart {
                 .border_style(ShapeStyle::from(INTEGRATION_LINE_COLOR).stroke_width(2)),
             )
             .expect("failed to draw chart data");
+
+        if self.overlays.is_empty() {
+            return;
+        }
+        for ((label, data), color) in self.overlays.iter().zip(OVERLAY_COLORS.iter().cycle()) {
+            chart
+                .draw_series(LineSeries::new(
+                    data.iter().cloned(),
+                    ShapeStyle::from(color).stroke_width(2),
+                ))
+                .expect("failed to draw overlay")
+                .label(label.as_str())
+                .legend(move |(x, y)| {
+                    PathElement::new(vec![(x, y), (x + 20, y)], ShapeStyle::from(color))
+                });
+        }
+        chart
+            .configure_series_labels()
+            .label_font(("mono", 15.0).into_font().color(&WHITE))
+            .border_style(plotters::style::colors::BLUE.mix(0.45))
+            .draw()
+            .expect("failed to draw overlay labels");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readings_are_resampled_to_tick_len() {
+        let mut chart = CustomChart {
+            tick_len: 5.0,
+            ..Default::default()
+        };
+        chart.push_reading(1.0, None);
+        chart.push_reading(2.0, Some(5.2));
+        chart.push_reading(3.0, Some(1.0));
+        chart.push_reading(4.0, Some(14.0));
+        assert_eq!(chart.data, [1.0, 3.0, 4.0, 4.0, 4.0]);
     }
 }